@@ -1,7 +1,38 @@
+//! A small constant product automated market maker.
+//!
+//! Pools are built with [`AmmBuilder`] and driven through [`Amm`] or the
+//! [`AutomatedMarketMaker`] trait:
+//!
+//! ```
+//! use ramm::{AmmBuilder, AutomatedMarketMaker};
+//!
+//! let mut amm = AmmBuilder::<u128>::new()
+//!     .fees(3)
+//!     .token_names("USDC", "WETH")
+//!     .initial_reserves(String::from("lp"), 20_000, 10)
+//!     .build()
+//!     .unwrap();
+//! amm.get_free_tokens(String::from("trader"), 1_000, 0).unwrap();
+//! let token_b_amount = amm.swap_token_a_for_token_b(String::from("trader"), 1_000, 0).unwrap();
+//! assert_eq!(amm.get_account_balance(String::from("trader")), (0, token_b_amount, 0));
+//! ```
+
 extern crate core;
 
+use std::fmt::Debug;
+use std::hash::Hash;
+
+pub use amm::{
+    AccountHandle, AccountRegistry, Amm, AmmBuilder, AmmView, Event, EventSink, HistoryEntry, LimitOrder, PoolRegistry,
+    Preview, Quote, SwapInstruction,
+};
+pub use concentrated::ConcentratedPool;
+pub use multi_asset::{BasketCurve, MultiAssetPool};
+pub use router::{Router, Split, SplitQuote};
+pub use vault::Vault;
+
 const PRECISION: u32 = 1_000_000;
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     /// Share should be less than totalShare
     InvalidShare,
@@ -19,374 +50,7198 @@ pub enum Error {
     ZeroAmount,
     /// Zero Liquidity
     ZeroLiquidity,
+    /// Redenomination factor must be non-zero
+    InvalidFactor,
+    /// Deposit would push the pool reserves above the configured cap
+    PoolCapExceeded,
+    /// Deposit would push the account shares above the configured cap
+    AccountCapExceeded,
+    /// Operation rejected by a pool hook
+    HookRejected,
+    /// Arithmetic overflow or underflow
+    Overflow,
+    /// Deposit would push the pool value above the configured TVL cap
+    TvlCapExceeded,
+    /// Deposits are paused
+    DepositsPaused,
+    /// Account is not permitted to swap in this pool
+    SwapNotPermitted,
+    /// Pool price is already at the bound of its price band
+    PriceBandReached,
+    /// Pool accounting is inconsistent
+    InvariantViolation,
+    /// A pool must trade two distinct tokens
+    InvalidTokenPair,
+    /// A pool for this token pair and fee tier already exists
+    PoolAlreadyExists,
+    /// Amount is not a multiple of the token's lot size
+    InvalidLotSize,
+    /// Token is not traded by the pool
+    UnknownToken,
+    /// Number of amounts does not match the number of pool assets
+    AssetCountMismatch,
+    /// Curve weights must be non-zero
+    InvalidWeight,
+    /// Fee share is outside the range from 0 to 10_000 basis points
+    InvalidFeeShare,
+    /// Tick range is empty, out of bounds or not aligned to the tick spacing
+    InvalidTickRange,
+    /// The creator's registry balance does not cover the pool creation fee
+    CreationFeeNotPaid,
+    /// Initial reserves are below the registry minimum
+    InsufficientInitialLiquidity,
+    /// Only the pool owner may perform this operation
+    Unauthorized,
+    /// Share transfer exceeds the spender's allowance
+    InsufficientAllowance,
+    /// Shares are locked until the account's unlock time
+    SharesLocked,
+    /// No open limit order has this id
+    OrderNotFound,
+    /// No open position has this id
+    PositionNotFound,
+    /// Price observations do not cover the requested window
+    InsufficientHistory,
+    /// Operation executed after its deadline
+    Expired,
+    /// Flash swap repayment does not restore the invariant after fees
+    InsufficientRepayment,
+    /// Revealed swap does not match the account's commitment
+    CommitmentMismatch,
+    /// Pool operation called from a callback the same pool is running
+    Reentrancy,
+    /// Swap revealed before the reveal delay has passed
+    RevealTooEarly,
 }
 
-mod amm {
-    use std::collections::HashMap;
-    use crate::{Error, PRECISION};
+/// Identifier of an account holding tokens and shares. Implemented for every
+/// hashable type, so integrators can use `String`, integer ids or addresses.
+pub trait AccountId: Clone + Eq + Hash + Debug {}
 
-    //hold the balance of an Account
-    type Balances = HashMap<String, u32>;
+impl<I: Clone + Eq + Hash + Debug> AccountId for I {}
 
-    #[derive(Default)]
-    struct Amm {
-        fees: u32,
-        total_pool_shares: u32,
-        token_a_pool_balance: u32,
-        token_b_pool_balance: u32,
-        token_a_user_balance: Balances,
-        token_b_user_balance: Balances,
-        user_pool_shares: Balances,
-    }
-    impl Amm {
-        pub fn new(fees: u32) -> Self {
-            Self {
-                fees: if fees >= 1000 { 0 } else { fees },
-                ..Default::default()
-            }
-        }
+/// Numeric type usable for token amounts and shares. Covers the checked
+/// arithmetic the pool needs so `Amm` can run on different integer widths
+/// or a custom fixed-point type.
+pub trait Amount: Copy + Ord + Default + Debug + From<u32> {
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+    fn checked_div(self, rhs: Self) -> Option<Self>;
+    /// `self * b / c` rounded down and whether a remainder was discarded.
+    /// `None` if `c` is zero or the quotient does not fit in `Self`.
+    fn checked_mul_div_rem(self, b: Self, c: Self) -> Option<(Self, bool)>;
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    /// Nearest `f64`, for curves that need non-integer powers
+    fn to_f64(self) -> f64;
+    /// `value` truncated towards zero, `None` if it is negative, not finite
+    /// or out of range
+    fn from_f64(value: f64) -> Option<Self>;
+}
 
-        fn is_valid_amount(&self, account_id: &str, balances: &Balances, amount: u32 ) -> Result<(), Error> {
-            let account_balance = *balances.get(account_id).unwrap_or(&0);
-            match amount {
-                0 => Err(Error::ZeroAmount),
-                _ if amount > account_balance => Err(Error::InsufficientAmount),
-                _ => Ok(())
+macro_rules! impl_amount_widening {
+    ($($amount:ty),*) => {$(
+        impl Amount for $amount {
+            fn checked_add(self, rhs: Self) -> Option<Self> { <$amount>::checked_add(self, rhs) }
+            fn checked_sub(self, rhs: Self) -> Option<Self> { <$amount>::checked_sub(self, rhs) }
+            fn checked_mul(self, rhs: Self) -> Option<Self> { <$amount>::checked_mul(self, rhs) }
+            fn checked_div(self, rhs: Self) -> Option<Self> { <$amount>::checked_div(self, rhs) }
+            fn wrapping_add(self, rhs: Self) -> Self { <$amount>::wrapping_add(self, rhs) }
+            fn wrapping_sub(self, rhs: Self) -> Self { <$amount>::wrapping_sub(self, rhs) }
+            fn wrapping_mul(self, rhs: Self) -> Self { <$amount>::wrapping_mul(self, rhs) }
+
+            fn checked_mul_div_rem(self, b: Self, c: Self) -> Option<(Self, bool)> {
+                let product = u128::from(self) * u128::from(b);
+                let c = u128::from(c);
+                let quotient = <$amount>::try_from(product.checked_div(c)?).ok()?;
+                Some((quotient, product % c != 0))
             }
-        }
 
-        fn is_pool_active(&self) -> Result<(), Error> {
-            match self.get_pool_balance() {
-                0 => Err(Error::ZeroLiquidity),
-                _ => Ok(())
+            fn to_f64(self) -> f64 { self as f64 }
+
+            fn from_f64(value: f64) -> Option<Self> {
+                (value.is_finite() && value >= 0.0 && value < <$amount>::MAX as f64).then_some(value as $amount)
             }
         }
+    )*};
+}
+
+impl_amount_widening!(u32, u64);
+
+impl Amount for u128 {
+    fn checked_add(self, rhs: Self) -> Option<Self> { u128::checked_add(self, rhs) }
+    fn checked_sub(self, rhs: Self) -> Option<Self> { u128::checked_sub(self, rhs) }
+    fn checked_mul(self, rhs: Self) -> Option<Self> { u128::checked_mul(self, rhs) }
+    fn checked_div(self, rhs: Self) -> Option<Self> { u128::checked_div(self, rhs) }
+    fn wrapping_add(self, rhs: Self) -> Self { u128::wrapping_add(self, rhs) }
+    fn wrapping_sub(self, rhs: Self) -> Self { u128::wrapping_sub(self, rhs) }
+    fn wrapping_mul(self, rhs: Self) -> Self { u128::wrapping_mul(self, rhs) }
+    fn to_f64(self) -> f64 { self as f64 }
 
-        fn get_pool_balance(&self) -> u32 {
-            self.token_a_pool_balance * self.token_b_pool_balance
+    fn from_f64(value: f64) -> Option<Self> {
+        (value.is_finite() && value >= 0.0 && value < u128::MAX as f64).then_some(value as u128)
+    }
+
+    fn checked_mul_div_rem(self, b: Self, c: Self) -> Option<(Self, bool)> {
+        let (high, low) = mul_wide(self, b);
+        if c == 0 || high >= c {
+            return None;
+        }
+        if high == 0 {
+            return Some((low / c, low % c != 0));
         }
 
-        pub fn get_free_tokens(&mut self, account_id: String, token_a_amount: u32, token_b_amount: u32) {
-            let _account_id = account_id.as_str();
-            let token_a_balance = *self.token_a_user_balance.get(_account_id).unwrap_or(&0);
-            let token_b_balance = *self.token_b_user_balance.get(_account_id).unwrap_or(&0);
-            self.token_a_user_balance.insert(account_id.clone(), token_a_balance + token_a_amount);
-            self.token_b_user_balance.insert(account_id, token_b_balance + token_b_amount);
+        let mut quotient = 0;
+        let mut remainder = high;
+        for bit in (0..128).rev() {
+            let carry = remainder >> 127;
+            remainder = (remainder << 1) | ((low >> bit) & 1);
+            if carry == 1 || remainder >= c {
+                remainder = remainder.wrapping_sub(c);
+                quotient |= 1 << bit;
+            }
         }
+        Some((quotient, remainder != 0))
+    }
+}
 
-        pub fn get_account_balance(&self, account_id: String,) -> (u32, u32, u32) {
-            let token_a_balance = *self.token_a_user_balance
-                .get(account_id.as_str()).unwrap_or(&0);
-            let token_b_balance = *self.token_b_user_balance.
-                get(account_id.as_str()).unwrap_or(&0);
+/// Full 256-bit product of `a` and `b` as (high, low) halves
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
 
-            let pool_shares = *self.user_pool_shares
-                .get(account_id.as_str()).unwrap_or(&0);
-            (token_a_balance, token_b_balance, pool_shares)
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    let middle = (lo_lo >> 64) + (hi_lo & MASK) + (lo_hi & MASK);
+    let low = (middle << 64) | (lo_lo & MASK);
+    let high = hi_hi + (hi_lo >> 64) + (lo_hi >> 64) + (middle >> 64);
+    (high, low)
+}
+
+/// Operations shared by every pool implementation, so routers, agents and
+/// tests can drive any pool through `dyn AutomatedMarketMaker`.
+pub trait AutomatedMarketMaker<T: Amount = u128, Id = String> {
+    fn deposit(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T) -> Result<T, Error>;
+    fn withdraw(&mut self, account_id: Id, share: T) -> Result<(T, T), Error>;
+    fn swap_token_a_for_token_b(&mut self, account_id: Id, token_a_amount: T, min_token_b: T)
+        -> Result<T, Error>;
+    fn swap_token_b_for_token_a(&mut self, account_id: Id, token_b_amount: T, min_token_a: T)
+        -> Result<T, Error>;
+    fn get_swap_amount_for_token_b(&self, token_a_amount: T) -> Result<T, Error>;
+    fn get_swap_amount_for_token_a(&self, token_b_amount: T) -> Result<T, Error>;
+    fn get_withdraw_amount(&self, share: T) -> Result<(T, T), Error>;
+    fn get_pool_info(&self) -> (T, T, T, u32);
+}
+
+pub mod amm {
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::ops::Range;
+    use std::rc::Rc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::{AccountId, Amount, AutomatedMarketMaker, Error, PRECISION};
+
+    //hold the balance of an Account
+    type Balances<Id, T> = HashMap<Id, T>;
+
+    //scale of the per-share fee growth accumulators
+    const FEE_GROWTH_PRECISION: u32 = 1_000_000_000;
+
+    /// Direction a division result is rounded in. Amounts paid out by the
+    /// pool round down and amounts paid in round up, so rounding never
+    /// moves value out of the reserves.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Rounding {
+        Down,
+        Up,
+    }
+
+    /// What happens to the liquidity provider part of swap fees
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub enum FeeMode {
+        /// Fees stay in the reserves and grow the value of every share
+        #[default]
+        Compound,
+        /// Fees are kept outside the reserves and claimed with `claim_fees`
+        Accumulate,
+    }
+
+    /// One of the two pool tokens
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Token {
+        A,
+        B,
+    }
+
+    /// One swap of a `batch_swap`
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct SwapInstruction<T> {
+        pub token_in: Token,
+        pub amount_in: T,
+        pub min_out: T,
+    }
+
+    /// Preview of a swap returned by `Amm::quote_swap`. Prices are of the
+    /// input token in the other token, scaled by the precision.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Quote<T> {
+        pub amount_in: T,
+        pub amount_out: T,
+        pub fee_paid: T,
+        pub price_before: T,
+        pub price_after: T,
+        pub price_impact_bps: T,
+    }
+
+    /// Outcome of a previewed operation. Balances are the account's token A,
+    /// token B and share balances, as from `Amm::get_account_balance`.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Preview<R, T> {
+        pub result: R,
+        pub balance_before: (T, T, T),
+        pub balance_after: (T, T, T),
+    }
+
+    /// Order to sell `amount_in` of `token_in` for at least `min_amount_out`
+    /// of the other token, at the same price or better for partial fills.
+    /// The unfilled input is held in escrow by the pool.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LimitOrder<T, Id> {
+        pub account_id: Id,
+        pub token_in: Token,
+        pub amount_in: T,
+        pub min_amount_out: T,
+        pub remaining: T,
+        pub amount_out: T,
+        /// Set when a fill failed for a reason other than price. Stalled
+        /// orders are not filled again until `resume_limit_order`.
+        pub stalled: bool,
+    }
+
+    /// Liquidity added by one deposit. Positions divide the account's
+    /// shares by origin: share transfers and withdrawals draw on its oldest
+    /// positions first, and positions it empties are closed.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Position<T, Id> {
+        pub owner: Id,
+        pub shares: T,
+        /// Spot prices of token A in token B and of token B in token A right
+        /// after the deposit, scaled by the precision
+        pub entry_prices: (T, T),
+        /// Liquidity provider fees the position earned up to its last
+        /// settlement and its owner has not claimed yet, see
+        /// `get_position_fees`
+        pub accrued_fees: (T, T),
+        fee_growth_checkpoint: (T, T),
+    }
+
+    /// Per-pool callbacks at lifecycle points. `before_*` hooks can reject the
+    /// operation, `after_*` hooks observe its outcome. Every method defaults to
+    /// a no-op so implementors only override what they need.
+    pub trait PoolHooks<T: Amount, Id = String> {
+        fn before_swap(&mut self, _pool: &PoolState<T>, _account_id: &Id, _token_in: Token, _amount_in: T)
+            -> Result<(), Error> {
+            Ok(())
         }
 
-        pub fn get_pool_info(&self) -> (u32, u32, u32, u32) {
-            (
-                self.token_a_pool_balance,
-                self.token_b_pool_balance,
-                self.total_pool_shares,
-                self.fees
-            )
+        fn after_swap(&mut self, _pool: &PoolState<T>, _account_id: &Id, _token_in: Token, _amount_in: T, _amount_out: T) {}
 
+        fn before_deposit(&mut self, _pool: &PoolState<T>, _account_id: &Id, _token_a_amount: T, _token_b_amount: T)
+            -> Result<(), Error> {
+            Ok(())
         }
 
-        pub fn deposit(&mut self, account_id: String, token_a_amount: u32, token_b_amount: u32)
-            -> Result<u32, Error>
-        {
-            self.is_valid_amount(
-                account_id.as_str(),
-                &self.token_a_user_balance,
-                token_a_amount
-            )?;
-            self.is_valid_amount(
-                account_id.as_str(),
-                &self.token_b_user_balance,
-                token_b_amount
-            )?;
+        fn after_withdraw(&mut self, _pool: &PoolState<T>, _account_id: &Id, _share: T, _token_a_amount: T, _token_b_amount: T) {}
 
-            let mut shares = 0;
-            if self.total_pool_shares == 0 {
-                shares = 100 * PRECISION
-            } else {
-                let token_a_share = self.total_pool_shares * token_a_amount /  self.token_a_pool_balance;
-                let token_b_share = self.total_pool_shares * token_b_amount /  self.token_b_pool_balance;
+        fn after_transfer_shares(&mut self, _pool: &PoolState<T>, _from: &Id, _to: &Id, _share: T) {}
+    }
 
-                if token_a_share != token_b_share {
-                    return Err(Error::NonEquivalentValue);
-                }
-                shares = token_a_share;
-            }
+    /// Change to a pool, emitted to its `EventSink` once it has happened
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Event<T, Id> {
+        TokensIssued { account_id: Id, token_a_amount: T, token_b_amount: T },
+        TokensBurned { account_id: Id, token_a_amount: T, token_b_amount: T },
+        /// Tokens added to the reserves, including earlier deposit credits.
+        /// Zero shares means the deposit was kept as a credit.
+        Deposited { account_id: Id, token_a_amount: T, token_b_amount: T, shares: T },
+        Withdrawn { account_id: Id, share: T, token_a_amount: T, token_b_amount: T },
+        Swapped { account_id: Id, token_in: Token, amount_in: T, amount_out: T },
+        FlashSwapped { account_id: Id, token_out: Token, amount_out: T, token_a_repaid: T, token_b_repaid: T },
+        SharesTransferred { from: Id, to: Id, share: T },
+        SharesApproved { owner: Id, spender: Id, share: T },
+        /// Every share balance rescaled, leaving `total_pool_shares`
+        SharesRedenominated { redenomination: Redenomination<T>, total_pool_shares: T },
+        SharesLocked { account_id: Id, unlock_time: u64 },
+        /// A deposit breached the TVL cap with the pause action
+        DepositsPaused { tvl: T },
+        FeesCollected { account_id: Id, token_a_amount: T, token_b_amount: T },
+        ProtocolFeesCollected { account_id: Id, token_a_amount: T, token_b_amount: T },
+        PoolRecapitalized { token_a_amount: T, token_b_amount: T },
+        RewardTokensIssued { account_id: Id, amount: T },
+        RewardsFunded { account_id: Id, amount: T },
+        RewardsClaimed { account_id: Id, amount: T },
+        LimitOrderPlaced { order_id: u64, account_id: Id },
+        LimitOrderFilled { order_id: u64, account_id: Id, amount_in: T, amount_out: T },
+        LimitOrderCancelled { order_id: u64, account_id: Id, refund: T },
+        /// A fill of the order failed with `error` and the order is stalled
+        LimitOrderStalled { order_id: u64, account_id: Id, error: Error },
+        SwapCommitted { account_id: Id, commitment: u64 },
+    }
 
-            if shares == 0 {
-                return Err(Error::ThresholdNotReached);
+    impl<T, Id> Event<T, Id> {
+        /// Accounts whose balances or permissions the event changed
+        pub fn get_account_ids(&self) -> Vec<&Id> {
+            match self {
+                Event::SharesTransferred { from, to, .. } => vec![from, to],
+                Event::SharesApproved { owner, .. } => vec![owner],
+                Event::PoolRecapitalized { .. }
+                | Event::SharesRedenominated { .. }
+                | Event::DepositsPaused { .. } => vec![],
+                Event::TokensIssued { account_id, .. }
+                | Event::TokensBurned { account_id, .. }
+                | Event::Deposited { account_id, .. }
+                | Event::Withdrawn { account_id, .. }
+                | Event::Swapped { account_id, .. }
+                | Event::FlashSwapped { account_id, .. }
+                | Event::FeesCollected { account_id, .. }
+                | Event::ProtocolFeesCollected { account_id, .. }
+                | Event::RewardTokensIssued { account_id, .. }
+                | Event::RewardsFunded { account_id, .. }
+                | Event::RewardsClaimed { account_id, .. }
+                | Event::LimitOrderPlaced { account_id, .. }
+                | Event::LimitOrderFilled { account_id, .. }
+                | Event::LimitOrderCancelled { account_id, .. }
+                | Event::LimitOrderStalled { account_id, .. }
+                | Event::SharesLocked { account_id, .. }
+                | Event::SwapCommitted { account_id, .. } => vec![account_id],
             }
+        }
+    }
 
-            let token_a_balance = *self.token_a_user_balance.get(account_id.as_str()).unwrap_or(&0);
-            let token_b_balance = *self.token_b_user_balance.get(account_id.as_str()).unwrap_or(&0);
-            self.token_a_user_balance.insert(
-                account_id.clone(),
-                token_a_balance - token_a_amount,
-            );
-            self.token_b_user_balance.insert(
-                account_id.clone(),
-                token_b_balance - token_b_amount
-            );
+    /// Operation in an account's history with the account's token A, token B
+    /// and share balances right after it. Sequence numbers order entries
+    /// across every account of the pool.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct HistoryEntry<T, Id> {
+        pub sequence: u64,
+        pub event: Event<T, Id>,
+        pub balance: (T, T, T),
+    }
 
-            self.token_a_pool_balance += token_a_amount;
-            self.token_b_pool_balance += token_b_amount;
-            self.total_pool_shares += shares;
-            self.user_pool_shares
-                .entry(account_id)
-                .and_modify(|val| { *val += shares })
-                .or_insert(shares);
+    /// Receiver of the events of a pool
+    pub trait EventSink<T, Id> {
+        fn emit(&mut self, event: Event<T, Id>);
+    }
 
-            Ok(shares)
+    /// Shared log, so the events stay readable after the sink is attached
+    impl<T, Id> EventSink<T, Id> for Rc<RefCell<Vec<Event<T, Id>>>> {
+        fn emit(&mut self, event: Event<T, Id>) {
+            self.borrow_mut().push(event);
         }
+    }
 
-        pub fn get_token_a_swap_amount_out(&self, token_b_amount: u32) -> Result<u32, Error> {
-            self.is_pool_active()?;
-            Ok(self.token_a_pool_balance * token_b_amount/self.token_b_pool_balance)
-        }
+    /// Oldest price observations are dropped beyond this count
+    const MAX_PRICE_OBSERVATIONS: usize = 1024;
 
-        pub fn get_token_b_swap_amount_out(&self, token_a_amount: u32) -> Result<u32, Error> {
-            self.is_pool_active()?;
-            Ok(self.token_b_pool_balance * token_a_amount/self.token_a_pool_balance)
-        }
+    /// Pool price after the swaps at `timestamp`, and the sum of price times
+    /// elapsed time up to it. The sum wraps on overflow, so only differences
+    /// between observations are meaningful.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct PriceObservation<T> {
+        timestamp: u64,
+        price_cumulative: T,
+        price: T,
+    }
 
-        pub fn get_withdraw_amount(&self, share: u32) -> Result<(u32, u32), Error> {
-            self.is_pool_active()?;
-            if share > self.total_pool_shares {
-                return Err(Error::InvalidShare);
-            }
+    /// Source of the current time for time-based pool features. The unit is
+    /// up to the implementor, e.g. seconds or block numbers.
+    pub trait Clock {
+        fn now(&self) -> u64;
+    }
 
-            let token_a_amount = self.token_a_pool_balance * share / self.total_pool_shares;
-            let token_b_amount = self.token_b_pool_balance * share / self.total_pool_shares;
+    /// Seconds since the Unix epoch
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct SystemClock;
 
-            Ok((token_a_amount, token_b_amount))
+    impl Clock for SystemClock {
+        fn now(&self) -> u64 {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
         }
+    }
 
-        pub fn withdraw(&mut self, account_id: String, share: u32) -> Result<(u32, u32), Error> {
-            self.is_valid_amount(
-                account_id.as_str(),
-                &self.user_pool_shares,
-                share
-            )?;
-            let (token_a_amount, token_b_amount) = self.get_withdraw_amount(share)?;
-            self.user_pool_shares
-                .entry(account_id.clone())
-                .and_modify(|val| {*val += share});
+    /// Limits enforced on deposit, `None` means uncapped
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct DepositCaps<T> {
+        pub max_token_a_pool_balance: Option<T>,
+        pub max_token_b_pool_balance: Option<T>,
+        pub max_account_shares: Option<T>,
+    }
+
+    /// What a deposit breaching the TVL cap triggers
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub enum TvlCapAction {
+        /// Reject only the breaching deposit
+        #[default]
+        Reject,
+        /// Reject the breaching deposit and pause deposits until `resume_deposits`
+        Pause,
+    }
 
-            self.total_pool_shares -= share;
+    /// Ceiling on the pool value expressed in one of its tokens
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct TvlCap<T> {
+        pub numeraire: Token,
+        pub max_value: T,
+        pub action: TvlCapAction,
+    }
 
-            self.token_a_pool_balance -= token_a_amount;
-            self.token_b_pool_balance -= token_b_amount;
+    /// Decimal places of each pool token. Amounts are always passed in each
+    /// token's smallest unit; the decimals are only used to bring both tokens
+    /// to a common scale before the curve prices deposits, swaps and the pool
+    /// value.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct TokenConfig {
+        pub token_a_decimals: u8,
+        pub token_b_decimals: u8,
+    }
 
-            self.token_a_user_balance
-                .entry(account_id.clone())
-                .and_modify(|val| { *val += token_a_amount });
-            self.token_b_user_balance
-                .entry(account_id.clone())
-                .and_modify(|val| { *val += token_b_amount });
+    /// Hard bounds on the pool price, quoted as token B per token A scaled by
+    /// the pool precision. Swaps that would leave the band are filled up to the bound.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct PriceBand<T> {
+        pub min_price: T,
+        pub max_price: T,
+    }
 
+    /// Pricing invariant of a pool. The `Amm` keeps the accounting and asks
+    /// the curve for swap outputs and deposit shares.
+    pub trait Curve<T: Amount> {
+        /// Output for swapping `amount_in` of `token_in` against
+        /// `(reserve_in, reserve_out)` after a fee of `fees` per mille, rounded down
+        fn amount_out(&self, reserves: (T, T), token_in: Token, amount_in: T, fees: u32) -> Result<T, Error>;
 
-            Ok((token_a_amount,token_b_amount))
+        /// Shares minted for depositing `amounts` into a pool with `reserves`
+        /// and `total_shares` outstanding, rounded down
+        fn shares_for_deposit(&self, reserves: (T, T), amounts: (T, T), total_shares: T) -> Result<T, Error>;
+
+        /// Value the curve keeps constant across swaps. Fees and rounding may
+        /// only increase it.
+        fn invariant(&self, reserves: (T, T)) -> Result<T, Error>;
+
+        /// Relative weights of token A and token B in the pool value
+        fn weights(&self) -> (u32, u32) {
+            (1, 1)
         }
 
-        pub fn get_swap_amount_for_token_b(&self, token_a_amount: u32) -> Result<u32, Error> {
-            self.is_pool_active()?;
-            let token_a_amount = (1000 - self.fees) * token_a_amount / 1000;
+        /// Output for `amount_in` at the current spot price after fees,
+        /// ignoring price impact, rounded down. Defaults to the weighted
+        /// reserve ratio, which covers the constant product case.
+        fn spot_amount_out(&self, (reserve_in, reserve_out): (T, T), token_in: Token, amount_in: T, fees: u32)
+            -> Result<T, Error> {
+            let (token_a_weight, token_b_weight) = self.weights();
+            let (weight_in, weight_out) = match token_in {
+                Token::A => (token_a_weight, token_b_weight),
+                Token::B => (token_b_weight, token_a_weight),
+            };
+            let amount_in = mul_div(T::from(1000 - fees), amount_in, T::from(1000), Rounding::Down)?;
+            let amount_in = mul_div(amount_in, T::from(weight_in), T::from(weight_out), Rounding::Down)?;
+            mul_div(amount_in, reserve_out, reserve_in, Rounding::Down)
+        }
 
-            let total_token_a = self.token_a_pool_balance + token_a_amount;
-            let total_token_b = self.get_pool_balance() / total_token_a;
-            let mut token_b_amount = self.token_b_pool_balance - total_token_b;
+        /// Value of both reserves in units of `numeraire` at the spot price.
+        /// Defaults to the constant product case where both sides are worth
+        /// the same.
+        fn value(&self, (token_a_reserve, token_b_reserve): (T, T), numeraire: Token) -> Result<T, Error> {
+            match numeraire {
+                Token::A => mul(token_a_reserve, T::from(2)),
+                Token::B => mul(token_b_reserve, T::from(2)),
+            }
+        }
+    }
+
+    /// The `x * y = k` curve
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct ConstantProduct;
 
-            if total_token_b == self.token_b_pool_balance {
-                token_b_amount -= 1;
+    impl<T: Amount> Curve<T> for ConstantProduct {
+        /// The remaining output reserve is rounded up so the trader never
+        /// receives more than the curve allows
+        fn amount_out(&self, (reserve_in, reserve_out): (T, T), _token_in: Token, amount_in: T, fees: u32)
+            -> Result<T, Error> {
+            let amount_in = mul_div(T::from(1000 - fees), amount_in, T::from(1000), Rounding::Down)?;
+
+            let total_in = add(reserve_in, amount_in)?;
+            let total_out = mul_div(reserve_in, reserve_out, total_in, Rounding::Up)?;
+            sub(reserve_out, total_out)
+        }
+
+        /// Deposits must match the reserve ratio, checked by both tokens
+        /// minting the same number of shares
+        fn shares_for_deposit(&self, (token_a_reserve, token_b_reserve): (T, T), (token_a_amount, token_b_amount): (T, T),
+            total_shares: T) -> Result<T, Error> {
+            let token_a_share = mul_div(total_shares, token_a_amount, token_a_reserve, Rounding::Down)?;
+            let token_b_share = mul_div(total_shares, token_b_amount, token_b_reserve, Rounding::Down)?;
+            if token_a_share != token_b_share {
+                return Err(Error::NonEquivalentValue);
             }
+            Ok(token_a_share)
+        }
 
-            Ok(token_b_amount)
+        fn invariant(&self, (token_a_reserve, token_b_reserve): (T, T)) -> Result<T, Error> {
+            mul(token_a_reserve, token_b_reserve)
         }
+    }
 
-        pub fn get_swap_amount_for_token_a(&self, token_b_amount: u32) -> Result<u32, Error> {
-            self.is_pool_active()?;
-            if token_b_amount > self.token_b_pool_balance {
+    /// The `x + y = k` curve for assets pegged 1:1. Both tokens are expected
+    /// to share the same decimals. A swap can never empty the output reserve,
+    /// so the depleted side can always be refilled by swapping into it.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct ConstantSum;
+
+    impl<T: Amount> Curve<T> for ConstantSum {
+        fn amount_out(&self, (_, reserve_out): (T, T), _token_in: Token, amount_in: T, fees: u32)
+            -> Result<T, Error> {
+            let amount_out = mul_div(T::from(1000 - fees), amount_in, T::from(1000), Rounding::Down)?;
+            if amount_out >= reserve_out {
                 return Err(Error::InsufficientLiquidity);
             }
+            Ok(amount_out)
+        }
 
-            let total_token_b = self.token_b_pool_balance - token_b_amount;
-            let total_token_a = self.get_pool_balance() /total_token_b;
-            let token_a_amount = (total_token_a - self.token_a_pool_balance) * 1000 /
-                (1000 - self.fees);
+        /// Any mix of the tokens is accepted and valued at par
+        fn shares_for_deposit(&self, (token_a_reserve, token_b_reserve): (T, T), (token_a_amount, token_b_amount): (T, T),
+            total_shares: T) -> Result<T, Error> {
+            mul_div(
+                total_shares,
+                add(token_a_amount, token_b_amount)?,
+                add(token_a_reserve, token_b_reserve)?,
+                Rounding::Down,
+            )
+        }
 
-            Ok(token_a_amount)
+        fn invariant(&self, (token_a_reserve, token_b_reserve): (T, T)) -> Result<T, Error> {
+            add(token_a_reserve, token_b_reserve)
         }
 
-        pub fn swap_token_a_for_token_b(&mut self, account_id: String, token_a_amount: u32, min_token_b: u32)
-                                        -> Result<u32, Error> {
-            self.is_valid_amount(
-                account_id.as_str(),
-                &self.token_a_user_balance,
-                token_a_amount
-            )?;
+        fn spot_amount_out(&self, _reserves: (T, T), _token_in: Token, amount_in: T, fees: u32) -> Result<T, Error> {
+            mul_div(T::from(1000 - fees), amount_in, T::from(1000), Rounding::Down)
+        }
 
-            let token_b_amount = self.get_swap_amount_for_token_a(token_a_amount)?;
-            if token_b_amount < min_token_b {
-                return Err(Error::SlippageExceeded);
+        fn value(&self, reserves: (T, T), _numeraire: Token) -> Result<T, Error> {
+            self.invariant(reserves)
+        }
+    }
+
+    /// Balancer-style curve keeping `x^wa * y^wb` constant. Weights are
+    /// relative, so 80/20 and 4/1 describe the same pool. The power math runs
+    /// in `f64`, so swap outputs are rounded down by one extra unit to absorb
+    /// floating point error.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Weighted {
+        token_a_weight: u32,
+        token_b_weight: u32,
+    }
+
+    impl Weighted {
+        pub fn new(token_a_weight: u32, token_b_weight: u32) -> Result<Self, Error> {
+            if token_a_weight == 0 || token_b_weight == 0 {
+                return Err(Error::InvalidWeight);
             }
+            Ok(Self { token_a_weight, token_b_weight })
+        }
+    }
 
-            self.token_a_user_balance
-                .entry(account_id.clone())
-                .and_modify(|val| { *val -= token_a_amount });
+    impl<T: Amount> Curve<T> for Weighted {
+        fn amount_out(&self, (reserve_in, reserve_out): (T, T), token_in: Token, amount_in: T, fees: u32)
+            -> Result<T, Error> {
+            let (weight_in, weight_out) = match token_in {
+                Token::A => (self.token_a_weight, self.token_b_weight),
+                Token::B => (self.token_b_weight, self.token_a_weight),
+            };
+            let amount_in = mul_div(T::from(1000 - fees), amount_in, T::from(1000), Rounding::Down)?;
 
-            self.token_a_pool_balance += token_a_amount;
-            self.token_b_pool_balance -= token_b_amount;
+            let ratio = reserve_in.to_f64() / (reserve_in.to_f64() + amount_in.to_f64());
+            let amount_out = reserve_out.to_f64() * (1.0 - ratio.powf(f64::from(weight_in) / f64::from(weight_out)));
+            let amount_out = T::from_f64(amount_out.floor()).ok_or(Error::Overflow)?;
+            match amount_out > T::default() {
+                true => sub(amount_out, T::from(1)),
+                false => Ok(amount_out),
+            }
+        }
 
-            self.token_b_user_balance
-                .entry(account_id)
-                .and_modify(|val| { *val += token_b_amount });
+        /// Deposits must match the reserve ratio, as for a constant product pool
+        fn shares_for_deposit(&self, reserves: (T, T), amounts: (T, T), total_shares: T) -> Result<T, Error> {
+            ConstantProduct.shares_for_deposit(reserves, amounts, total_shares)
+        }
 
-            Ok(token_b_amount)
+        /// Weighted geometric mean of the reserves, rounded down. Reserves
+        /// above 2^53 cannot be represented exactly and fail with
+        /// `Error::Overflow`.
+        fn invariant(&self, (token_a_reserve, token_b_reserve): (T, T)) -> Result<T, Error> {
+            let (token_a_reserve, token_b_reserve) = (token_a_reserve.to_f64(), token_b_reserve.to_f64());
+            if token_a_reserve.max(token_b_reserve) > 2f64.powi(53) {
+                return Err(Error::Overflow);
+            }
+            let (token_a_weight, token_b_weight) = (f64::from(self.token_a_weight), f64::from(self.token_b_weight));
+            let invariant = token_a_reserve.powf(token_a_weight / (token_a_weight + token_b_weight))
+                * token_b_reserve.powf(token_b_weight / (token_a_weight + token_b_weight));
+            T::from_f64(invariant.floor()).ok_or(Error::Overflow)
         }
 
-        pub fn swap_token_b_for_token_a(&mut self, account_id: String, token_b_amount: u32, min_token_a: u32)
-                                        -> Result<u32, Error> {
-            self.is_valid_amount(
-                account_id.as_str(),
-                &self.token_b_user_balance,
-                token_b_amount
-            )?;
+        fn weights(&self) -> (u32, u32) {
+            (self.token_a_weight, self.token_b_weight)
+        }
 
-            let token_a_amount = self.get_swap_amount_for_token_a(token_b_amount)?;
-            if token_a_amount < min_token_a {
-                return Err(Error::SlippageExceeded);
+        /// At the spot price each token's share of the pool value equals its
+        /// weight
+        fn value(&self, (token_a_reserve, token_b_reserve): (T, T), numeraire: Token) -> Result<T, Error> {
+            let total_weight = T::from(self.token_a_weight + self.token_b_weight);
+            match numeraire {
+                Token::A => mul_div(token_a_reserve, total_weight, T::from(self.token_a_weight), Rounding::Down),
+                Token::B => mul_div(token_b_reserve, total_weight, T::from(self.token_b_weight), Rounding::Down),
             }
+        }
+    }
+
+    /// Which accounts may swap, checked against the pool's swap access list
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub enum SwapAccessMode {
+        /// Anyone can swap
+        #[default]
+        Open,
+        /// Only listed accounts can swap
+        Allowlist,
+        /// Listed accounts cannot swap
+        Blocklist,
+    }
+
+    /// Swap fee that follows recent volatility. The spread between the
+    /// highest and lowest pool price over the last `window` swaps scales the
+    /// fee linearly from `min_fee` up to `max_fee`, reached at a spread of
+    /// `max_spread_bps` of the lowest price. Fees are per mille.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct DynamicFee {
+        pub min_fee: u32,
+        pub max_fee: u32,
+        pub window: usize,
+        pub max_spread_bps: u32,
+    }
 
-            self.token_b_user_balance
-                .entry(account_id.clone())
-                .and_modify(|val| { *val -= token_b_amount });
+    /// Protocol insurance fund fed by a slice of swap fees
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct InsuranceFund<T> {
+        pub token_a_balance: T,
+        pub token_b_balance: T,
+        pub token_a_inflow: T,
+        pub token_b_inflow: T,
+        pub token_a_outflow: T,
+        pub token_b_outflow: T,
+    }
 
-            self.token_a_pool_balance -= token_a_amount;
-            self.token_b_pool_balance += token_b_amount;
+    /// How deposits too small to mint a single share are handled
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub enum ThresholdPolicy {
+        /// Fail with `Error::ThresholdNotReached`
+        #[default]
+        Reject,
+        /// Mint one share unit
+        RoundUp,
+        /// Hold the tokens as a credit that is added to the account's next deposit
+        Accrue,
+    }
 
-            self.token_a_user_balance
-                .entry(account_id)
-                .and_modify(|val| { *val += token_a_amount });
+    /// Typed snapshot of the full pool configuration and balances
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct PoolState<T> {
+        pub token_a_pool_balance: T,
+        pub token_b_pool_balance: T,
+        pub total_pool_shares: T,
+        pub fees: u32,
+        pub token_config: TokenConfig,
+        pub withdrawal_fee_bps: u32,
+        pub insurance_fee_share_bps: u32,
+        pub insurance_fund: InsuranceFund<T>,
+        pub protocol_fee_share_bps: u32,
+        pub protocol_fees: (T, T),
+        pub fee_mode: FeeMode,
+        pub token_a_fee_balance: T,
+        pub token_b_fee_balance: T,
+        pub deposit_caps: DepositCaps<T>,
+        pub tvl_cap: Option<TvlCap<T>>,
+        pub deposits_paused: bool,
+        pub swap_access_mode: SwapAccessMode,
+        pub price_band: Option<PriceBand<T>>,
+        pub lot_sizes: (T, T),
+        pub weights: (u32, u32),
+        pub is_active: bool,
+    }
 
-            Ok(token_a_amount)
-        }
+    /// Where one token's supply is held, as reported by `Amm::audit`
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct TokenAudit<T> {
+        pub account_balances: T,
+        pub pool_reserve: T,
+        pub accrued_fees: T,
+        pub insurance_fund: T,
+        pub protocol_fees: T,
+        pub deposit_credits: T,
+        pub limit_order_escrow: T,
+        pub total_supply: T,
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
-        fn get_account_id() -> String {
-            String::from("account-1")
+    impl<T: Amount> TokenAudit<T> {
+        /// Sum of every place the token is held
+        pub fn total_held(&self) -> Result<T, Error> {
+            [self.pool_reserve, self.accrued_fees, self.insurance_fund, self.protocol_fees, self.deposit_credits,
+                self.limit_order_escrow]
+                .into_iter()
+                .try_fold(self.account_balances, add)
         }
 
-        #[test]
-        fn test_constructor() {
-            let amm = Amm::new(0);
-            assert_eq!(amm.get_account_balance(get_account_id()), (0, 0, 0));
-            assert_eq!(amm.get_pool_info(), (0, 0, 0, 0));
+        pub fn is_balanced(&self) -> Result<bool, Error> {
+            Ok(self.total_held()? == self.total_supply)
         }
+    }
 
-        #[test]
-        fn test_get_free_tokens() {
-            let mut amm = Amm::new(100);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            assert_eq!(amm.get_account_balance(get_account_id()), (100, 200, 0));
+    /// Conservation check of a pool's tokens and shares
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct AuditReport<T> {
+        pub token_a: TokenAudit<T>,
+        pub token_b: TokenAudit<T>,
+        /// Reward tokens, with emitted but unclaimed rewards as accrued fees
+        pub reward_token: TokenAudit<T>,
+        pub account_shares: T,
+        pub position_shares: T,
+        pub total_pool_shares: T,
+    }
+
+    impl<T: Amount> AuditReport<T> {
+        pub fn is_balanced(&self) -> Result<bool, Error> {
+            Ok(self.token_a.is_balanced()?
+                && self.token_b.is_balanced()?
+                && self.reward_token.is_balanced()?
+                && self.account_shares == self.total_pool_shares
+                && self.position_shares == self.account_shares)
         }
+    }
 
-        #[test]
-        fn test_zero_liquidity() {
-            let mut amm = Amm::new(100);
-            let res = amm.get_token_a_swap_amount_out(4);
-            assert_eq!(res, Err(Error::ZeroLiquidity));
+    /// Consolidated result of `withdraw_all`
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Settlement<T> {
+        pub shares_redeemed: T,
+        pub token_a_withdrawn: T,
+        pub token_b_withdrawn: T,
+        pub token_a_fees: T,
+        pub token_b_fees: T,
+        pub rewards: T,
+    }
+
+    /// Renormalization applied to every share balance of the pool
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Redenomination<T> {
+        /// Multiply every share balance by the factor
+        Split(T),
+        /// Divide every share balance by the factor, rounding down
+        Merge(T),
+    }
+
+    /// Compact account identifier handed out by `AccountRegistry`
+    pub type AccountHandle = u32;
+
+    /// Interns account names into `AccountHandle`s. A pool keyed by handles,
+    /// `Amm<T, AccountHandle>`, copies a `u32` where a `String` pool would
+    /// clone the name on every balance update.
+    #[derive(Debug, Default, Clone)]
+    pub struct AccountRegistry {
+        handles: HashMap<String, AccountHandle>,
+        names: Vec<String>,
+    }
+
+    impl AccountRegistry {
+        pub fn new() -> Self {
+            Self::default()
         }
 
-        #[test]
-        fn test_deposit() {
-            let mut amm = Amm::new(100);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            let share = amm.deposit(
-                get_account_id(),
-                10,
-                20
-            ).unwrap();
-            assert_eq!(share, 100_000_000);
-            assert_eq!(amm.get_pool_info(), (10, 20, share, 100));
-            assert_eq!(amm.get_account_balance(get_account_id()), (90, 180, share));
+        /// Returns the handle of `name`, registering it on first use
+        pub fn intern(&mut self, name: &str) -> Result<AccountHandle, Error> {
+            if let Some(handle) = self.handles.get(name) {
+                return Ok(*handle);
+            }
+            let handle = AccountHandle::try_from(self.names.len()).map_err(|_| Error::Overflow)?;
+            self.handles.insert(String::from(name), handle);
+            self.names.push(String::from(name));
+            Ok(handle)
         }
 
-        #[test]
-        fn test_withdraw() {
-            let mut amm = Amm::new(0);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            let share = amm.deposit(
-                get_account_id(),
-                10,
-                20
-            ).unwrap();
-            assert_eq!(amm.withdraw(get_account_id(),share / 5).unwrap(), (2, 4));
-            //assert_eq!(amm.get_account_balance(get_account_id()), (92, 184, 4 * share / 5));
-            assert_eq!(amm.get_pool_info(), (8, 16, 4 * share / 5, 0));
+        pub fn get_handle(&self, name: &str) -> Option<AccountHandle> {
+            self.handles.get(name).copied()
         }
 
-        #[test]
-        fn test_swap() {
-            let mut amm = Amm::new(0);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            let share = amm.deposit(
-                get_account_id(),
-                50,
-                100
-            ).unwrap();
-            let token_b_amount = amm.swap_token_a_for_token_b(
-                get_account_id(),
-                50,
-                50
-            ).unwrap();
-            assert_eq!(token_b_amount, 50);
-            assert_eq!(amm.get_pool_info(), (100, 50, share, 0));
-            assert_eq!(amm.get_account_balance(get_account_id()), (0, 150, share));
+        pub fn get_name(&self, handle: AccountHandle) -> Option<&str> {
+            self.names.get(handle as usize).map(String::as_str)
         }
 
-        #[test]
-        fn test_slippage() {
-            let mut amm = Amm::new(0);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            let share = amm.deposit(
-                get_account_id(),
-                50,
-                100
-            ).unwrap();
-            let token_b_amount = amm.swap_token_a_for_token_b(
-                get_account_id(),
-                50,
-                51
-            );
-            assert_eq!(token_b_amount, Err(Error::SlippageExceeded));
-            assert_eq!(amm.get_pool_info(), (50, 100, share, 0));
-            assert_eq!(amm.get_account_balance(get_account_id()), (50, 100, share));
+        pub fn len(&self) -> usize {
+            self.names.len()
         }
 
-        #[test]
-        fn test_fees() {
-            let mut amm = Amm::new(100);
-            amm.get_free_tokens(get_account_id(), 100, 200);
-            let share = amm.deposit(
-                get_account_id(),
-                50,
+        pub fn is_empty(&self) -> bool {
+            self.names.is_empty()
+        }
+    }
+
+    /// Constant product pool of two tokens that also keeps the token and
+    /// share balances of every account using it
+    pub struct Amm<T: Amount = u128, Id: AccountId = String> {
+        fees: u32,
+        dynamic_fee: Option<DynamicFee>,
+        recent_prices: VecDeque<T>,
+        price_observations: VecDeque<PriceObservation<T>>,
+        precision: u32,
+        token_names: (String, String),
+        token_config: TokenConfig,
+        withdrawal_fee_bps: u32,
+        withdrawal_fee_decay: u64,
+        deposit_times: HashMap<Id, u64>,
+        deposit_caps: DepositCaps<T>,
+        tvl_cap: Option<TvlCap<T>>,
+        deposits_paused: bool,
+        swap_access_mode: SwapAccessMode,
+        swap_access_list: HashSet<Id>,
+        price_band: Option<PriceBand<T>>,
+        lot_sizes: (T, T),
+        threshold_policy: ThresholdPolicy,
+        deposit_credits: HashMap<Id, (T, T)>,
+        insurance_fee_share_bps: u32,
+        insurance_fund: InsuranceFund<T>,
+        protocol_fee_share_bps: u32,
+        protocol_fees: (T, T),
+        owner: Option<Id>,
+        fee_mode: FeeMode,
+        token_a_fee_balance: T,
+        token_b_fee_balance: T,
+        fee_growth_per_share: (T, T),
+        fee_growth_checkpoints: HashMap<Id, (T, T)>,
+        unclaimed_fees: HashMap<Id, (T, T)>,
+        hooks: Option<Box<dyn PoolHooks<T, Id>>>,
+        event_sink: Option<Box<dyn EventSink<T, Id>>>,
+        buffered_events: Option<Vec<Event<T, Id>>>,
+        history_enabled: bool,
+        history_sequence: u64,
+        history: HashMap<Id, Vec<HistoryEntry<T, Id>>>,
+        clock: Rc<dyn Clock>,
+        unlock_times: HashMap<Id, u64>,
+        reveal_delay: u64,
+        swap_commitments: HashMap<Id, (u64, u64)>,
+        reward_rate: T,
+        reward_reserve: T,
+        reward_per_share: T,
+        last_reward_time: Option<u64>,
+        reward_checkpoints: HashMap<Id, T>,
+        unclaimed_rewards: HashMap<Id, T>,
+        reward_balances: HashMap<Id, T>,
+        reward_total_supply: T,
+        reward_outstanding: T,
+        curve: Rc<dyn Curve<T>>,
+        token_a_total_supply: T,
+        token_b_total_supply: T,
+        total_pool_shares: T,
+        token_a_pool_balance: T,
+        token_b_pool_balance: T,
+        token_a_user_balance: Balances<Id, T>,
+        token_b_user_balance: Balances<Id, T>,
+        user_pool_shares: Balances<Id, T>,
+        share_allowances: HashMap<(Id, Id), T>,
+        positions: BTreeMap<u64, Position<T, Id>>,
+        next_position_id: u64,
+        limit_orders: BTreeMap<u64, LimitOrder<T, Id>>,
+        next_order_id: u64,
+        filling_limit_orders: bool,
+        // set while a flash swap callback runs
+        locked: bool,
+    }
+
+    // derived Default would require `Id: Default`
+    impl<T: Amount, Id: AccountId> Default for Amm<T, Id> {
+        fn default() -> Self {
+            Self {
+                fees: Default::default(),
+                dynamic_fee: Default::default(),
+                recent_prices: Default::default(),
+                price_observations: Default::default(),
+                precision: Default::default(),
+                token_names: Default::default(),
+                token_config: Default::default(),
+                withdrawal_fee_bps: Default::default(),
+                withdrawal_fee_decay: Default::default(),
+                deposit_times: Default::default(),
+                deposit_caps: Default::default(),
+                tvl_cap: Default::default(),
+                deposits_paused: Default::default(),
+                swap_access_mode: Default::default(),
+                swap_access_list: Default::default(),
+                price_band: Default::default(),
+                lot_sizes: Default::default(),
+                threshold_policy: Default::default(),
+                deposit_credits: Default::default(),
+                insurance_fee_share_bps: Default::default(),
+                insurance_fund: Default::default(),
+                protocol_fee_share_bps: Default::default(),
+                protocol_fees: Default::default(),
+                owner: Default::default(),
+                fee_mode: Default::default(),
+                token_a_fee_balance: Default::default(),
+                token_b_fee_balance: Default::default(),
+                fee_growth_per_share: Default::default(),
+                fee_growth_checkpoints: Default::default(),
+                unclaimed_fees: Default::default(),
+                hooks: Default::default(),
+                event_sink: Default::default(),
+                buffered_events: Default::default(),
+                history_enabled: Default::default(),
+                history_sequence: Default::default(),
+                history: Default::default(),
+                clock: Rc::new(SystemClock),
+                unlock_times: Default::default(),
+                reveal_delay: Default::default(),
+                swap_commitments: Default::default(),
+                reward_rate: Default::default(),
+                reward_reserve: Default::default(),
+                reward_per_share: Default::default(),
+                last_reward_time: Default::default(),
+                reward_checkpoints: Default::default(),
+                unclaimed_rewards: Default::default(),
+                reward_balances: Default::default(),
+                reward_total_supply: Default::default(),
+                reward_outstanding: Default::default(),
+                curve: Rc::new(ConstantProduct),
+                token_a_total_supply: Default::default(),
+                token_b_total_supply: Default::default(),
+                total_pool_shares: Default::default(),
+                token_a_pool_balance: Default::default(),
+                token_b_pool_balance: Default::default(),
+                token_a_user_balance: Default::default(),
+                token_b_user_balance: Default::default(),
+                user_pool_shares: Default::default(),
+                share_allowances: Default::default(),
+                positions: Default::default(),
+                next_position_id: Default::default(),
+                limit_orders: Default::default(),
+                next_order_id: Default::default(),
+                filling_limit_orders: Default::default(),
+                locked: Default::default(),
+            }
+        }
+    }
+
+    pub(crate) fn add<T: Amount>(a: T, b: T) -> Result<T, Error> {
+        a.checked_add(b).ok_or(Error::Overflow)
+    }
+
+    pub(crate) fn sub<T: Amount>(a: T, b: T) -> Result<T, Error> {
+        a.checked_sub(b).ok_or(Error::Overflow)
+    }
+
+    pub(crate) fn mul<T: Amount>(a: T, b: T) -> Result<T, Error> {
+        a.checked_mul(b).ok_or(Error::Overflow)
+    }
+
+    pub(crate) fn div<T: Amount>(a: T, b: T) -> Result<T, Error> {
+        a.checked_div(b).ok_or(Error::Overflow)
+    }
+
+    pub(crate) fn mul_div<T: Amount>(a: T, b: T, c: T, rounding: Rounding) -> Result<T, Error> {
+        let (quotient, has_remainder) = a.checked_mul_div_rem(b, c).ok_or(Error::Overflow)?;
+        match (rounding, has_remainder) {
+            (Rounding::Up, true) => add(quotient, T::from(1)),
+            _ => Ok(quotient),
+        }
+    }
+
+    /// Cumulative price at `timestamp`, with the observed price holding since
+    fn get_price_cumulative<T: Amount>(observation: &PriceObservation<T>, timestamp: u64) -> T {
+        let elapsed = timestamp.saturating_sub(observation.timestamp);
+        // elapsed may not fit in a u32, so it is applied in two 32-bit halves
+        let (high, low) = (T::from((elapsed >> 32) as u32), T::from(elapsed as u32));
+        let shift = T::from(1 << 16).wrapping_mul(T::from(1 << 16));
+        let price_time = observation.price.wrapping_mul(low)
+            .wrapping_add(observation.price.wrapping_mul(high).wrapping_mul(shift));
+        observation.price_cumulative.wrapping_add(price_time)
+    }
+
+    impl<T: Amount, Id: AccountId> Amm<T, Id> {
+        /// Creates an empty pool charging `fees` per mille on swaps
+        pub fn new(fees: u32) -> Self {
+            Self {
+                fees: if fees >= 1000 { 0 } else { fees },
+                precision: PRECISION,
+                token_names: (String::from("A"), String::from("B")),
+                ..Default::default()
+            }
+        }
+
+        pub fn with_token_config(fees: u32, token_config: TokenConfig) -> Self {
+            Self {
+                token_config,
+                ..Self::new(fees)
+            }
+        }
+
+        pub fn with_curve(fees: u32, curve: Box<dyn Curve<T>>) -> Self {
+            Self {
+                curve: Rc::from(curve),
+                ..Self::new(fees)
+            }
+        }
+
+        /// Relative weights of token A and token B set by the pool curve
+        pub fn get_weights(&self) -> (u32, u32) {
+            self.curve.weights()
+        }
+
+        pub fn get_token_config(&self) -> TokenConfig {
+            self.token_config
+        }
+
+        pub fn get_token_names(&self) -> (&str, &str) {
+            (self.token_names.0.as_str(), self.token_names.1.as_str())
+        }
+
+        /// Scales `amount` of `token` up to the larger of the two token
+        /// decimals so amounts of both tokens can be compared directly
+        pub fn normalize_amount(&self, token: Token, amount: T) -> Result<T, Error> {
+            mul(amount, self.get_decimal_scale(token)?)
+        }
+
+        /// Scales a normalized amount back to the decimals of `token`,
+        /// rounding down
+        pub fn denormalize_amount(&self, token: Token, amount: T) -> Result<T, Error> {
+            div(amount, self.get_decimal_scale(token)?)
+        }
+
+        fn get_decimal_scale(&self, token: Token) -> Result<T, Error> {
+            let TokenConfig { token_a_decimals, token_b_decimals } = self.token_config;
+            let decimals = match token {
+                Token::A => token_a_decimals,
+                Token::B => token_b_decimals,
+            };
+            let mut scale = T::from(1);
+            for _ in decimals..token_a_decimals.max(token_b_decimals) {
+                scale = mul(scale, T::from(10))?;
+            }
+            Ok(scale)
+        }
+
+        /// Input and output reserves ordered by `token_in`, normalized so the
+        /// curve prices tokens of different decimals correctly
+        fn normalize_reserves(&self, token_in: Token, (reserve_in, reserve_out): (T, T)) -> Result<(T, T), Error> {
+            let token_out = Self::get_token_out(token_in);
+            Ok((self.normalize_amount(token_in, reserve_in)?, self.normalize_amount(token_out, reserve_out)?))
+        }
+
+        fn get_token_out(token_in: Token) -> Token {
+            match token_in {
+                Token::A => Token::B,
+                Token::B => Token::A,
+            }
+        }
+
+        /// Read-only view of the pool that can be handed to untrusted code
+        pub fn view(&self) -> AmmView<'_, T, Id> {
+            AmmView { amm: self }
+        }
+
+        pub fn pool_state(&self) -> PoolState<T> {
+            PoolState {
+                token_a_pool_balance: self.token_a_pool_balance,
+                token_b_pool_balance: self.token_b_pool_balance,
+                total_pool_shares: self.total_pool_shares,
+                fees: self.get_effective_fee(),
+                token_config: self.token_config,
+                withdrawal_fee_bps: self.withdrawal_fee_bps,
+                insurance_fee_share_bps: self.insurance_fee_share_bps,
+                insurance_fund: self.insurance_fund,
+                protocol_fee_share_bps: self.protocol_fee_share_bps,
+                protocol_fees: self.protocol_fees,
+                fee_mode: self.fee_mode,
+                token_a_fee_balance: self.token_a_fee_balance,
+                token_b_fee_balance: self.token_b_fee_balance,
+                deposit_caps: self.deposit_caps,
+                tvl_cap: self.tvl_cap,
+                deposits_paused: self.deposits_paused,
+                swap_access_mode: self.swap_access_mode,
+                price_band: self.price_band,
+                lot_sizes: self.lot_sizes,
+                weights: self.curve.weights(),
+                is_active: self.is_pool_active().is_ok(),
+            }
+        }
+
+        /// Attaches hooks to this pool, replacing any previously attached ones
+        pub fn set_hooks(&mut self, hooks: Option<Box<dyn PoolHooks<T, Id>>>) {
+            self.hooks = hooks;
+        }
+
+        /// Sends the pool's events to `event_sink`, or stops emitting with `None`
+        pub fn set_event_sink(&mut self, event_sink: Option<Box<dyn EventSink<T, Id>>>) {
+            self.event_sink = event_sink;
+        }
+
+        fn emit(&mut self, event: Event<T, Id>) {
+            self.record_history(&event);
+            self.dispatch(event);
+        }
+
+        fn dispatch(&mut self, event: Event<T, Id>) {
+            if let Some(buffered_events) = self.buffered_events.as_mut() {
+                buffered_events.push(event);
+            } else if let Some(event_sink) = self.event_sink.as_mut() {
+                event_sink.emit(event);
+            }
+        }
+
+        /// Starts or stops recording the operations of every account for
+        /// `get_history`. Recording is off by default to save memory.
+        pub fn set_history_enabled(&mut self, history_enabled: bool) {
+            self.history_enabled = history_enabled;
+        }
+
+        fn record_history(&mut self, event: &Event<T, Id>) {
+            if !self.history_enabled {
+                return;
+            }
+            // a redenomination rescales the shares of every holder
+            let account_ids: Vec<Id> = match event {
+                Event::SharesRedenominated { .. } => self.user_pool_shares.iter()
+                    .filter(|(_, shares)| **shares > T::default())
+                    .map(|(account_id, _)| account_id.clone())
+                    .collect(),
+                _ => event.get_account_ids().into_iter().cloned().collect(),
+            };
+            for account_id in account_ids {
+                let entry = HistoryEntry {
+                    sequence: self.history_sequence,
+                    event: event.clone(),
+                    balance: self.get_account_balance(account_id.clone()),
+                };
+                self.history.entry(account_id).or_default().push(entry);
+            }
+            self.history_sequence += 1;
+        }
+
+        /// Entries of the account's history in `range`, oldest first. The
+        /// range is clamped to the recorded entries.
+        pub fn get_history(&self, account_id: &Id, range: Range<usize>) -> &[HistoryEntry<T, Id>] {
+            let history = self.history.get(account_id).map_or(&[][..], Vec::as_slice);
+            let end = range.end.min(history.len());
+            &history[range.start.min(end)..end]
+        }
+
+        /// Replaces the clock used for time locks, deadlines, reward emission
+        /// and the price oracle, `SystemClock` by default
+        pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+            self.clock = Rc::from(clock);
+        }
+
+        /// Time from which the account's shares can be withdrawn or
+        /// transferred, `None` if they were never locked
+        pub fn get_unlock_time(&self, account_id: Id) -> Option<u64> {
+            self.unlock_times.get(&account_id).copied()
+        }
+
+        fn is_unlocked(&self, account_id: &Id) -> Result<(), Error> {
+            match self.unlock_times.get(account_id) {
+                Some(&unlock_time) if self.clock.now() < unlock_time => Err(Error::SharesLocked),
+                _ => Ok(()),
+            }
+        }
+
+        /// Runs `f` against the attached hooks, if any. The hooks are detached
+        /// for the duration of the call so they only see a snapshot of the pool.
+        fn run_hooks(&mut self, f: impl FnOnce(&mut dyn PoolHooks<T, Id>, &PoolState<T>) -> Result<(), Error>)
+            -> Result<(), Error> {
+            let Some(mut hooks) = self.hooks.take() else {
+                return Ok(());
+            };
+            let result = f(hooks.as_mut(), &self.pool_state());
+            self.hooks = Some(hooks);
+            result
+        }
+
+        /// Sets the withdrawal fee in basis points of the redeemed amounts.
+        /// The fee stays in the pool for the remaining liquidity providers.
+        pub fn set_withdrawal_fee(&mut self, fee_bps: u32) {
+            self.withdrawal_fee_bps = if fee_bps >= 10_000 { 0 } else { fee_bps };
+        }
+
+        pub fn get_withdrawal_fee(&self) -> u32 {
+            self.withdrawal_fee_bps
+        }
+
+        /// Makes the withdrawal fee decay linearly to zero over
+        /// `decay_period` from an account's latest deposit. Zero, the
+        /// default, charges the full fee regardless of age.
+        pub fn set_withdrawal_fee_decay(&mut self, decay_period: u64) {
+            self.withdrawal_fee_decay = decay_period;
+        }
+
+        pub fn get_withdrawal_fee_decay(&self) -> u64 {
+            self.withdrawal_fee_decay
+        }
+
+        /// Withdrawal fee in basis points for redeeming `share`, decayed by the
+        /// age of the account's latest deposit when an account is given. It is
+        /// waived when every share is redeemed, as no provider remains to
+        /// receive it.
+        fn get_withdrawal_fee_for(&self, account_id: Option<&Id>, share: T) -> u32 {
+            if share == self.total_pool_shares {
+                return 0;
+            }
+            let deposit_time = account_id.and_then(|account_id| self.deposit_times.get(account_id));
+            let Some(deposit_time) = deposit_time.filter(|_| self.withdrawal_fee_decay > 0) else {
+                return self.withdrawal_fee_bps;
+            };
+            let remaining = self.withdrawal_fee_decay.saturating_sub(self.clock.now().saturating_sub(*deposit_time));
+            (u128::from(self.withdrawal_fee_bps) * u128::from(remaining) / u128::from(self.withdrawal_fee_decay)) as u32
+        }
+
+        pub fn set_deposit_caps(&mut self, deposit_caps: DepositCaps<T>) {
+            self.deposit_caps = deposit_caps;
+        }
+
+        pub fn get_deposit_caps(&self) -> DepositCaps<T> {
+            self.deposit_caps
+        }
+
+        pub fn set_tvl_cap(&mut self, tvl_cap: Option<TvlCap<T>>) {
+            self.tvl_cap = tvl_cap;
+        }
+
+        /// Lifts a pause triggered by `TvlCapAction::Pause`
+        pub fn resume_deposits(&mut self) {
+            self.deposits_paused = false;
+        }
+
+        /// Switches how the swap access list is interpreted. The list itself is
+        /// kept, so toggling between modes does not lose entries.
+        pub fn set_swap_access_mode(&mut self, swap_access_mode: SwapAccessMode) {
+            self.swap_access_mode = swap_access_mode;
+        }
+
+        pub fn add_to_swap_access_list(&mut self, account_id: Id) {
+            self.swap_access_list.insert(account_id);
+        }
+
+        pub fn remove_from_swap_access_list(&mut self, account_id: Id) {
+            self.swap_access_list.remove(&account_id);
+        }
+
+        pub fn is_swap_permitted(&self, account_id: &Id) -> bool {
+            let listed = self.swap_access_list.contains(account_id);
+            match self.swap_access_mode {
+                SwapAccessMode::Open => true,
+                SwapAccessMode::Allowlist => listed,
+                SwapAccessMode::Blocklist => !listed,
+            }
+        }
+
+        /// Sets the unit that deposit and swap inputs, and flash swap
+        /// repayments, of each token must be a multiple of. A lot size of zero
+        /// accepts any amount.
+        pub fn set_lot_sizes(&mut self, token_a_lot_size: T, token_b_lot_size: T) {
+            self.lot_sizes = (token_a_lot_size, token_b_lot_size);
+        }
+
+        /// Rounds `amount` of `token` to a multiple of its lot size
+        pub fn round_to_lot(&self, token: Token, amount: T, rounding: Rounding) -> Result<T, Error> {
+            let lot_size = match token {
+                Token::A => self.lot_sizes.0,
+                Token::B => self.lot_sizes.1,
+            };
+            if lot_size == T::default() {
+                return Ok(amount);
+            }
+            mul(mul_div(amount, T::from(1), lot_size, rounding)?, lot_size)
+        }
+
+        /// Lot size check shared by every operation that takes tokens from
+        /// an account into the pool. Zero is a multiple of any lot size.
+        fn is_valid_lot(&self, token_a_amount: T, token_b_amount: T) -> Result<(), Error> {
+            if self.round_to_lot(Token::A, token_a_amount, Rounding::Down)? != token_a_amount
+                || self.round_to_lot(Token::B, token_b_amount, Rounding::Down)? != token_b_amount {
+                return Err(Error::InvalidLotSize);
+            }
+            Ok(())
+        }
+
+        fn get_token_amounts(token: Token, amount: T) -> (T, T) {
+            match token {
+                Token::A => (amount, T::default()),
+                Token::B => (T::default(), amount),
+            }
+        }
+
+        pub fn set_price_band(&mut self, price_band: Option<PriceBand<T>>) {
+            self.price_band = price_band;
+        }
+
+        /// Largest part of `amount_in` that can be swapped without pushing the
+        /// price outside the price band, rounded down to the lot size. The
+        /// whole input is added to the input reserve when pricing, so the fill
+        /// errs on the side of the band.
+        pub fn get_fillable_amount(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            let Some(PriceBand { min_price, max_price }) = self.price_band else {
+                return Ok(amount_in);
+            };
+            let within_band = |amount: T| -> Result<bool, Error> {
+                let (token_a_pool_balance, token_b_pool_balance) = match token_in {
+                    Token::A => {
+                        let amount_out = self.get_swap_amount_for_token_b(amount)?;
+                        (add(self.token_a_pool_balance, amount)?, sub(self.token_b_pool_balance, amount_out)?)
+                    }
+                    Token::B => {
+                        let amount_out = self.get_swap_amount_for_token_a(amount)?;
+                        (sub(self.token_a_pool_balance, amount_out)?, add(self.token_b_pool_balance, amount)?)
+                    }
+                };
+                if token_a_pool_balance == T::default() {
+                    return Ok(false);
+                }
+                let price = mul_div(token_b_pool_balance, T::from(self.precision), token_a_pool_balance, Rounding::Down)?;
+                Ok(price >= min_price && price <= max_price)
+            };
+            if within_band(amount_in)? {
+                return Ok(amount_in);
+            }
+
+            let (mut low, mut high) = (T::default(), amount_in);
+            while low < high {
+                let mid = add(low, div(add(sub(high, low)?, T::from(1))?, T::from(2))?)?;
+                match within_band(mid)? {
+                    true => low = mid,
+                    false => high = sub(mid, T::from(1))?,
+                }
+            }
+            let low = self.round_to_lot(token_in, low, Rounding::Down)?;
+            if low == T::default() && amount_in > T::default() {
+                return Err(Error::PriceBandReached);
+            }
+            Ok(low)
+        }
+
+        /// Pool value in units of `numeraire`, pricing the other reserve at the
+        /// spot price of the pool curve
+        pub fn get_tvl(&self, numeraire: Token) -> Result<T, Error> {
+            self.get_value((self.token_a_pool_balance, self.token_b_pool_balance), numeraire)
+        }
+
+        fn get_value(&self, (token_a_reserve, token_b_reserve): (T, T), numeraire: Token) -> Result<T, Error> {
+            let reserves = (self.normalize_amount(Token::A, token_a_reserve)?, self.normalize_amount(Token::B, token_b_reserve)?);
+            self.denormalize_amount(numeraire, self.curve.value(reserves, numeraire)?)
+        }
+
+        fn is_within_tvl_cap(&mut self, token_a_amount: T, token_b_amount: T) -> Result<(), Error> {
+            if self.deposits_paused {
+                return Err(Error::DepositsPaused);
+            }
+            let Some(tvl_cap) = self.tvl_cap else {
+                return Ok(());
+            };
+            let reserves = (add(self.token_a_pool_balance, token_a_amount)?, add(self.token_b_pool_balance, token_b_amount)?);
+            let tvl = self.get_value(reserves, tvl_cap.numeraire)?;
+            match tvl_cap.action {
+                _ if tvl <= tvl_cap.max_value => Ok(()),
+                TvlCapAction::Reject => Err(Error::TvlCapExceeded),
+                TvlCapAction::Pause => {
+                    self.deposits_paused = true;
+                    self.emit(Event::DepositsPaused { tvl });
+                    Err(Error::DepositsPaused)
+                }
+            }
+        }
+
+        /// Sets the share of every swap fee, in basis points, diverted to the
+        /// insurance fund. Fails with `Error::InvalidFeeShare` for 10_000 or more.
+        pub fn set_insurance_fee_share(&mut self, share_bps: u32) -> Result<(), Error> {
+            if share_bps >= 10_000 {
+                return Err(Error::InvalidFeeShare);
+            }
+            self.insurance_fee_share_bps = share_bps;
+            Ok(())
+        }
+
+        pub fn get_insurance_fund(&self) -> InsuranceFund<T> {
+            self.insurance_fund
+        }
+
+        /// Admin operation that moves insurance fund balances back into the pool
+        /// reserves, e.g. after a modeled exploit or bad-debt event. Fails with
+        /// `Error::Unauthorized` for any account but the owner.
+        pub fn recapitalize_pool(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T) -> Result<(), Error> {
+            self.is_not_reentered()?;
+            self.is_owner(&account_id)?;
+            if token_a_amount > self.insurance_fund.token_a_balance
+                || token_b_amount > self.insurance_fund.token_b_balance {
+                return Err(Error::InsufficientAmount);
+            }
+
+            let insurance_fund = InsuranceFund {
+                token_a_balance: sub(self.insurance_fund.token_a_balance, token_a_amount)?,
+                token_b_balance: sub(self.insurance_fund.token_b_balance, token_b_amount)?,
+                token_a_outflow: add(self.insurance_fund.token_a_outflow, token_a_amount)?,
+                token_b_outflow: add(self.insurance_fund.token_b_outflow, token_b_amount)?,
+                ..self.insurance_fund
+            };
+            let token_a_pool_balance = add(self.token_a_pool_balance, token_a_amount)?;
+            let token_b_pool_balance = add(self.token_b_pool_balance, token_b_amount)?;
+
+            self.insurance_fund = insurance_fund;
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = token_b_pool_balance;
+            self.emit(Event::PoolRecapitalized { token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        /// Lets the swap fee follow recent volatility, or restores the fixed
+        /// fee with `None`. Configurations with `max_fee` of 1000 or more,
+        /// `min_fee` above `max_fee`, or a zero window or spread fall back to
+        /// the fixed fee. The price history restarts either way.
+        pub fn set_dynamic_fee(&mut self, dynamic_fee: Option<DynamicFee>) {
+            self.dynamic_fee = dynamic_fee.filter(|dynamic_fee| {
+                dynamic_fee.max_fee < 1000 && dynamic_fee.min_fee <= dynamic_fee.max_fee
+                    && dynamic_fee.window > 0 && dynamic_fee.max_spread_bps > 0
+            });
+            self.recent_prices.clear();
+        }
+
+        /// Fee per mille charged on the next swap
+        pub fn get_effective_fee(&self) -> u32 {
+            let Some(DynamicFee { min_fee, max_fee, max_spread_bps, .. }) = self.dynamic_fee else {
+                return self.fees;
+            };
+            let (Some(&lowest), Some(&highest)) = (self.recent_prices.iter().min(), self.recent_prices.iter().max()) else {
+                return min_fee;
+            };
+            if lowest == T::default() {
+                return max_fee;
+            }
+            let extra_fee = sub(highest, lowest)
+                .and_then(|spread| mul_div(spread, T::from(10_000), lowest, Rounding::Down))
+                .and_then(|spread_bps| mul_div(
+                    T::from(max_fee - min_fee),
+                    spread_bps.min(T::from(max_spread_bps)),
+                    T::from(max_spread_bps),
+                    Rounding::Down,
+                ));
+            match extra_fee {
+                Ok(extra_fee) => min_fee + extra_fee.to_f64() as u32,
+                Err(_) => max_fee,
+            }
+        }
+
+        fn record_price(&mut self) {
+            if self.token_a_pool_balance == T::default() {
+                return;
+            }
+            let Ok(price) = mul_div(self.token_b_pool_balance, T::from(self.precision), self.token_a_pool_balance, Rounding::Down) else {
+                return;
+            };
+            self.observe_price(price);
+            let Some(DynamicFee { window, .. }) = self.dynamic_fee else {
+                return;
+            };
+            self.recent_prices.push_back(price);
+            while self.recent_prices.len() > window {
+                self.recent_prices.pop_front();
+            }
+        }
+
+        fn observe_price(&mut self, price: T) {
+            let timestamp = self.clock.now();
+            let price_cumulative = match self.price_observations.back() {
+                Some(last) if last.timestamp >= timestamp => {
+                    let price_cumulative = last.price_cumulative;
+                    self.price_observations.pop_back();
+                    price_cumulative
+                }
+                Some(last) => get_price_cumulative(last, timestamp),
+                None => T::default(),
+            };
+            self.price_observations.push_back(PriceObservation { timestamp, price_cumulative, price });
+            while self.price_observations.len() > MAX_PRICE_OBSERVATIONS {
+                self.price_observations.pop_front();
+            }
+        }
+
+        /// Time-weighted average price of token A in token B, scaled by the
+        /// precision, over the last `window` units of the clock. Prices are
+        /// observed on every swap, so the window must start after the first
+        /// retained observation. A zero window gives the last observed price.
+        /// The price summed over the window must fit in `T`, and a window
+        /// too long for `T` fails with `Error::Overflow`.
+        pub fn consult(&self, window: u64) -> Result<T, Error> {
+            let last = self.price_observations.back().ok_or(Error::InsufficientHistory)?;
+            if window == 0 {
+                return Ok(last.price);
+            }
+            let now = self.clock.now().max(last.timestamp);
+            let start = now.checked_sub(window).ok_or(Error::InsufficientHistory)?;
+            let first = self.price_observations.iter().rev()
+                .find(|observation| observation.timestamp <= start)
+                .ok_or(Error::InsufficientHistory)?;
+            let price_change = get_price_cumulative(last, now).wrapping_sub(get_price_cumulative(first, start));
+            // like the cumulative price, the window goes in two 32-bit halves
+            let mut window_amount = T::from(window as u32);
+            if window >> 32 != 0 {
+                let shift = mul(T::from(1 << 16), T::from(1 << 16))?;
+                window_amount = add(mul(T::from((window >> 32) as u32), shift)?, window_amount)?;
+            }
+            div(price_change, window_amount)
+        }
+
+        fn get_swap_fee(&self, amount_in: T) -> Result<T, Error> {
+            mul_div(amount_in, T::from(self.get_effective_fee()), T::from(1000), Rounding::Down)
+        }
+
+        fn get_insurance_fee(&self, amount_in: T) -> Result<T, Error> {
+            mul_div(self.get_swap_fee(amount_in)?, T::from(self.insurance_fee_share_bps), T::from(10_000), Rounding::Down)
+        }
+
+        /// Protocol part of the swap fee, limited to what the insurance fund
+        /// leaves of it
+        fn get_protocol_fee(&self, amount_in: T) -> Result<T, Error> {
+            let swap_fee = self.get_swap_fee(amount_in)?;
+            let protocol_fee = mul_div(swap_fee, T::from(self.protocol_fee_share_bps), T::from(10_000), Rounding::Down)?;
+            Ok(protocol_fee.min(sub(swap_fee, self.get_insurance_fee(amount_in)?)?))
+        }
+
+        /// Sets the share of every swap fee, in basis points, diverted to the
+        /// protocol treasury. The insurance fund is served first. Fails with
+        /// `Error::InvalidFeeShare` for 10_000 or more.
+        pub fn set_protocol_fee_share(&mut self, share_bps: u32) -> Result<(), Error> {
+            if share_bps >= 10_000 {
+                return Err(Error::InvalidFeeShare);
+            }
+            self.protocol_fee_share_bps = share_bps;
+            Ok(())
+        }
+
+        /// Protocol fees collected and not yet claimed
+        pub fn get_protocol_fees(&self) -> (T, T) {
+            self.protocol_fees
+        }
+
+        /// Sets the account allowed to run admin operations: claiming
+        /// protocol fees, funding rewards, recapitalizing the pool and
+        /// redenominating shares
+        pub fn set_owner(&mut self, account_id: Id) {
+            self.owner = Some(account_id);
+        }
+
+        fn is_owner(&self, account_id: &Id) -> Result<(), Error> {
+            if self.owner.as_ref() != Some(account_id) {
+                return Err(Error::Unauthorized);
+            }
+            Ok(())
+        }
+
+        /// Pays the protocol fees into the owner's token balances. Fails with
+        /// `Error::Unauthorized` for any other account.
+        pub fn claim_protocol_fees(&mut self, account_id: Id) -> Result<(T, T), Error> {
+            self.is_not_reentered()?;
+            self.is_owner(&account_id)?;
+            let (token_a_amount, token_b_amount) = self.protocol_fees;
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_balance = add(token_a_balance, token_a_amount)?;
+            let token_b_balance = add(token_b_balance, token_b_amount)?;
+
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.protocol_fees = Default::default();
+            self.emit(Event::ProtocolFeesCollected { account_id, token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        /// Chooses whether swap fees compound into the reserves or accumulate
+        /// separately. Fees already accumulated stay claimable after a switch.
+        pub fn set_fee_mode(&mut self, fee_mode: FeeMode) {
+            self.fee_mode = fee_mode;
+        }
+
+        /// Fees accumulated for an account that `claim_fees` would pay out
+        pub fn get_unclaimed_fees(&self, account_id: Id) -> Result<(T, T), Error> {
+            let (token_a_owed, token_b_owed) = self.unclaimed_fees.get(&account_id).copied().unwrap_or_default();
+            let (token_a_pending, token_b_pending) = self.get_pending_fees(&account_id)?;
+            Ok((add(token_a_owed, token_a_pending)?, add(token_b_owed, token_b_pending)?))
+        }
+
+        /// Pays the account's accumulated fees into its token balances
+        pub fn claim_fees(&mut self, account_id: Id) -> Result<(T, T), Error> {
+            self.is_not_reentered()?;
+            let (token_a_amount, token_b_amount) = self.get_unclaimed_fees(account_id.clone())?;
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_fee_balance = sub(self.token_a_fee_balance, token_a_amount)?;
+            let token_b_fee_balance = sub(self.token_b_fee_balance, token_b_amount)?;
+            let token_a_balance = add(token_a_balance, token_a_amount)?;
+            let token_b_balance = add(token_b_balance, token_b_amount)?;
+
+            self.settle_fees(&account_id)?;
+            self.unclaimed_fees.remove(&account_id);
+            for position in self.positions.values_mut().filter(|position| position.owner == account_id) {
+                position.accrued_fees = (T::default(), T::default());
+            }
+            self.token_a_fee_balance = token_a_fee_balance;
+            self.token_b_fee_balance = token_b_fee_balance;
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.emit(Event::FeesCollected { account_id, token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        fn get_pending_fees(&self, account_id: &Id) -> Result<(T, T), Error> {
+            let shares = self.user_pool_shares.get(account_id).copied().unwrap_or_default();
+            let (growth_a, growth_b) = self.fee_growth_per_share;
+            let (checkpoint_a, checkpoint_b) = self.fee_growth_checkpoints.get(account_id).copied().unwrap_or_default();
+            let pending = |growth: T, checkpoint: T| {
+                mul_div(sub(growth, checkpoint)?, shares, T::from(FEE_GROWTH_PRECISION), Rounding::Down)
+            };
+            Ok((pending(growth_a, checkpoint_a)?, pending(growth_b, checkpoint_b)?))
+        }
+
+        /// Sets the reward tokens emitted per clock unit, shared among
+        /// liquidity providers pro rata to their shares. Emissions up to now
+        /// are accounted at the previous rate.
+        pub fn set_reward_rate(&mut self, reward_rate: T) -> Result<(), Error> {
+            self.update_rewards()?;
+            self.reward_rate = reward_rate;
+            Ok(())
+        }
+
+        /// Issues reward tokens to the account, raising the reward token supply
+        pub fn get_free_reward_tokens(&mut self, account_id: Id, amount: T) -> Result<(), Error> {
+            let reward_balance = add(self.get_reward_balance(account_id.clone()), amount)?;
+            let reward_total_supply = add(self.reward_total_supply, amount)?;
+
+            self.reward_balances.insert(account_id.clone(), reward_balance);
+            self.reward_total_supply = reward_total_supply;
+            self.emit(Event::RewardTokensIssued { account_id, amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        pub fn get_reward_total_supply(&self) -> T {
+            self.reward_total_supply
+        }
+
+        /// Moves reward tokens from the owner's reward balance into the
+        /// reserve to be emitted. Only the owner may fund rewards.
+        pub fn fund_rewards(&mut self, account_id: Id, amount: T) -> Result<(), Error> {
+            self.is_not_reentered()?;
+            self.is_owner(&account_id)?;
+            self.is_valid_amount(&account_id, &self.reward_balances, amount)?;
+            let reward_balance = sub(self.get_reward_balance(account_id.clone()), amount)?;
+
+            self.update_rewards()?;
+            self.reward_reserve = add(self.reward_reserve, amount)?;
+            self.reward_balances.insert(account_id.clone(), reward_balance);
+            self.emit(Event::RewardsFunded { account_id, amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        /// Reward tokens funded and not yet emitted
+        pub fn get_reward_reserve(&self) -> T {
+            self.reward_reserve
+        }
+
+        /// Reward tokens claimed by the account
+        pub fn get_reward_balance(&self, account_id: Id) -> T {
+            self.reward_balances.get(&account_id).copied().unwrap_or_default()
+        }
+
+        /// Reward per share after emitting everything due up to now, and the
+        /// reward tokens that emission takes from the reserve. Nothing is
+        /// emitted while the pool has no shares.
+        fn get_reward_emission(&self) -> Result<(T, T), Error> {
+            let now = self.clock.now();
+            let elapsed = self.last_reward_time.map_or(0, |last_reward_time| now.saturating_sub(last_reward_time));
+            if self.total_pool_shares == T::default() || elapsed == 0 {
+                return Ok((self.reward_per_share, T::default()));
+            }
+            let elapsed = T::from(u32::try_from(elapsed).unwrap_or(u32::MAX));
+            let emitted = self.reward_rate.checked_mul(elapsed)
+                .map_or(self.reward_reserve, |emitted| emitted.min(self.reward_reserve));
+            let growth = mul_div(emitted, T::from(FEE_GROWTH_PRECISION), self.total_pool_shares, Rounding::Down)?;
+            Ok((add(self.reward_per_share, growth)?, emitted))
+        }
+
+        fn update_rewards(&mut self) -> Result<(), Error> {
+            let (reward_per_share, emitted) = self.get_reward_emission()?;
+            self.reward_reserve = sub(self.reward_reserve, emitted)?;
+            self.reward_outstanding = add(self.reward_outstanding, emitted)?;
+            self.reward_per_share = reward_per_share;
+            self.last_reward_time = Some(self.clock.now());
+            Ok(())
+        }
+
+        /// Rewards the account has earned and not yet claimed
+        pub fn pending_rewards(&self, account_id: Id) -> Result<T, Error> {
+            let (reward_per_share, _) = self.get_reward_emission()?;
+            let shares = self.user_pool_shares.get(&account_id).copied().unwrap_or_default();
+            let checkpoint = self.reward_checkpoints.get(&account_id).copied().unwrap_or_default();
+            let pending = mul_div(sub(reward_per_share, checkpoint)?, shares, T::from(FEE_GROWTH_PRECISION), Rounding::Down)?;
+            add(self.unclaimed_rewards.get(&account_id).copied().unwrap_or_default(), pending)
+        }
+
+        /// Pays the account's pending rewards into its reward balance
+        pub fn claim_rewards(&mut self, account_id: Id) -> Result<T, Error> {
+            self.is_not_reentered()?;
+            let amount = self.pending_rewards(account_id.clone())?;
+            let reward_balance = add(self.get_reward_balance(account_id.clone()), amount)?;
+            self.settle_fees(&account_id)?;
+            self.unclaimed_rewards.remove(&account_id);
+            self.reward_outstanding = sub(self.reward_outstanding, amount)?;
+            self.reward_balances.insert(account_id.clone(), reward_balance);
+            self.emit(Event::RewardsClaimed { account_id, amount });
+            Ok(amount)
+        }
+
+        /// Moves fees and rewards earned since the last checkpoint into the
+        /// account's unclaimed balances. Must run before the account's shares
+        /// change.
+        fn settle_fees(&mut self, account_id: &Id) -> Result<(), Error> {
+            let rewards_owed = self.pending_rewards(account_id.clone())?;
+            self.update_rewards()?;
+            if rewards_owed > T::default() {
+                self.unclaimed_rewards.insert(account_id.clone(), rewards_owed);
+            }
+            self.reward_checkpoints.insert(account_id.clone(), self.reward_per_share);
+
+            let (token_a_owed, token_b_owed) = self.get_unclaimed_fees(account_id.clone())?;
+            if token_a_owed > T::default() || token_b_owed > T::default() {
+                self.unclaimed_fees.insert(account_id.clone(), (token_a_owed, token_b_owed));
+            }
+            self.fee_growth_checkpoints.insert(account_id.clone(), self.fee_growth_per_share);
+
+            let (growth_a, growth_b) = self.fee_growth_per_share;
+            for position in self.positions.values_mut().filter(|position| &position.owner == account_id) {
+                let (checkpoint_a, checkpoint_b) = position.fee_growth_checkpoint;
+                let earned = |growth: T, checkpoint: T| {
+                    mul_div(sub(growth, checkpoint)?, position.shares, T::from(FEE_GROWTH_PRECISION), Rounding::Down)
+                };
+                position.accrued_fees = (
+                    add(position.accrued_fees.0, earned(growth_a, checkpoint_a)?)?,
+                    add(position.accrued_fees.1, earned(growth_b, checkpoint_b)?)?,
+                );
+                position.fee_growth_checkpoint = self.fee_growth_per_share;
+            }
+            Ok(())
+        }
+
+        /// Parts of the fee on a swap input kept out of the reserves: the
+        /// insurance, protocol and accumulated liquidity provider fees
+        fn get_swap_fee_split(&self, amount_in: T) -> Result<(T, T, T), Error> {
+            let insurance_fee = self.get_insurance_fee(amount_in)?;
+            let protocol_fee = self.get_protocol_fee(amount_in)?;
+            let lp_fee = match self.fee_mode {
+                FeeMode::Compound => T::default(),
+                FeeMode::Accumulate => sub(sub(self.get_swap_fee(amount_in)?, insurance_fee)?, protocol_fee)?,
+            };
+            Ok((insurance_fee, protocol_fee, lp_fee))
+        }
+
+        /// Splits the fee on a swap input between the insurance fund, the
+        /// protocol, the liquidity providers and the reserves. Returns the
+        /// amount that enters the reserves.
+        fn collect_swap_fee(&mut self, amount_in: T, is_token_a: bool) -> Result<T, Error> {
+            let (insurance_fee, protocol_fee, lp_fee) = self.get_swap_fee_split(amount_in)?;
+            let growth = mul_div(lp_fee, T::from(FEE_GROWTH_PRECISION), self.total_pool_shares, Rounding::Down)?;
+            let amount_in = sub(sub(sub(amount_in, insurance_fee)?, protocol_fee)?, lp_fee)?;
+
+            let mut insurance_fund = self.insurance_fund;
+            let mut protocol_fees = self.protocol_fees;
+            let mut fee_growth_per_share = self.fee_growth_per_share;
+            let (mut token_a_fee_balance, mut token_b_fee_balance) = (self.token_a_fee_balance, self.token_b_fee_balance);
+            if is_token_a {
+                insurance_fund.token_a_balance = add(insurance_fund.token_a_balance, insurance_fee)?;
+                insurance_fund.token_a_inflow = add(insurance_fund.token_a_inflow, insurance_fee)?;
+                protocol_fees.0 = add(protocol_fees.0, protocol_fee)?;
+                token_a_fee_balance = add(token_a_fee_balance, lp_fee)?;
+                fee_growth_per_share.0 = add(fee_growth_per_share.0, growth)?;
+            } else {
+                insurance_fund.token_b_balance = add(insurance_fund.token_b_balance, insurance_fee)?;
+                insurance_fund.token_b_inflow = add(insurance_fund.token_b_inflow, insurance_fee)?;
+                protocol_fees.1 = add(protocol_fees.1, protocol_fee)?;
+                token_b_fee_balance = add(token_b_fee_balance, lp_fee)?;
+                fee_growth_per_share.1 = add(fee_growth_per_share.1, growth)?;
+            }
+
+            self.insurance_fund = insurance_fund;
+            self.protocol_fees = protocol_fees;
+            self.fee_growth_per_share = fee_growth_per_share;
+            self.token_a_fee_balance = token_a_fee_balance;
+            self.token_b_fee_balance = token_b_fee_balance;
+            Ok(amount_in)
+        }
+
+        pub fn set_threshold_policy(&mut self, threshold_policy: ThresholdPolicy) {
+            self.threshold_policy = threshold_policy;
+        }
+
+        /// Tokens held for the account under `ThresholdPolicy::Accrue`
+        pub fn get_deposit_credit(&self, account_id: Id) -> (T, T) {
+            self.deposit_credits.get(&account_id).copied().unwrap_or_default()
+        }
+
+        /// Breaks down where each token's supply is held and compares it with
+        /// the issued total, and compares account shares with the pool total
+        pub fn audit(&self) -> Result<AuditReport<T>, Error> {
+            let sum = |balances: &Balances<Id, T>| {
+                balances.values().try_fold(T::default(), |total, balance| add(total, *balance))
+            };
+            let (token_a_credits, token_b_credits) = self.deposit_credits.values()
+                .try_fold((T::default(), T::default()), |(token_a_total, token_b_total), (token_a, token_b)| {
+                    Ok::<_, Error>((add(token_a_total, *token_a)?, add(token_b_total, *token_b)?))
+                })?;
+            let (token_a_escrow, token_b_escrow) = self.limit_orders.values()
+                .try_fold((T::default(), T::default()), |(token_a_total, token_b_total), order| match order.token_in {
+                    Token::A => Ok::<_, Error>((add(token_a_total, order.remaining)?, token_b_total)),
+                    Token::B => Ok((token_a_total, add(token_b_total, order.remaining)?)),
+                })?;
+
+            Ok(AuditReport {
+                token_a: TokenAudit {
+                    account_balances: sum(&self.token_a_user_balance)?,
+                    pool_reserve: self.token_a_pool_balance,
+                    accrued_fees: self.token_a_fee_balance,
+                    insurance_fund: self.insurance_fund.token_a_balance,
+                    protocol_fees: self.protocol_fees.0,
+                    deposit_credits: token_a_credits,
+                    limit_order_escrow: token_a_escrow,
+                    total_supply: self.token_a_total_supply,
+                },
+                token_b: TokenAudit {
+                    account_balances: sum(&self.token_b_user_balance)?,
+                    pool_reserve: self.token_b_pool_balance,
+                    accrued_fees: self.token_b_fee_balance,
+                    insurance_fund: self.insurance_fund.token_b_balance,
+                    protocol_fees: self.protocol_fees.1,
+                    deposit_credits: token_b_credits,
+                    limit_order_escrow: token_b_escrow,
+                    total_supply: self.token_b_total_supply,
+                },
+                reward_token: TokenAudit {
+                    account_balances: sum(&self.reward_balances)?,
+                    pool_reserve: self.reward_reserve,
+                    accrued_fees: self.reward_outstanding,
+                    total_supply: self.reward_total_supply,
+                    ..Default::default()
+                },
+                account_shares: sum(&self.user_pool_shares)?,
+                position_shares: self.positions.values()
+                    .try_fold(T::default(), |total, position| add(total, position.shares))?,
+                total_pool_shares: self.total_pool_shares,
+            })
+        }
+
+        /// Fails with `Error::InvariantViolation` unless `audit` balances.
+        /// Mutating calls assert this in debug builds.
+        pub fn verify_invariants(&self) -> Result<(), Error> {
+            match self.audit()?.is_balanced()? {
+                true => Ok(()),
+                false => Err(Error::InvariantViolation),
+            }
+        }
+
+        fn is_within_deposit_caps(&self, account_id: &Id, token_a_amount: T, token_b_amount: T, shares: T)
+            -> Result<(), Error>
+        {
+            let exceeds = |cap: Option<T>, current: T, amount: T| {
+                cap.is_some_and(|cap| current.checked_add(amount).is_none_or(|total| total > cap))
+            };
+            let account_shares = self.user_pool_shares.get(account_id).copied().unwrap_or_default();
+
+            if exceeds(self.deposit_caps.max_token_a_pool_balance, self.token_a_pool_balance, token_a_amount)
+                || exceeds(self.deposit_caps.max_token_b_pool_balance, self.token_b_pool_balance, token_b_amount) {
+                return Err(Error::PoolCapExceeded);
+            }
+            if exceeds(self.deposit_caps.max_account_shares, account_shares, shares) {
+                return Err(Error::AccountCapExceeded);
+            }
+            Ok(())
+        }
+
+        fn is_valid_amount(&self, account_id: &Id, balances: &Balances<Id, T>, amount: T ) -> Result<(), Error> {
+            let account_balance = balances.get(account_id).copied().unwrap_or_default();
+            match amount {
+                _ if amount == T::default() => Err(Error::ZeroAmount),
+                _ if amount > account_balance => Err(Error::InsufficientAmount),
+                _ => Ok(())
+            }
+        }
+
+        fn is_not_reentered(&self) -> Result<(), Error> {
+            match self.locked {
+                true => Err(Error::Reentrancy),
+                false => Ok(()),
+            }
+        }
+
+        fn is_pool_active(&self) -> Result<(), Error> {
+            if self.token_a_pool_balance == T::default() || self.token_b_pool_balance == T::default() {
+                return Err(Error::ZeroLiquidity);
+            }
+            Ok(())
+        }
+
+        fn get_pool_balance(&self) -> Result<T, Error> {
+            self.get_invariant((self.token_a_pool_balance, self.token_b_pool_balance))
+        }
+
+        fn get_invariant(&self, reserves: (T, T)) -> Result<T, Error> {
+            self.curve.invariant(self.normalize_reserves(Token::A, reserves)?)
+        }
+
+        pub fn get_free_tokens(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T)
+            -> Result<(), Error> {
+            let _account_id = &account_id;
+            let token_a_balance = self.token_a_user_balance.get(_account_id).copied().unwrap_or_default();
+            let token_b_balance = self.token_b_user_balance.get(_account_id).copied().unwrap_or_default();
+            let token_a_balance = add(token_a_balance, token_a_amount)?;
+            let token_b_balance = add(token_b_balance, token_b_amount)?;
+            let token_a_total_supply = add(self.token_a_total_supply, token_a_amount)?;
+            let token_b_total_supply = add(self.token_b_total_supply, token_b_amount)?;
+
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.token_a_total_supply = token_a_total_supply;
+            self.token_b_total_supply = token_b_total_supply;
+            self.emit(Event::TokensIssued { account_id, token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        /// Moves pool shares between accounts. Fees accumulated so far stay
+        /// with the sender; the receiver earns from the transfer on.
+        pub fn transfer_shares(&mut self, from: Id, to: Id, share: T) -> Result<(), Error> {
+            self.transfer_shares_of(from, to, share, None)
+        }
+
+        /// Hands one of the account's positions, with all its shares, to
+        /// `to`. The position keeps its id and entry prices; fees it earned
+        /// before the transfer stay claimable by `from`.
+        pub fn transfer_position(&mut self, from: Id, to: Id, position_id: u64) -> Result<T, Error> {
+            let share = self.get_owned_position(&from, position_id)?.shares;
+            self.transfer_shares_of(from, to, share, Some(position_id))?;
+            Ok(share)
+        }
+
+        /// Moves shares out of the position, or out of the oldest positions
+        /// of `from` if none is given
+        fn transfer_shares_of(&mut self, from: Id, to: Id, share: T, position_id: Option<u64>) -> Result<(), Error> {
+            self.is_not_reentered()?;
+            if share == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let from_shares = self.user_pool_shares.get(&from).copied().unwrap_or_default();
+            if share > from_shares {
+                return Err(Error::InsufficientAmount);
+            }
+            self.is_unlocked(&from)?;
+            if from == to {
+                return Ok(());
+            }
+            let to_shares = self.user_pool_shares.get(&to).copied().unwrap_or_default();
+            if self.deposit_caps.max_account_shares.is_some_and(|cap| to_shares.checked_add(share).is_none_or(|total| total > cap)) {
+                return Err(Error::AccountCapExceeded);
+            }
+            let to_shares = add(to_shares, share)?;
+
+            self.settle_fees(&from)?;
+            self.settle_fees(&to)?;
+            self.user_pool_shares.insert(from.clone(), sub(from_shares, share)?);
+            self.user_pool_shares.insert(to.clone(), to_shares);
+            match position_id.and_then(|position_id| self.positions.get_mut(&position_id)) {
+                Some(position) => {
+                    position.owner = to.clone();
+                    position.accrued_fees = (T::default(), T::default());
+                }
+                None => {
+                    for position in self.take_position_shares(&from, share, None)? {
+                        self.open_position(to.clone(), position.shares, position.entry_prices);
+                    }
+                }
+            }
+            self.run_hooks(|hooks, pool| {
+                hooks.after_transfer_shares(pool, &from, &to, share);
+                Ok(())
+            })?;
+            self.emit(Event::SharesTransferred { from, to, share });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        /// Lets `spender` move up to `share` of the owner's shares with
+        /// `transfer_shares_from`, replacing any previous allowance
+        pub fn approve_shares(&mut self, owner: Id, spender: Id, share: T) {
+            match share == T::default() {
+                true => self.share_allowances.remove(&(owner.clone(), spender.clone())),
+                false => self.share_allowances.insert((owner.clone(), spender.clone()), share),
+            };
+            self.emit(Event::SharesApproved { owner, spender, share });
+        }
+
+        pub fn get_share_allowance(&self, owner: Id, spender: Id) -> T {
+            self.share_allowances.get(&(owner, spender)).copied().unwrap_or_default()
+        }
+
+        /// Moves shares out of `from` on its behalf, spending the allowance
+        /// `from` gave `spender`
+        pub fn transfer_shares_from(&mut self, spender: Id, from: Id, to: Id, share: T) -> Result<(), Error> {
+            let allowance = self.get_share_allowance(from.clone(), spender.clone());
+            if share > allowance {
+                return Err(Error::InsufficientAllowance);
+            }
+            self.transfer_shares(from.clone(), to, share)?;
+            self.approve_shares(from, spender, sub(allowance, share)?);
+            Ok(())
+        }
+
+        /// Destroys tokens held by the account, reducing the total supply
+        pub fn burn_tokens(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T)
+            -> Result<(), Error> {
+            self.is_not_reentered()?;
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            if token_a_amount > token_a_balance || token_b_amount > token_b_balance {
+                return Err(Error::InsufficientAmount);
+            }
+            let token_a_total_supply = sub(self.token_a_total_supply, token_a_amount)?;
+            let token_b_total_supply = sub(self.token_b_total_supply, token_b_amount)?;
+
+            self.token_a_user_balance.insert(account_id.clone(), sub(token_a_balance, token_a_amount)?);
+            self.token_b_user_balance.insert(account_id.clone(), sub(token_b_balance, token_b_amount)?);
+            self.token_a_total_supply = token_a_total_supply;
+            self.token_b_total_supply = token_b_total_supply;
+            self.emit(Event::TokensBurned { account_id, token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(())
+        }
+
+        /// Total issued supply of token A and token B
+        pub fn get_total_supply(&self) -> (T, T) {
+            (self.token_a_total_supply, self.token_b_total_supply)
+        }
+
+        pub fn get_account_balance(&self, account_id: Id,) -> (T, T, T) {
+            let token_a_balance = self.token_a_user_balance
+                .get(&account_id).copied().unwrap_or_default();
+            let token_b_balance = self.token_b_user_balance.
+                get(&account_id).copied().unwrap_or_default();
+
+            let pool_shares = self.user_pool_shares
+                .get(&account_id).copied().unwrap_or_default();
+            (token_a_balance, token_b_balance, pool_shares)
+        }
+
+        pub fn get_pool_info(&self) -> (T, T, T, u32) {
+            (
+                self.token_a_pool_balance,
+                self.token_b_pool_balance,
+                self.total_pool_shares,
+                self.get_effective_fee()
+            )
+
+        }
+
+        /// Admin operation that splits or merges all pool shares by a factor.
+        /// Every holder, position, allowance, the account share cap and the total supply
+        /// are updated together, or not at all. On a merge, holder balances
+        /// round down and the total is recomputed from the holders so both
+        /// stay consistent. Fails with `Error::Unauthorized` for any account
+        /// but the owner, and with `Error::InvalidFactor` for a zero factor or
+        /// a merge that would leave a holder with no shares.
+        pub fn redenominate_shares(&mut self, account_id: Id, redenomination: Redenomination<T>) -> Result<T, Error> {
+            self.is_not_reentered()?;
+            self.is_owner(&account_id)?;
+            let rescale = |shares: T| match redenomination {
+                Redenomination::Split(factor) => mul(shares, factor),
+                Redenomination::Merge(factor) => div(shares, factor),
+            };
+            match redenomination {
+                Redenomination::Split(factor) | Redenomination::Merge(factor) if factor == T::default() => {
+                    return Err(Error::InvalidFactor)
+                }
+                _ => {}
+            }
+
+            let mut user_pool_shares = Balances::with_capacity(self.user_pool_shares.len());
+            let mut total_pool_shares = T::default();
+            for (account_id, shares) in self.user_pool_shares.iter() {
+                let rescaled = rescale(*shares)?;
+                if rescaled == T::default() && *shares > T::default() {
+                    return Err(Error::InvalidFactor);
+                }
+                total_pool_shares = add(total_pool_shares, rescaled)?;
+                user_pool_shares.insert(account_id.clone(), rescaled);
+            }
+            let mut share_allowances = HashMap::with_capacity(self.share_allowances.len());
+            for (accounts, allowance) in self.share_allowances.iter() {
+                let allowance = rescale(*allowance)?;
+                if allowance > T::default() {
+                    share_allowances.insert(accounts.clone(), allowance);
+                }
+            }
+            let max_account_shares = self.deposit_caps.max_account_shares.map(rescale).transpose()?;
+
+            let account_ids: Vec<Id> = self.user_pool_shares.keys().cloned().collect();
+            for account_id in account_ids {
+                self.settle_fees(&account_id)?;
+            }
+            // positions round down one by one on a merge, the newest
+            // position of each holder takes the remainder
+            let mut positions = self.positions.clone();
+            let mut position_totals: HashMap<Id, T> = HashMap::new();
+            for position in positions.values_mut() {
+                position.shares = rescale(position.shares)?;
+                let total = position_totals.entry(position.owner.clone()).or_default();
+                *total = add(*total, position.shares)?;
+            }
+            for position in positions.values_mut().rev() {
+                if let Some(total) = position_totals.remove(&position.owner) {
+                    let holder_shares = user_pool_shares.get(&position.owner).copied().unwrap_or_default();
+                    position.shares = add(position.shares, sub(holder_shares, total)?)?;
+                }
+            }
+            positions.retain(|_, position| position.shares > T::default());
+
+            self.user_pool_shares = user_pool_shares;
+            self.positions = positions;
+            self.share_allowances = share_allowances;
+            self.deposit_caps.max_account_shares = max_account_shares;
+            self.total_pool_shares = total_pool_shares;
+            self.emit(Event::SharesRedenominated { redenomination, total_pool_shares });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(total_pool_shares)
+        }
+
+        /// `deposit` that fails with `Error::Expired` once the clock is past
+        /// `deadline`
+        pub fn deposit_with_deadline(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T, deadline: u64)
+            -> Result<T, Error> {
+            self.is_before_deadline(deadline)?;
+            self.deposit(account_id, token_a_amount, token_b_amount)
+        }
+
+        fn is_before_deadline(&self, deadline: u64) -> Result<(), Error> {
+            if self.clock.now() > deadline {
+                return Err(Error::Expired);
+            }
+            Ok(())
+        }
+
+        pub fn deposit(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T)
+            -> Result<T, Error>
+        {
+            self.is_not_reentered()?;
+            self.is_valid_amount(
+                &account_id,
+                &self.token_a_user_balance,
+                token_a_amount
+            )?;
+            self.is_valid_amount(
+                &account_id,
+                &self.token_b_user_balance,
+                token_b_amount
+            )?;
+            self.is_valid_lot(token_a_amount, token_b_amount)?;
+            self.run_hooks(|hooks, pool| {
+                hooks.before_deposit(pool, &account_id, token_a_amount, token_b_amount)
+            })?;
+
+            let (token_a_credit, token_b_credit) = self.get_deposit_credit(account_id.clone());
+            let token_a_total = add(token_a_amount, token_a_credit)?;
+            let token_b_total = add(token_b_amount, token_b_credit)?;
+
+            let mut shares = if self.total_pool_shares == T::default() {
+                mul(T::from(100), T::from(self.precision))?
+            } else {
+                let reserves = (
+                    self.normalize_amount(Token::A, self.token_a_pool_balance)?,
+                    self.normalize_amount(Token::B, self.token_b_pool_balance)?,
+                );
+                let amounts = (
+                    self.normalize_amount(Token::A, token_a_total)?,
+                    self.normalize_amount(Token::B, token_b_total)?,
+                );
+                match self.curve.shares_for_deposit(reserves, amounts, self.total_pool_shares) {
+                    Err(Error::NonEquivalentValue) => self.get_imbalanced_deposit_shares(reserves, amounts)?,
+                    shares => shares?,
+                }
+            };
+
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_balance = sub(token_a_balance, token_a_amount)?;
+            let token_b_balance = sub(token_b_balance, token_b_amount)?;
+
+            if shares == T::default() {
+                match self.threshold_policy {
+                    ThresholdPolicy::Reject => return Err(Error::ThresholdNotReached),
+                    ThresholdPolicy::RoundUp => shares = T::from(1),
+                    ThresholdPolicy::Accrue => {
+                        self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+                        self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+                        self.deposit_credits.insert(account_id.clone(), (token_a_total, token_b_total));
+                        self.emit(Event::Deposited { account_id, token_a_amount, token_b_amount, shares: T::default() });
+                        debug_assert_eq!(self.verify_invariants(), Ok(()));
+                        return Ok(T::default());
+                    }
+                }
+            }
+            self.is_within_deposit_caps(&account_id, token_a_total, token_b_total, shares)?;
+            self.is_within_tvl_cap(token_a_total, token_b_total)?;
+
+            self.mint_shares(account_id.clone(), token_a_total, token_b_total, shares)?;
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.deposit_credits.remove(&account_id);
+            self.emit(Event::Deposited {
+                account_id,
+                token_a_amount: token_a_total,
+                token_b_amount: token_b_total,
+                shares,
+            });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+
+            Ok(shares)
+        }
+
+        /// Shares for a deposit off the pool ratio. The part matching the
+        /// ratio mints shares as usual. Half of the excess is then swapped
+        /// through the curve, paying the swap fee, and the result minted as a
+        /// balanced deposit against the reserves that swap leaves behind.
+        fn get_imbalanced_deposit_shares(&self, (token_a_reserve, token_b_reserve): (T, T), (token_a_amount, token_b_amount): (T, T))
+            -> Result<T, Error> {
+            let total_pool_shares = self.total_pool_shares;
+            let token_a_share = mul_div(total_pool_shares, token_a_amount, token_a_reserve, Rounding::Down)?;
+            let token_b_share = mul_div(total_pool_shares, token_b_amount, token_b_reserve, Rounding::Down)?;
+            let (excess_token, (reserve_in, reserve_out), (amount_in, amount_other), balanced_shares) = match token_a_share > token_b_share {
+                true => (Token::A, (token_a_reserve, token_b_reserve), (token_a_amount, token_b_amount), token_b_share),
+                false => (Token::B, (token_b_reserve, token_a_reserve), (token_b_amount, token_a_amount), token_a_share),
+            };
+
+            let balanced_in = mul_div(balanced_shares, reserve_in, total_pool_shares, Rounding::Up)?;
+            let reserve_in = add(reserve_in, balanced_in)?;
+            let reserve_out = add(reserve_out, amount_other)?;
+            let total_pool_shares = add(total_pool_shares, balanced_shares)?;
+
+            let excess = sub(amount_in, balanced_in)?;
+            let swap_amount = div(excess, T::from(2))?;
+            let swapped_out = self.curve.amount_out((reserve_in, reserve_out), excess_token, swap_amount, self.get_effective_fee())?;
+            let reserve_in = add(reserve_in, swap_amount)?;
+            let reserve_out = sub(reserve_out, swapped_out)?;
+            let zap_shares = mul_div(total_pool_shares, sub(excess, swap_amount)?, reserve_in, Rounding::Down)?
+                .min(mul_div(total_pool_shares, swapped_out, reserve_out, Rounding::Down)?);
+            add(balanced_shares, zap_shares)
+        }
+
+        /// Deposits like `deposit` and locks every share of the account for
+        /// `lock_duration` clock units from now. An existing later unlock
+        /// time is kept.
+        pub fn deposit_locked(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T, lock_duration: u64)
+            -> Result<T, Error> {
+            let unlock_time = self.clock.now().checked_add(lock_duration).ok_or(Error::Overflow)?;
+            let shares = self.deposit(account_id.clone(), token_a_amount, token_b_amount)?;
+            let unlock_time = self.get_unlock_time(account_id.clone()).map_or(unlock_time, |current| current.max(unlock_time));
+            self.unlock_times.insert(account_id.clone(), unlock_time);
+            self.emit(Event::SharesLocked { account_id, unlock_time });
+            Ok(shares)
+        }
+
+        /// Deposits whatever token amounts are needed to mint exactly
+        /// `target_shares`, rounding the amounts up in favour of the pool.
+        /// Returns the token amounts taken from the account.
+        pub fn deposit_for_shares(&mut self, account_id: Id, target_shares: T, max_token_a: T, max_token_b: T)
+            -> Result<(T, T), Error>
+        {
+            self.is_not_reentered()?;
+            self.is_pool_active()?;
+            if target_shares == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let token_a_amount = mul_div(target_shares, self.token_a_pool_balance, self.total_pool_shares, Rounding::Up)?;
+            let token_b_amount = mul_div(target_shares, self.token_b_pool_balance, self.total_pool_shares, Rounding::Up)?;
+            if token_a_amount > max_token_a || token_b_amount > max_token_b {
+                return Err(Error::SlippageExceeded);
+            }
+
+            self.is_valid_lot(token_a_amount, token_b_amount)?;
+            self.is_valid_amount(&account_id, &self.token_a_user_balance, token_a_amount)?;
+            self.is_valid_amount(&account_id, &self.token_b_user_balance, token_b_amount)?;
+            self.run_hooks(|hooks, pool| {
+                hooks.before_deposit(pool, &account_id, token_a_amount, token_b_amount)
+            })?;
+            self.is_within_deposit_caps(&account_id, token_a_amount, token_b_amount, target_shares)?;
+            self.is_within_tvl_cap(token_a_amount, token_b_amount)?;
+
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_balance = sub(token_a_balance, token_a_amount)?;
+            let token_b_balance = sub(token_b_balance, token_b_amount)?;
+            self.mint_shares(account_id.clone(), token_a_amount, token_b_amount, target_shares)?;
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.emit(Event::Deposited { account_id, token_a_amount, token_b_amount, shares: target_shares });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        /// Token B that `deposit_with_a` takes alongside `token_a_amount` at
+        /// the current pool ratio, rounded up
+        pub fn quote_deposit_b_for_a(&self, token_a_amount: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            let shares = mul_div(self.total_pool_shares, token_a_amount, self.token_a_pool_balance, Rounding::Down)?;
+            mul_div(shares, self.token_b_pool_balance, self.total_pool_shares, Rounding::Up)
+        }
+
+        /// Deposits `token_a_amount` with the matching token B amount at the
+        /// current ratio. Returns the shares minted and the token B taken.
+        /// Any token A the ratio leaves over is not taken.
+        pub fn deposit_with_a(&mut self, account_id: Id, token_a_amount: T, max_token_b: T) -> Result<(T, T), Error> {
+            self.is_pool_active()?;
+            let shares = mul_div(self.total_pool_shares, token_a_amount, self.token_a_pool_balance, Rounding::Down)?;
+            let (_, token_b_amount) = self.deposit_for_shares(account_id, shares, token_a_amount, max_token_b)?;
+            Ok((shares, token_b_amount))
+        }
+
+        /// Zaps a single token into the pool: swaps half of `amount` for the
+        /// other token, then deposits as much of both as the pool ratio allows.
+        /// Returns the shares minted and the token A and token B dust left in
+        /// the account. Fails with `Error::SlippageExceeded` before anything
+        /// moves if fewer than `min_shares` would be minted. If the deposit
+        /// fails after the swap, the swap is undone as well.
+        pub fn deposit_single(&mut self, account_id: Id, token: Token, amount: T, min_shares: T)
+            -> Result<(T, T, T), Error> {
+            self.is_pool_active()?;
+            let (token_a_amount, token_b_amount) = Self::get_token_amounts(token, amount);
+            self.is_valid_lot(token_a_amount, token_b_amount)?;
+            let swap_amount = self.round_to_lot(token, div(amount, T::from(2))?, Rounding::Down)?;
+            if swap_amount == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let (reserve_in, reserve_out, amount_out) = match token {
+                Token::A => (self.token_a_pool_balance, self.token_b_pool_balance, self.get_swap_amount_for_token_b(swap_amount)?),
+                Token::B => (self.token_b_pool_balance, self.token_a_pool_balance, self.get_swap_amount_for_token_a(swap_amount)?),
+            };
+            let (insurance_fee, protocol_fee, lp_fee) = self.get_swap_fee_split(swap_amount)?;
+            let reserve_in = add(reserve_in, sub(sub(sub(swap_amount, insurance_fee)?, protocol_fee)?, lp_fee)?)?;
+            let reserve_out = sub(reserve_out, amount_out)?;
+            let amount_in = sub(amount, swap_amount)?;
+            let shares = mul_div(self.total_pool_shares, amount_in, reserve_in, Rounding::Down)?
+                .min(mul_div(self.total_pool_shares, amount_out, reserve_out, Rounding::Down)?);
+            if shares < min_shares {
+                return Err(Error::SlippageExceeded);
+            }
+
+            self.atomically(|amm| {
+                let (token_a_amount, token_b_amount) = match token {
+                    Token::A => {
+                        amm.swap_token_a_for_token_b(account_id.clone(), swap_amount, amount_out)?;
+                        (amount_in, amount_out)
+                    }
+                    Token::B => {
+                        amm.swap_token_b_for_token_a(account_id.clone(), swap_amount, amount_out)?;
+                        (amount_out, amount_in)
+                    }
+                };
+                let (token_a_deposited, token_b_deposited) =
+                    amm.deposit_for_shares(account_id, shares, token_a_amount, token_b_amount)?;
+                Ok((shares, sub(token_a_amount, token_a_deposited)?, sub(token_b_amount, token_b_deposited)?))
+            })
+        }
+
+        /// Adds tokens taken from the account to the reserves and credits it
+        /// with the minted shares. Callers debit the account balances.
+        fn mint_shares(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T, shares: T)
+            -> Result<(), Error> {
+            let token_a_pool_balance = add(self.token_a_pool_balance, token_a_amount)?;
+            let token_b_pool_balance = add(self.token_b_pool_balance, token_b_amount)?;
+            let total_pool_shares = add(self.total_pool_shares, shares)?;
+            let account_shares = add(self.user_pool_shares.get(&account_id).copied().unwrap_or_default(), shares)?;
+
+            self.settle_fees(&account_id)?;
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = token_b_pool_balance;
+            self.total_pool_shares = total_pool_shares;
+            self.deposit_times.insert(account_id.clone(), self.clock.now());
+            self.user_pool_shares.insert(account_id.clone(), account_shares);
+            if shares > T::default() {
+                let entry_prices = (self.spot_price(Token::A)?, self.spot_price(Token::B)?);
+                self.open_position(account_id, shares, entry_prices);
+            }
+            Ok(())
+        }
+
+        fn open_position(&mut self, owner: Id, shares: T, entry_prices: (T, T)) {
+            let position_id = self.next_position_id;
+            self.next_position_id += 1;
+            self.positions.insert(position_id, Position {
+                owner,
+                shares,
+                entry_prices,
+                accrued_fees: Default::default(),
+                fee_growth_checkpoint: self.fee_growth_per_share,
+            });
+        }
+
+        /// Takes `share` out of the position, or out of the account's oldest
+        /// positions if none is given, closing the positions it empties.
+        /// Returns the part taken from each position.
+        fn take_position_shares(&mut self, account_id: &Id, share: T, position_id: Option<u64>)
+            -> Result<Vec<Position<T, Id>>, Error> {
+            let position_ids: Vec<u64> = match position_id {
+                Some(position_id) => vec![position_id],
+                None => self.positions.iter()
+                    .filter(|(_, position)| &position.owner == account_id)
+                    .map(|(position_id, _)| *position_id)
+                    .collect(),
+            };
+            let mut remaining = share;
+            let mut taken = Vec::new();
+            for position_id in position_ids {
+                if remaining == T::default() {
+                    break;
+                }
+                let position = self.positions.get_mut(&position_id).ok_or(Error::PositionNotFound)?;
+                let shares = remaining.min(position.shares);
+                position.shares = sub(position.shares, shares)?;
+                remaining = sub(remaining, shares)?;
+                taken.push(Position { shares, ..position.clone() });
+                if position.shares == T::default() {
+                    self.positions.remove(&position_id);
+                }
+            }
+            Ok(taken)
+        }
+
+        fn get_owned_position(&self, account_id: &Id, position_id: u64) -> Result<&Position<T, Id>, Error> {
+            let position = self.positions.get(&position_id).ok_or(Error::PositionNotFound)?;
+            if &position.owner != account_id {
+                return Err(Error::Unauthorized);
+            }
+            Ok(position)
+        }
+
+        pub fn get_position(&self, position_id: u64) -> Option<&Position<T, Id>> {
+            self.positions.get(&position_id)
+        }
+
+        /// Open positions of the account with their ids, oldest first
+        pub fn list_positions(&self, account_id: &Id) -> Vec<(u64, &Position<T, Id>)> {
+            self.positions.iter()
+                .filter(|(_, position)| &position.owner == account_id)
+                .map(|(position_id, position)| (*position_id, position))
+                .collect()
+        }
+
+        /// Liquidity provider fees the position has earned since its owner
+        /// last claimed them with `claim_fees`. Only
+        /// `FeeMode::Accumulate` pays fees out of the reserves, so positions
+        /// earn none under `FeeMode::Compound`.
+        pub fn get_position_fees(&self, position_id: u64) -> Result<(T, T), Error> {
+            let position = self.positions.get(&position_id).ok_or(Error::PositionNotFound)?;
+            let (growth_a, growth_b) = self.fee_growth_per_share;
+            let (checkpoint_a, checkpoint_b) = position.fee_growth_checkpoint;
+            let earned = |growth: T, checkpoint: T| {
+                mul_div(sub(growth, checkpoint)?, position.shares, T::from(FEE_GROWTH_PRECISION), Rounding::Down)
+            };
+            Ok((
+                add(position.accrued_fees.0, earned(growth_a, checkpoint_a)?)?,
+                add(position.accrued_fees.1, earned(growth_b, checkpoint_b)?)?,
+            ))
+        }
+
+        pub fn get_token_a_swap_amount_out(&self, token_b_amount: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            mul_div(self.token_a_pool_balance, token_b_amount, self.token_b_pool_balance, Rounding::Down)
+        }
+
+        pub fn get_token_b_swap_amount_out(&self, token_a_amount: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            mul_div(self.token_b_pool_balance, token_a_amount, self.token_a_pool_balance, Rounding::Down)
+        }
+
+        /// Token amounts redeemed for `share` after the full withdrawal fee
+        pub fn get_withdraw_amount(&self, share: T) -> Result<(T, T), Error> {
+            self.get_withdraw_amount_for(None, share)
+        }
+
+        /// Token amounts the account redeems for `share` after its withdrawal
+        /// fee, which decays with the age of its latest deposit
+        pub fn get_account_withdraw_amount(&self, account_id: Id, share: T) -> Result<(T, T), Error> {
+            self.get_withdraw_amount_for(Some(&account_id), share)
+        }
+
+        fn get_withdraw_amount_for(&self, account_id: Option<&Id>, share: T) -> Result<(T, T), Error> {
+            self.is_pool_active()?;
+            if share > self.total_pool_shares {
+                return Err(Error::InvalidShare);
+            }
+
+            let token_a_amount = mul_div(self.token_a_pool_balance, share, self.total_pool_shares, Rounding::Down)?;
+            let token_b_amount = mul_div(self.token_b_pool_balance, share, self.total_pool_shares, Rounding::Down)?;
+
+            let withdrawal_fee_bps = self.get_withdrawal_fee_for(account_id, share);
+            let token_a_amount = mul_div(token_a_amount, T::from(10_000 - withdrawal_fee_bps), T::from(10_000), Rounding::Down)?;
+            let token_b_amount = mul_div(token_b_amount, T::from(10_000 - withdrawal_fee_bps), T::from(10_000), Rounding::Down)?;
+
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        pub fn withdraw(&mut self, account_id: Id, share: T) -> Result<(T, T), Error> {
+            self.withdraw_shares_of(account_id, share, None)
+        }
+
+        /// `withdraw` that redeems shares of one of the account's positions
+        pub fn withdraw_position(&mut self, account_id: Id, position_id: u64, share: T) -> Result<(T, T), Error> {
+            if share > self.get_owned_position(&account_id, position_id)?.shares {
+                return Err(Error::InsufficientAmount);
+            }
+            self.withdraw_shares_of(account_id, share, Some(position_id))
+        }
+
+        /// Redeems shares out of the position, or out of the account's oldest
+        /// positions if none is given
+        fn withdraw_shares_of(&mut self, account_id: Id, share: T, position_id: Option<u64>) -> Result<(T, T), Error> {
+            self.is_not_reentered()?;
+            self.is_unlocked(&account_id)?;
+            self.is_valid_amount(
+                &account_id,
+                &self.user_pool_shares,
+                share
+            )?;
+            let (token_a_amount, token_b_amount) = self.get_withdraw_amount_for(Some(&account_id), share)?;
+            let (token_a_balance, token_b_balance, account_shares) = self.get_account_balance(account_id.clone());
+            let account_shares = sub(account_shares, share)?;
+            let total_pool_shares = sub(self.total_pool_shares, share)?;
+            let token_a_pool_balance = sub(self.token_a_pool_balance, token_a_amount)?;
+            let token_b_pool_balance = sub(self.token_b_pool_balance, token_b_amount)?;
+            let token_a_balance = add(token_a_balance, token_a_amount)?;
+            let token_b_balance = add(token_b_balance, token_b_amount)?;
+
+            self.settle_fees(&account_id)?;
+            self.user_pool_shares.insert(account_id.clone(), account_shares);
+            self.take_position_shares(&account_id, share, position_id)?;
+            self.total_pool_shares = total_pool_shares;
+
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = token_b_pool_balance;
+
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.run_hooks(|hooks, pool| {
+                hooks.after_withdraw(pool, &account_id, share, token_a_amount, token_b_amount);
+                Ok(())
+            })?;
+            self.emit(Event::Withdrawn { account_id, share, token_a_amount, token_b_amount });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+
+            Ok((token_a_amount,token_b_amount))
+        }
+
+        /// Redeems `bps` basis points of the account's shares, rounded down.
+        /// 10_000 redeems every share. Fails with `Error::SlippageExceeded`
+        /// if either redeemed amount falls below its minimum.
+        pub fn withdraw_percent(&mut self, account_id: Id, bps: u32, min_token_a: T, min_token_b: T)
+            -> Result<(T, T), Error> {
+            if bps > 10_000 {
+                return Err(Error::InvalidShare);
+            }
+            let account_shares = self.user_pool_shares.get(&account_id).copied().unwrap_or_default();
+            let share = mul_div(account_shares, T::from(bps), T::from(10_000), Rounding::Down)?;
+            let (token_a_amount, token_b_amount) = self.get_withdraw_amount_for(Some(&account_id), share)?;
+            if token_a_amount < min_token_a || token_b_amount < min_token_b {
+                return Err(Error::SlippageExceeded);
+            }
+            self.withdraw(account_id, share)
+        }
+
+        /// Redeems the account's entire share balance, claims its accumulated
+        /// fees and rewards and releases its expired lock in one call. Fails
+        /// with `Error::SharesLocked` while the lock runs; if any step fails
+        /// the pool and the account are left unchanged.
+        pub fn withdraw_all(&mut self, account_id: Id) -> Result<Settlement<T>, Error> {
+            self.is_unlocked(&account_id)?;
+            self.atomically(|amm| {
+                let shares_redeemed = amm.user_pool_shares.get(&account_id).copied().unwrap_or_default();
+                let (token_a_withdrawn, token_b_withdrawn) = match shares_redeemed {
+                    _ if shares_redeemed == T::default() => Default::default(),
+                    _ => amm.withdraw(account_id.clone(), shares_redeemed)?,
+                };
+                let (token_a_fees, token_b_fees) = amm.claim_fees(account_id.clone())?;
+                let rewards = amm.claim_rewards(account_id.clone())?;
+                amm.unlock_times.remove(&account_id);
+
+                Ok(Settlement {
+                    shares_redeemed,
+                    token_a_withdrawn,
+                    token_b_withdrawn,
+                    token_a_fees,
+                    token_b_fees,
+                    rewards,
+                })
+            })
+        }
+
+        /// Redeems shares and swaps the proceeds of the other token into
+        /// `target_token`, returning the consolidated amount of `target_token`.
+        /// Whatever part of the other token the price band leaves unswapped
+        /// stays in the account. Fails with `Error::SlippageExceeded`, leaving
+        /// the pool and the account unchanged, if less than `min_out` results.
+        pub fn withdraw_to(&mut self, account_id: Id, share: T, target_token: Token, min_out: T)
+            -> Result<T, Error> {
+            self.atomically(|amm| {
+                let (token_a_amount, token_b_amount) = amm.withdraw(account_id.clone(), share)?;
+                let amount_out = match target_token {
+                    Token::A if token_b_amount > T::default() => {
+                        add(token_a_amount, amm.swap_token_b_for_token_a(account_id, token_b_amount, T::default())?)?
+                    }
+                    Token::B if token_a_amount > T::default() => {
+                        add(token_b_amount, amm.swap_token_a_for_token_b(account_id, token_a_amount, T::default())?)?
+                    }
+                    Token::A => token_a_amount,
+                    Token::B => token_b_amount,
+                };
+                if amount_out < min_out {
+                    return Err(Error::SlippageExceeded);
+                }
+                Ok(amount_out)
+            })
+        }
+
+        pub fn get_swap_amount_for_token_b(&self, token_a_amount: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            self.get_amount_out(self.token_a_pool_balance, self.token_b_pool_balance, Token::A, token_a_amount)
+        }
+
+        pub fn get_swap_amount_for_token_a(&self, token_b_amount: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            self.get_amount_out(self.token_b_pool_balance, self.token_a_pool_balance, Token::B, token_b_amount)
+        }
+
+        /// Pool reserves of `token_in` and of the other token
+        fn get_reserves(&self, token_in: Token) -> (T, T) {
+            match token_in {
+                Token::A => (self.token_a_pool_balance, self.token_b_pool_balance),
+                Token::B => (self.token_b_pool_balance, self.token_a_pool_balance),
+            }
+        }
+
+        fn get_swap_amount(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            match token_in {
+                Token::A => self.get_swap_amount_for_token_b(amount_in),
+                Token::B => self.get_swap_amount_for_token_a(amount_in),
+            }
+        }
+
+        fn get_amount_out(&self, reserve_in: T, reserve_out: T, token_in: Token, amount_in: T) -> Result<T, Error> {
+            let reserves = self.normalize_reserves(token_in, (reserve_in, reserve_out))?;
+            let amount_in = self.normalize_amount(token_in, amount_in)?;
+            let amount_out = self.curve.amount_out(reserves, token_in, amount_in, self.get_effective_fee())?;
+            self.denormalize_amount(Self::get_token_out(token_in), amount_out)
+        }
+
+        fn get_curve_spot_amount_out(&self, reserves: (T, T), token_in: Token, amount_in: T, fees: u32)
+            -> Result<T, Error> {
+            let reserves = self.normalize_reserves(token_in, reserves)?;
+            let amount_in = self.normalize_amount(token_in, amount_in)?;
+            let amount_out = self.curve.spot_amount_out(reserves, token_in, amount_in, fees)?;
+            self.denormalize_amount(Self::get_token_out(token_in), amount_out)
+        }
+
+        /// Saves the state `restore` returns to. Events are held back from
+        /// here on until `release` sends them to the sink, or `restore`
+        /// drops them.
+        pub(crate) fn checkpoint(&mut self, account_id: &Id) -> SwapCheckpoint<T, Id> {
+            let flushes_events = self.buffered_events.is_none();
+            let buffered_events = self.buffered_events.get_or_insert_with(Vec::new).len();
+            // swaps fill limit orders, crediting their owners
+            let mut account_ids: Vec<&Id> = self.limit_orders.values().map(|order| &order.account_id).collect();
+            account_ids.push(account_id);
+            SwapCheckpoint {
+                account_balances: account_ids.into_iter()
+                    .map(|account_id| (
+                        account_id.clone(),
+                        self.token_a_user_balance.get(account_id).copied(),
+                        self.token_b_user_balance.get(account_id).copied(),
+                    ))
+                    .collect(),
+                limit_orders: self.limit_orders.clone(),
+                pool_balances: (self.token_a_pool_balance, self.token_b_pool_balance),
+                total_supply: (self.token_a_total_supply, self.token_b_total_supply),
+                fee_balances: (self.token_a_fee_balance, self.token_b_fee_balance),
+                fee_growth_per_share: self.fee_growth_per_share,
+                insurance_fund: self.insurance_fund,
+                protocol_fees: self.protocol_fees,
+                recent_prices: self.recent_prices.clone(),
+                price_observations: self.price_observations.clone(),
+                buffered_events,
+                flushes_events,
+                history_sequence: self.history_sequence,
+            }
+        }
+
+        pub(crate) fn restore(&mut self, checkpoint: SwapCheckpoint<T, Id>) {
+            for (account_id, token_a_balance, token_b_balance) in checkpoint.account_balances {
+                for (balances, balance) in [
+                    (&mut self.token_a_user_balance, token_a_balance),
+                    (&mut self.token_b_user_balance, token_b_balance),
+                ] {
+                    match balance {
+                        Some(balance) => balances.insert(account_id.clone(), balance),
+                        None => balances.remove(&account_id),
+                    };
+                }
+            }
+            self.limit_orders = checkpoint.limit_orders;
+            (self.token_a_pool_balance, self.token_b_pool_balance) = checkpoint.pool_balances;
+            (self.token_a_total_supply, self.token_b_total_supply) = checkpoint.total_supply;
+            (self.token_a_fee_balance, self.token_b_fee_balance) = checkpoint.fee_balances;
+            self.fee_growth_per_share = checkpoint.fee_growth_per_share;
+            self.insurance_fund = checkpoint.insurance_fund;
+            self.protocol_fees = checkpoint.protocol_fees;
+            self.recent_prices = checkpoint.recent_prices;
+            self.price_observations = checkpoint.price_observations;
+            if let Some(buffered_events) = self.buffered_events.as_mut() {
+                buffered_events.truncate(checkpoint.buffered_events);
+            }
+            if checkpoint.flushes_events {
+                self.buffered_events = None;
+            }
+            for history in self.history.values_mut() {
+                while history.last().is_some_and(|entry| entry.sequence >= checkpoint.history_sequence) {
+                    history.pop();
+                }
+            }
+            self.history.retain(|_, history| !history.is_empty());
+            self.history_sequence = checkpoint.history_sequence;
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+        }
+
+        /// Keeps the changes made since the checkpoint and sends the events
+        /// it held back
+        pub(crate) fn release(&mut self, checkpoint: SwapCheckpoint<T, Id>) {
+            if !checkpoint.flushes_events {
+                return;
+            }
+            for event in self.buffered_events.take().unwrap_or_default() {
+                self.dispatch(event);
+            }
+        }
+
+        /// Executes the swaps in order, each against the pool state left by
+        /// the previous one, and returns their outputs. If any swap fails the
+        /// pool and the account are restored to their state before the batch.
+        pub fn batch_swap(&mut self, account_id: Id, instructions: Vec<SwapInstruction<T>>) -> Result<Vec<T>, Error> {
+            self.atomically(|amm| {
+                instructions.into_iter()
+                    .map(|instruction| match instruction.token_in {
+                        Token::A => amm.swap_token_a_for_token_b(account_id.clone(), instruction.amount_in, instruction.min_out),
+                        Token::B => amm.swap_token_b_for_token_a(account_id.clone(), instruction.amount_in, instruction.min_out),
+                    })
+                    .collect()
+            })
+        }
+
+        /// Places an order selling `amount_in` of `token_in` for at least
+        /// `min_amount_out` of the other token and moves the input into
+        /// escrow. The order fills, in part or in full, whenever the pool
+        /// can pay its price, including straight away. Returns the order id.
+        pub fn place_limit_order(&mut self, account_id: Id, token_in: Token, amount_in: T, min_amount_out: T)
+            -> Result<u64, Error> {
+            self.is_not_reentered()?;
+            if !self.is_swap_permitted(&account_id) {
+                return Err(Error::SwapNotPermitted);
+            }
+            if min_amount_out == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let balances = match token_in {
+                Token::A => &mut self.token_a_user_balance,
+                Token::B => &mut self.token_b_user_balance,
+            };
+            let balance = balances.get(&account_id).copied().unwrap_or_default();
+            match amount_in {
+                _ if amount_in == T::default() => return Err(Error::ZeroAmount),
+                _ if amount_in > balance => return Err(Error::InsufficientAmount),
+                _ => balances.insert(account_id.clone(), sub(balance, amount_in)?),
+            };
+
+            let order_id = self.next_order_id;
+            self.next_order_id += 1;
+            self.limit_orders.insert(order_id, LimitOrder {
+                account_id: account_id.clone(),
+                token_in,
+                amount_in,
+                min_amount_out,
+                remaining: amount_in,
+                amount_out: T::default(),
+                stalled: false,
+            });
+            self.emit(Event::LimitOrderPlaced { order_id, account_id });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            self.fill_limit_orders();
+            Ok(order_id)
+        }
+
+        /// Cancels the account's order and returns its unfilled input
+        pub fn cancel_limit_order(&mut self, account_id: Id, order_id: u64) -> Result<T, Error> {
+            self.is_not_reentered()?;
+            let order = self.limit_orders.get(&order_id).ok_or(Error::OrderNotFound)?;
+            if order.account_id != account_id {
+                return Err(Error::Unauthorized);
+            }
+            let (token_in, remaining) = (order.token_in, order.remaining);
+            let balances = match token_in {
+                Token::A => &mut self.token_a_user_balance,
+                Token::B => &mut self.token_b_user_balance,
+            };
+            let balance = balances.get(&account_id).copied().unwrap_or_default();
+            balances.insert(account_id.clone(), add(balance, remaining)?);
+            self.limit_orders.remove(&order_id);
+            self.emit(Event::LimitOrderCancelled { order_id, account_id, refund: remaining });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            Ok(remaining)
+        }
+
+        /// Puts the account's stalled order back in line for fills and
+        /// tries to fill it straight away
+        pub fn resume_limit_order(&mut self, account_id: Id, order_id: u64) -> Result<(), Error> {
+            self.is_not_reentered()?;
+            let order = self.limit_orders.get_mut(&order_id).ok_or(Error::OrderNotFound)?;
+            if order.account_id != account_id {
+                return Err(Error::Unauthorized);
+            }
+            order.stalled = false;
+            self.fill_limit_orders();
+            Ok(())
+        }
+
+        pub fn get_limit_order(&self, order_id: u64) -> Option<&LimitOrder<T, Id>> {
+            self.limit_orders.get(&order_id)
+        }
+
+        /// Open orders of the account with their ids, oldest first
+        pub fn get_open_orders(&self, account_id: &Id) -> Vec<(u64, &LimitOrder<T, Id>)> {
+            self.limit_orders.iter()
+                .filter(|(_, order)| &order.account_id == account_id)
+                .map(|(order_id, order)| (*order_id, order))
+                .collect()
+        }
+
+        /// Fills open orders, oldest first, for as much as the pool can pay
+        /// at their price. Orders the price does not reach are left open;
+        /// orders whose fill fails for any other reason are stalled.
+        fn fill_limit_orders(&mut self) {
+            if self.filling_limit_orders {
+                return;
+            }
+            self.filling_limit_orders = true;
+            let order_ids: Vec<u64> = self.limit_orders.iter()
+                .filter(|(_, order)| !order.stalled)
+                .map(|(order_id, _)| *order_id)
+                .collect();
+            for order_id in order_ids {
+                // an unfillable order must not fail the swap that triggered the fill
+                match self.fill_limit_order(order_id) {
+                    Ok(()) | Err(Error::SlippageExceeded | Error::PriceBandReached | Error::InsufficientLiquidity) => {}
+                    Err(error) => {
+                        if let Some(order) = self.limit_orders.get_mut(&order_id) {
+                            order.stalled = true;
+                            let account_id = order.account_id.clone();
+                            self.emit(Event::LimitOrderStalled { order_id, account_id, error });
+                        }
+                    }
+                }
+            }
+            self.filling_limit_orders = false;
+        }
+
+        fn fill_limit_order(&mut self, order_id: u64) -> Result<(), Error> {
+            let order = self.limit_orders.get(&order_id).ok_or(Error::OrderNotFound)?.clone();
+            let min_out = |amount: T| mul_div(amount, order.min_amount_out, order.amount_in, Rounding::Up);
+            // no part of the order fills if even the spot price misses its limit
+            let spot_amount_out = self.get_spot_amount_out(order.token_in, order.remaining);
+            if !spot_amount_out.is_ok_and(|amount_out| min_out(order.remaining).is_ok_and(|min| amount_out >= min)) {
+                return Ok(());
+            }
+            let meets_price = |amount: T| -> Result<bool, Error> {
+                Ok(self.get_swap_amount(order.token_in, amount)? >= min_out(amount)?)
+            };
+
+            // the average price of a swap worsens as it grows
+            let (mut low, mut high) = (T::default(), order.remaining);
+            while low < high {
+                let middle = add(low, div(add(sub(high, low)?, T::from(1))?, T::from(2))?)?;
+                match meets_price(middle).unwrap_or(false) {
+                    true => low = middle,
+                    false => high = sub(middle, T::from(1))?,
+                }
+            }
+            let amount = self.get_fillable_amount(order.token_in, low)?;
+            let amount = self.round_to_lot(order.token_in, amount, Rounding::Down)?;
+            if amount == T::default() {
+                return Ok(());
+            }
+
+            // release the input from escrow and swap it on the owner's behalf
+            let release = |amm: &mut Self, remaining: T, balance_change: (T, bool)| -> Result<(), Error> {
+                let balances = match order.token_in {
+                    Token::A => &mut amm.token_a_user_balance,
+                    Token::B => &mut amm.token_b_user_balance,
+                };
+                let balance = balances.get(&order.account_id).copied().unwrap_or_default();
+                let balance = match balance_change {
+                    (amount, true) => add(balance, amount)?,
+                    (amount, false) => sub(balance, amount)?,
+                };
+                balances.insert(order.account_id.clone(), balance);
+                if let Some(order) = amm.limit_orders.get_mut(&order_id) {
+                    order.remaining = remaining;
+                }
+                Ok(())
+            };
+            release(self, sub(order.remaining, amount)?, (amount, true))?;
+            let result = match order.token_in {
+                Token::A => self.swap_token_a_for_token_b(order.account_id.clone(), amount, min_out(amount)?),
+                Token::B => self.swap_token_b_for_token_a(order.account_id.clone(), amount, min_out(amount)?),
+            };
+            let amount_out = match result {
+                Ok(amount_out) => amount_out,
+                Err(error) => {
+                    release(self, order.remaining, (amount, false))?;
+                    return Err(error);
+                }
+            };
+
+            let filled = self.limit_orders.get_mut(&order_id).ok_or(Error::OrderNotFound)?;
+            filled.amount_out = add(filled.amount_out, amount_out)?;
+            if filled.remaining == T::default() {
+                self.limit_orders.remove(&order_id);
+            }
+            self.emit(Event::LimitOrderFilled { order_id, account_id: order.account_id, amount_in: amount, amount_out });
+            Ok(())
+        }
+
+        /// Output the swap would receive at the current spot price, with no
+        /// price impact. Any shortfall of the actual output against it is
+        /// slippage.
+        pub fn get_spot_amount_out(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            self.is_pool_active()?;
+            self.get_curve_spot_amount_out(self.get_reserves(token_in), token_in, amount_in, self.get_effective_fee())
+        }
+
+        /// Runs `operation` on a copy of the pool and returns its result,
+        /// leaving this pool unchanged even if the operation panics. Hooks
+        /// are not run and events are not sent from the copy.
+        pub fn simulate<R>(&self, operation: impl FnOnce(&mut Self) -> Result<R, Error>) -> Result<R, Error> {
+            operation(&mut self.fork())
+        }
+
+        /// Runs `operation` on a copy of the pool that replaces the pool only
+        /// if the operation succeeds
+        fn atomically<R>(&mut self, operation: impl FnOnce(&mut Self) -> Result<R, Error>) -> Result<R, Error> {
+            let mut fork = self.fork();
+            fork.hooks = self.hooks.take();
+            fork.event_sink = self.event_sink.take();
+            let result = operation(&mut fork);
+            let events = fork.buffered_events.take().unwrap_or_default();
+            match result {
+                Ok(_) => {
+                    fork.buffered_events = self.buffered_events.take();
+                    *self = fork;
+                    for event in events {
+                        self.dispatch(event);
+                    }
+                }
+                Err(_) => self.join(fork),
+            }
+            result
+        }
+
+        /// Copy of the pool state sharing its curve and clock. The copy has
+        /// no hooks or event sink and holds back its events.
+        fn fork(&self) -> Self {
+            Self {
+                fees: self.fees,
+                dynamic_fee: self.dynamic_fee,
+                recent_prices: self.recent_prices.clone(),
+                price_observations: self.price_observations.clone(),
+                precision: self.precision,
+                token_names: self.token_names.clone(),
+                token_config: self.token_config,
+                withdrawal_fee_bps: self.withdrawal_fee_bps,
+                withdrawal_fee_decay: self.withdrawal_fee_decay,
+                deposit_times: self.deposit_times.clone(),
+                deposit_caps: self.deposit_caps,
+                tvl_cap: self.tvl_cap,
+                deposits_paused: self.deposits_paused,
+                swap_access_mode: self.swap_access_mode,
+                swap_access_list: self.swap_access_list.clone(),
+                price_band: self.price_band,
+                lot_sizes: self.lot_sizes,
+                threshold_policy: self.threshold_policy,
+                deposit_credits: self.deposit_credits.clone(),
+                insurance_fee_share_bps: self.insurance_fee_share_bps,
+                insurance_fund: self.insurance_fund,
+                protocol_fee_share_bps: self.protocol_fee_share_bps,
+                protocol_fees: self.protocol_fees,
+                owner: self.owner.clone(),
+                fee_mode: self.fee_mode,
+                token_a_fee_balance: self.token_a_fee_balance,
+                token_b_fee_balance: self.token_b_fee_balance,
+                fee_growth_per_share: self.fee_growth_per_share,
+                fee_growth_checkpoints: self.fee_growth_checkpoints.clone(),
+                unclaimed_fees: self.unclaimed_fees.clone(),
+                hooks: None,
+                event_sink: None,
+                buffered_events: Some(Vec::new()),
+                history_enabled: self.history_enabled,
+                history_sequence: self.history_sequence,
+                history: self.history.clone(),
+                clock: self.clock.clone(),
+                unlock_times: self.unlock_times.clone(),
+                reveal_delay: self.reveal_delay,
+                swap_commitments: self.swap_commitments.clone(),
+                reward_rate: self.reward_rate,
+                reward_reserve: self.reward_reserve,
+                reward_per_share: self.reward_per_share,
+                last_reward_time: self.last_reward_time,
+                reward_checkpoints: self.reward_checkpoints.clone(),
+                unclaimed_rewards: self.unclaimed_rewards.clone(),
+                reward_balances: self.reward_balances.clone(),
+                reward_total_supply: self.reward_total_supply,
+                reward_outstanding: self.reward_outstanding,
+                curve: self.curve.clone(),
+                token_a_total_supply: self.token_a_total_supply,
+                token_b_total_supply: self.token_b_total_supply,
+                total_pool_shares: self.total_pool_shares,
+                token_a_pool_balance: self.token_a_pool_balance,
+                token_b_pool_balance: self.token_b_pool_balance,
+                token_a_user_balance: self.token_a_user_balance.clone(),
+                token_b_user_balance: self.token_b_user_balance.clone(),
+                user_pool_shares: self.user_pool_shares.clone(),
+                share_allowances: self.share_allowances.clone(),
+                positions: self.positions.clone(),
+                next_position_id: self.next_position_id,
+                limit_orders: self.limit_orders.clone(),
+                next_order_id: self.next_order_id,
+                filling_limit_orders: self.filling_limit_orders,
+                locked: self.locked,
+            }
+        }
+
+        /// Takes back the hooks and event sink lent to a fork
+        fn join(&mut self, fork: Self) {
+            self.hooks = fork.hooks;
+            self.event_sink = fork.event_sink;
+        }
+
+        /// Credits `amount_out` of `token_out` to the account before it pays,
+        /// then runs `callback`, which may use the tokens and returns the
+        /// token A and token B amounts the account repays. The repayment,
+        /// less the swap fee, must keep the curve invariant at least where it
+        /// was, or the pool and the account are left as before the call.
+        /// Operations on this pool fail with `Error::Reentrancy` while the
+        /// callback runs.
+        pub fn flash_swap<F>(&mut self, account_id: Id, token_out: Token, amount_out: T, callback: F)
+            -> Result<(T, T), Error>
+        where
+            F: FnOnce(&mut Self, &Id) -> Result<(T, T), Error>,
+        {
+            self.is_not_reentered()?;
+            if !self.is_swap_permitted(&account_id) {
+                return Err(Error::SwapNotPermitted);
+            }
+            self.is_pool_active()?;
+            if amount_out == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            if amount_out >= self.get_reserves(token_out).0 {
+                return Err(Error::InsufficientLiquidity);
+            }
+            self.atomically(|amm| {
+                let invariant_before = amm.get_pool_balance()?;
+                let total_pool_shares = amm.total_pool_shares;
+                let (pool_balance, user_balances) = match token_out {
+                    Token::A => (&mut amm.token_a_pool_balance, &mut amm.token_a_user_balance),
+                    Token::B => (&mut amm.token_b_pool_balance, &mut amm.token_b_user_balance),
+                };
+                *pool_balance = sub(*pool_balance, amount_out)?;
+                let balance = user_balances.get(&account_id).copied().unwrap_or_default();
+                user_balances.insert(account_id.clone(), add(balance, amount_out)?);
+
+                amm.locked = true;
+                let repayment = callback(amm, &account_id);
+                amm.locked = false;
+                let (token_a_amount, token_b_amount) = repayment?;
+                // liquidity added during the callback would raise the
+                // invariant without repaying anything
+                if amm.total_pool_shares != total_pool_shares {
+                    return Err(Error::InsufficientRepayment);
+                }
+                amm.is_valid_lot(token_a_amount, token_b_amount)?;
+                // the reserves the invariant is checked on exclude the whole swap fee
+                let token_a_net = sub(token_a_amount, amm.get_swap_fee(token_a_amount)?)?;
+                let token_b_net = sub(token_b_amount, amm.get_swap_fee(token_b_amount)?)?;
+                let reserves_after_fees = (
+                    add(amm.token_a_pool_balance, token_a_net)?,
+                    add(amm.token_b_pool_balance, token_b_net)?,
+                );
+                if amm.get_invariant(reserves_after_fees)? < invariant_before {
+                    return Err(Error::InsufficientRepayment);
+                }
+
+                for (amount, is_token_a) in [(token_a_amount, true), (token_b_amount, false)] {
+                    if amount == T::default() {
+                        continue;
+                    }
+                    let user_balances = match is_token_a {
+                        true => &amm.token_a_user_balance,
+                        false => &amm.token_b_user_balance,
+                    };
+                    amm.is_valid_amount(&account_id, user_balances, amount)?;
+                    let reserve_amount = amm.collect_swap_fee(amount, is_token_a)?;
+                    let (pool_balance, user_balances) = match is_token_a {
+                        true => (&mut amm.token_a_pool_balance, &mut amm.token_a_user_balance),
+                        false => (&mut amm.token_b_pool_balance, &mut amm.token_b_user_balance),
+                    };
+                    *pool_balance = add(*pool_balance, reserve_amount)?;
+                    let balance = user_balances.get(&account_id).copied().unwrap_or_default();
+                    user_balances.insert(account_id.clone(), sub(balance, amount)?);
+                }
+                amm.record_price();
+                amm.emit(Event::FlashSwapped {
+                    account_id: account_id.clone(),
+                    token_out,
+                    amount_out,
+                    token_a_repaid: token_a_amount,
+                    token_b_repaid: token_b_amount,
+                });
+                debug_assert_eq!(amm.verify_invariants(), Ok(()));
+                amm.fill_limit_orders();
+                Ok((token_a_amount, token_b_amount))
+            })
+        }
+
+        fn preview<R>(&self, account_id: Id, operation: impl FnOnce(&mut Self, Id) -> Result<R, Error>)
+            -> Result<Preview<R, T>, Error> {
+            let balance_before = self.get_account_balance(account_id.clone());
+            self.simulate(|amm| {
+                let result = operation(amm, account_id.clone())?;
+                Ok(Preview { result, balance_before, balance_after: amm.get_account_balance(account_id) })
+            })
+        }
+
+        /// Result of `deposit` and the account's balances it would leave,
+        /// without changing the pool
+        pub fn preview_deposit(&self, account_id: Id, token_a_amount: T, token_b_amount: T)
+            -> Result<Preview<T, T>, Error> {
+            self.preview(account_id, |amm, account_id| amm.deposit(account_id, token_a_amount, token_b_amount))
+        }
+
+        /// Result of `withdraw` and the account's balances it would leave,
+        /// without changing the pool
+        pub fn preview_withdraw(&self, account_id: Id, share: T) -> Result<Preview<(T, T), T>, Error> {
+            self.preview(account_id, |amm, account_id| amm.withdraw(account_id, share))
+        }
+
+        /// Result of swapping `amount_in` of `token_in` and the account's
+        /// balances it would leave, without changing the pool
+        pub fn preview_swap(&self, account_id: Id, token_in: Token, amount_in: T, min_out: T)
+            -> Result<Preview<T, T>, Error> {
+            self.preview(account_id, |amm, account_id| match token_in {
+                Token::A => amm.swap_token_a_for_token_b(account_id, amount_in, min_out),
+                Token::B => amm.swap_token_b_for_token_a(account_id, amount_in, min_out),
+            })
+        }
+
+        /// Marginal price of `token_in` in the other token before fees, scaled
+        /// by the precision
+        pub fn spot_price(&self, token_in: Token) -> Result<T, Error> {
+            self.is_pool_active()?;
+            self.get_curve_spot_amount_out(self.get_reserves(token_in), token_in, T::from(self.precision), 0)
+        }
+
+        /// Average price of `token_in` in the other token a swap of
+        /// `amount_in` would get after fees, scaled by the precision
+        pub fn execution_price(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            if amount_in == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let amount_out = self.get_swap_amount(token_in, amount_in)?;
+            mul_div(amount_out, T::from(self.precision), amount_in, Rounding::Down)
+        }
+
+        /// Shortfall in basis points of a swap's output against the spot
+        /// quote after fees, i.e. how much the trade itself moves the price
+        pub fn price_impact(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            let spot_amount_out = self.get_spot_amount_out(token_in, amount_in)?;
+            if spot_amount_out == T::default() {
+                return Ok(T::default());
+            }
+            let amount_out = self.get_swap_amount(token_in, amount_in)?;
+            let shortfall = spot_amount_out.checked_sub(amount_out).unwrap_or_default();
+            mul_div(shortfall, T::from(10_000), spot_amount_out, Rounding::Up)
+        }
+
+        /// Full preview of swapping `amount_in` of `token_in`. The input is
+        /// reduced to what the price band lets the swap fill, and the fee is
+        /// paid in `token_in`.
+        pub fn quote_swap(&self, token_in: Token, amount_in: T) -> Result<Quote<T>, Error> {
+            if amount_in == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            let (token_a_amount, token_b_amount) = Self::get_token_amounts(token_in, amount_in);
+            self.is_valid_lot(token_a_amount, token_b_amount)?;
+            let amount_in = self.get_fillable_amount(token_in, amount_in)?;
+            let amount_out = self.get_swap_amount(token_in, amount_in)?;
+            // compounded fees stay in the reserves
+            let (insurance_fee, protocol_fee, lp_fee) = self.get_swap_fee_split(amount_in)?;
+            let reserve_amount = sub(sub(sub(amount_in, insurance_fee)?, protocol_fee)?, lp_fee)?;
+            let (reserve_in, reserve_out) = self.get_reserves(token_in);
+            let reserves_after = (add(reserve_in, reserve_amount)?, sub(reserve_out, amount_out)?);
+
+            Ok(Quote {
+                amount_in,
+                amount_out,
+                fee_paid: self.get_swap_fee(amount_in)?,
+                price_before: self.spot_price(token_in)?,
+                price_after: self.get_curve_spot_amount_out(reserves_after, token_in, T::from(self.precision), 0)?,
+                price_impact_bps: self.price_impact(token_in, amount_in)?,
+            })
+        }
+
+        /// Swaps `amount_in` of `token_in`, failing with
+        /// `Error::SlippageExceeded` if the output falls more than
+        /// `tolerance_bps` basis points below the spot quote at execution.
+        /// Tolerances above 10_000 accept any output.
+        pub fn swap_with_tolerance(&mut self, account_id: Id, token_in: Token, amount_in: T, tolerance_bps: u32)
+            -> Result<T, Error> {
+            let fillable_amount = self.get_fillable_amount(token_in, amount_in)?;
+            let spot_amount_out = self.get_spot_amount_out(token_in, fillable_amount)?;
+            let min_out = mul_div(spot_amount_out, T::from(10_000 - tolerance_bps.min(10_000)), T::from(10_000), Rounding::Up)?;
+            match token_in {
+                Token::A => self.swap_token_a_for_token_b(account_id, amount_in, min_out),
+                Token::B => self.swap_token_b_for_token_a(account_id, amount_in, min_out),
+            }
+        }
+
+        /// Time that must pass between `commit_swap` and `reveal_swap`
+        pub fn set_reveal_delay(&mut self, reveal_delay: u64) {
+            self.reveal_delay = reveal_delay;
+        }
+
+        pub fn get_reveal_delay(&self) -> u64 {
+            self.reveal_delay
+        }
+
+        /// Commitment to a swap for `commit_swap`. Amounts are hashed through
+        /// their `Debug` form, as `Amount` does not require `Hash`. The hash
+        /// is not cryptographic, which is enough for simulations.
+        pub fn get_swap_commitment(account_id: &Id, instruction: &SwapInstruction<T>, salt: u64) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            account_id.hash(&mut hasher);
+            instruction.token_in.hash(&mut hasher);
+            format!("{:?}/{:?}", instruction.amount_in, instruction.min_out).hash(&mut hasher);
+            salt.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        /// Records the account's commitment to a swap without revealing it,
+        /// replacing any earlier commitment
+        pub fn commit_swap(&mut self, account_id: Id, commitment: u64) {
+            self.swap_commitments.insert(account_id.clone(), (commitment, self.clock.now()));
+            self.emit(Event::SwapCommitted { account_id, commitment });
+        }
+
+        /// Executes the committed swap once the reveal delay has passed since
+        /// the commitment. The commitment is used up only if the swap succeeds.
+        pub fn reveal_swap(&mut self, account_id: Id, instruction: SwapInstruction<T>, salt: u64) -> Result<T, Error> {
+            let (commitment, committed_at) = self.swap_commitments.get(&account_id)
+                .copied()
+                .ok_or(Error::CommitmentMismatch)?;
+            if commitment != Self::get_swap_commitment(&account_id, &instruction, salt) {
+                return Err(Error::CommitmentMismatch);
+            }
+            if self.clock.now() < committed_at.saturating_add(self.reveal_delay) {
+                return Err(Error::RevealTooEarly);
+            }
+            let amount_out = match instruction.token_in {
+                Token::A => self.swap_token_a_for_token_b(account_id.clone(), instruction.amount_in, instruction.min_out)?,
+                Token::B => self.swap_token_b_for_token_a(account_id.clone(), instruction.amount_in, instruction.min_out)?,
+            };
+            self.swap_commitments.remove(&account_id);
+            Ok(amount_out)
+        }
+
+        /// Swaps `amount_in` of `token_in` for at least `min_out`, failing with
+        /// `Error::Expired` once the clock is past `deadline`
+        pub fn swap_with_deadline(&mut self, account_id: Id, token_in: Token, amount_in: T, min_out: T, deadline: u64)
+            -> Result<T, Error> {
+            self.is_before_deadline(deadline)?;
+            match token_in {
+                Token::A => self.swap_token_a_for_token_b(account_id, amount_in, min_out),
+                Token::B => self.swap_token_b_for_token_a(account_id, amount_in, min_out),
+            }
+        }
+
+        pub fn swap_token_a_for_token_b(&mut self, account_id: Id, token_a_amount: T, min_token_b: T)
+                                        -> Result<T, Error> {
+            self.is_not_reentered()?;
+            if !self.is_swap_permitted(&account_id) {
+                return Err(Error::SwapNotPermitted);
+            }
+            self.is_valid_lot(token_a_amount, T::default())?;
+            let token_a_amount = self.get_fillable_amount(Token::A, token_a_amount)?;
+            #[cfg(debug_assertions)]
+            let k_before = self.get_pool_balance();
+            self.is_valid_amount(
+                &account_id,
+                &self.token_a_user_balance,
+                token_a_amount
+            )?;
+            self.run_hooks(|hooks, pool| {
+                hooks.before_swap(pool, &account_id, Token::A, token_a_amount)
+            })?;
+
+            let token_b_amount = self.get_swap_amount_for_token_b(token_a_amount)?;
+            if token_b_amount < min_token_b {
+                return Err(Error::SlippageExceeded);
+            }
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_balance = sub(token_a_balance, token_a_amount)?;
+            let token_b_balance = add(token_b_balance, token_b_amount)?;
+            let token_b_pool_balance = sub(self.token_b_pool_balance, token_b_amount)?;
+
+            let token_a_reserve_amount = self.collect_swap_fee(token_a_amount, true)?;
+            self.token_a_pool_balance = add(self.token_a_pool_balance, token_a_reserve_amount)?;
+            self.token_b_pool_balance = token_b_pool_balance;
+
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.record_price();
+            self.run_hooks(|hooks, pool| {
+                hooks.after_swap(pool, &account_id, Token::A, token_a_amount, token_b_amount);
+                Ok(())
+            })?;
+            self.emit(Event::Swapped {
+                account_id,
+                token_in: Token::A,
+                amount_in: token_a_amount,
+                amount_out: token_b_amount,
+            });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            #[cfg(debug_assertions)]
+            if let (Ok(k_before), Ok(k_after)) = (k_before, self.get_pool_balance()) {
+                debug_assert!(k_after >= k_before, "swap decreased k from {k_before:?} to {k_after:?}");
+            }
+            self.fill_limit_orders();
+
+            Ok(token_b_amount)
+        }
+
+        pub fn swap_token_b_for_token_a(&mut self, account_id: Id, token_b_amount: T, min_token_a: T)
+                                        -> Result<T, Error> {
+            self.is_not_reentered()?;
+            if !self.is_swap_permitted(&account_id) {
+                return Err(Error::SwapNotPermitted);
+            }
+            self.is_valid_lot(T::default(), token_b_amount)?;
+            let token_b_amount = self.get_fillable_amount(Token::B, token_b_amount)?;
+            #[cfg(debug_assertions)]
+            let k_before = self.get_pool_balance();
+            self.is_valid_amount(
+                &account_id,
+                &self.token_b_user_balance,
+                token_b_amount
+            )?;
+            self.run_hooks(|hooks, pool| {
+                hooks.before_swap(pool, &account_id, Token::B, token_b_amount)
+            })?;
+
+            let token_a_amount = self.get_swap_amount_for_token_a(token_b_amount)?;
+            if token_a_amount < min_token_a {
+                return Err(Error::SlippageExceeded);
+            }
+            let (token_a_balance, token_b_balance, _) = self.get_account_balance(account_id.clone());
+            let token_a_balance = add(token_a_balance, token_a_amount)?;
+            let token_b_balance = sub(token_b_balance, token_b_amount)?;
+            let token_a_pool_balance = sub(self.token_a_pool_balance, token_a_amount)?;
+
+            let token_b_reserve_amount = self.collect_swap_fee(token_b_amount, false)?;
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = add(self.token_b_pool_balance, token_b_reserve_amount)?;
+
+            self.token_a_user_balance.insert(account_id.clone(), token_a_balance);
+            self.token_b_user_balance.insert(account_id.clone(), token_b_balance);
+            self.record_price();
+            self.run_hooks(|hooks, pool| {
+                hooks.after_swap(pool, &account_id, Token::B, token_b_amount, token_a_amount);
+                Ok(())
+            })?;
+            self.emit(Event::Swapped {
+                account_id,
+                token_in: Token::B,
+                amount_in: token_b_amount,
+                amount_out: token_a_amount,
+            });
+            debug_assert_eq!(self.verify_invariants(), Ok(()));
+            #[cfg(debug_assertions)]
+            if let (Ok(k_before), Ok(k_after)) = (k_before, self.get_pool_balance()) {
+                debug_assert!(k_after >= k_before, "swap decreased k from {k_before:?} to {k_after:?}");
+            }
+            self.fill_limit_orders();
+
+            Ok(token_a_amount)
+        }
+    }
+
+    /// Pool state a swap, token issuance or burn by one account can change,
+    /// saved so that the `Router` can undo every pool of a route if a later
+    /// hop fails. Operations on a single pool use `atomically` instead. Only
+    /// the fields listed here are restored, so hook state and anything else
+    /// a hop changes outside them stays as the failed route left it. Events
+    /// are held back until the route is known to stand.
+    pub(crate) struct SwapCheckpoint<T, Id> {
+        account_balances: Vec<(Id, Option<T>, Option<T>)>,
+        limit_orders: BTreeMap<u64, LimitOrder<T, Id>>,
+        pool_balances: (T, T),
+        total_supply: (T, T),
+        fee_balances: (T, T),
+        fee_growth_per_share: (T, T),
+        insurance_fund: InsuranceFund<T>,
+        protocol_fees: (T, T),
+        recent_prices: VecDeque<T>,
+        price_observations: VecDeque<PriceObservation<T>>,
+        /// Events held back before the checkpoint
+        buffered_events: usize,
+        /// Whether the checkpoint started holding back events
+        flushes_events: bool,
+        /// History entries from this sequence number on are dropped on restore
+        history_sequence: u64,
+    }
+
+    /// Read-only borrow of an `Amm` exposing only its query and quote APIs,
+    /// for code that must not be able to change the pool
+    #[derive(Clone, Copy)]
+    pub struct AmmView<'a, T: Amount = u128, Id: AccountId = String> {
+        amm: &'a Amm<T, Id>,
+    }
+
+    impl<'a, T: Amount, Id: AccountId> AmmView<'a, T, Id> {
+        pub fn pool_state(&self) -> PoolState<T> {
+            self.amm.pool_state()
+        }
+
+        pub fn get_pool_info(&self) -> (T, T, T, u32) {
+            self.amm.get_pool_info()
+        }
+
+        pub fn get_token_names(&self) -> (&'a str, &'a str) {
+            self.amm.get_token_names()
+        }
+
+        pub fn get_weights(&self) -> (u32, u32) {
+            self.amm.get_weights()
+        }
+
+        pub fn get_effective_fee(&self) -> u32 {
+            self.amm.get_effective_fee()
+        }
+
+        pub fn get_total_supply(&self) -> (T, T) {
+            self.amm.get_total_supply()
+        }
+
+        pub fn get_account_balance(&self, account_id: Id) -> (T, T, T) {
+            self.amm.get_account_balance(account_id)
+        }
+
+        pub fn get_unclaimed_fees(&self, account_id: Id) -> Result<(T, T), Error> {
+            self.amm.get_unclaimed_fees(account_id)
+        }
+
+        pub fn is_swap_permitted(&self, account_id: &Id) -> bool {
+            self.amm.is_swap_permitted(account_id)
+        }
+
+        pub fn consult(&self, window: u64) -> Result<T, Error> {
+            self.amm.consult(window)
+        }
+
+        pub fn spot_price(&self, token_in: Token) -> Result<T, Error> {
+            self.amm.spot_price(token_in)
+        }
+
+        pub fn execution_price(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            self.amm.execution_price(token_in, amount_in)
+        }
+
+        pub fn price_impact(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            self.amm.price_impact(token_in, amount_in)
+        }
+
+        pub fn quote_swap(&self, token_in: Token, amount_in: T) -> Result<Quote<T>, Error> {
+            self.amm.quote_swap(token_in, amount_in)
+        }
+
+        pub fn get_tvl(&self, numeraire: Token) -> Result<T, Error> {
+            self.amm.get_tvl(numeraire)
+        }
+
+        pub fn get_swap_amount_for_token_a(&self, token_b_amount: T) -> Result<T, Error> {
+            self.amm.get_swap_amount_for_token_a(token_b_amount)
+        }
+
+        pub fn get_swap_amount_for_token_b(&self, token_a_amount: T) -> Result<T, Error> {
+            self.amm.get_swap_amount_for_token_b(token_a_amount)
+        }
+
+        pub fn get_spot_amount_out(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            self.amm.get_spot_amount_out(token_in, amount_in)
+        }
+
+        pub fn get_fillable_amount(&self, token_in: Token, amount_in: T) -> Result<T, Error> {
+            self.amm.get_fillable_amount(token_in, amount_in)
+        }
+
+        pub fn get_withdraw_amount(&self, share: T) -> Result<(T, T), Error> {
+            self.amm.get_withdraw_amount(share)
+        }
+
+        pub fn get_account_withdraw_amount(&self, account_id: Id, share: T) -> Result<(T, T), Error> {
+            self.amm.get_account_withdraw_amount(account_id, share)
+        }
+
+        pub fn audit(&self) -> Result<AuditReport<T>, Error> {
+            self.amm.audit()
+        }
+    }
+
+    /// Builder for `Amm`. Unset options keep the defaults of `Amm::new(0)`.
+    pub struct AmmBuilder<T: Amount = u128, Id: AccountId = String> {
+        fees: u32,
+        precision: u32,
+        token_names: (String, String),
+        token_config: TokenConfig,
+        curve: Box<dyn Curve<T>>,
+        initial_reserves: Option<(Id, T, T)>,
+    }
+
+    impl<T: Amount, Id: AccountId> Default for AmmBuilder<T, Id> {
+        fn default() -> Self {
+            Self {
+                fees: 0,
+                precision: PRECISION,
+                token_names: (String::from("A"), String::from("B")),
+                token_config: TokenConfig::default(),
+                curve: Box::new(ConstantProduct),
+                initial_reserves: None,
+            }
+        }
+    }
+
+    impl<T: Amount, Id: AccountId> AmmBuilder<T, Id> {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Swap fee per mille, values of 1000 and above mean no fee
+        pub fn fees(mut self, fees: u32) -> Self {
+            self.fees = fees;
+            self
+        }
+
+        /// Scale of prices and of the first deposit, which mints
+        /// `100 * precision` shares. Zero keeps the default.
+        pub fn precision(mut self, precision: u32) -> Self {
+            self.precision = if precision == 0 { PRECISION } else { precision };
+            self
+        }
+
+        pub fn token_names(mut self, token_a_name: &str, token_b_name: &str) -> Self {
+            self.token_names = (String::from(token_a_name), String::from(token_b_name));
+            self
+        }
+
+        pub fn token_config(mut self, token_config: TokenConfig) -> Self {
+            self.token_config = token_config;
+            self
+        }
+
+        pub fn curve(mut self, curve: Box<dyn Curve<T>>) -> Self {
+            self.curve = curve;
+            self
+        }
+
+        /// Seeds the pool by issuing the tokens to `account_id` and depositing
+        /// them, so the account holds the initial shares
+        pub fn initial_reserves(mut self, account_id: Id, token_a_amount: T, token_b_amount: T) -> Self {
+            self.initial_reserves = Some((account_id, token_a_amount, token_b_amount));
+            self
+        }
+
+        pub fn build(self) -> Result<Amm<T, Id>, Error> {
+            let mut amm = Amm {
+                precision: self.precision,
+                token_names: self.token_names,
+                token_config: self.token_config,
+                curve: Rc::from(self.curve),
+                ..Amm::new(self.fees)
+            };
+            if let Some((account_id, token_a_amount, token_b_amount)) = self.initial_reserves {
+                amm.get_free_tokens(account_id.clone(), token_a_amount, token_b_amount)?;
+                amm.deposit(account_id, token_a_amount, token_b_amount)?;
+            }
+            Ok(amm)
+        }
+    }
+
+    /// Token pair with the names in sorted order, so both orientations of a
+    /// pair map to the same pool, followed by the pool fee tier
+    pub type PoolKey = (String, String, u32);
+
+    fn pool_key(token_a_name: &str, token_b_name: &str, fees: u32) -> PoolKey {
+        match token_a_name <= token_b_name {
+            true => (String::from(token_a_name), String::from(token_b_name), fees),
+            false => (String::from(token_b_name), String::from(token_a_name), fees),
+        }
+    }
+
+    /// Fee the registry charges for creating a pool. It is paid in `token`,
+    /// which the pool must trade, out of the creator's registry balance into
+    /// the registry treasury, and does not touch the initial reserves.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CreationFee<T> {
+        pub token: String,
+        pub amount: T,
+    }
+
+    /// Collection of pools, at most one per token pair and fee tier. Pools
+    /// are kept ordered by pair and tier so iteration and audits are
+    /// reproducible.
+    pub struct PoolRegistry<T: Amount = u128, Id: AccountId = String> {
+        pools: BTreeMap<PoolKey, Amm<T, Id>>,
+        creation_fee: Option<CreationFee<T>>,
+        min_initial_reserves: T,
+        treasury: BTreeMap<String, T>,
+        balances: HashMap<(Id, String), T>,
+    }
+
+    impl<T: Amount, Id: AccountId> Default for PoolRegistry<T, Id> {
+        fn default() -> Self {
+            Self {
+                pools: BTreeMap::new(),
+                creation_fee: None,
+                min_initial_reserves: T::default(),
+                treasury: BTreeMap::new(),
+                balances: HashMap::new(),
+            }
+        }
+    }
+
+    impl<T: Amount, Id: AccountId> PoolRegistry<T, Id> {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn set_creation_fee(&mut self, creation_fee: Option<CreationFee<T>>) {
+            self.creation_fee = creation_fee;
+        }
+
+        /// Smallest amount of each token a new pool must be seeded with.
+        /// Zero allows unseeded pools.
+        pub fn set_min_initial_reserves(&mut self, min_initial_reserves: T) {
+            self.min_initial_reserves = min_initial_reserves;
+        }
+
+        /// Creation fees collected in `token`
+        pub fn get_treasury_balance(&self, token: &str) -> T {
+            self.treasury.get(token).copied().unwrap_or_default()
+        }
+
+        /// Issues `amount` of `token` to the account's registry balance, out
+        /// of which it pays creation fees
+        pub fn get_free_tokens(&mut self, account_id: Id, token: &str, amount: T) -> Result<(), Error> {
+            let balance = self.get_balance(&account_id, token);
+            self.balances.insert((account_id, token.to_string()), add(balance, amount)?);
+            Ok(())
+        }
+
+        /// Account's registry balance of `token`
+        pub fn get_balance(&self, account_id: &Id, token: &str) -> T {
+            self.balances.get(&(account_id.clone(), token.to_string())).copied().unwrap_or_default()
+        }
+
+        /// Builds a pool and registers it under the token names and fee tier
+        /// set on the builder. The pool keeps the token order it was built with.
+        /// The creator pays the creation fee, if any, out of its registry
+        /// balance into the treasury, and is issued the initial reserves and
+        /// deposits them.
+        pub fn create_pool(&mut self, mut builder: AmmBuilder<T, Id>) -> Result<&mut Amm<T, Id>, Error> {
+            let (token_a_name, token_b_name) = &builder.token_names;
+            if token_a_name == token_b_name {
+                return Err(Error::InvalidTokenPair);
+            }
+            let fee_balance = match (&self.creation_fee, &builder.initial_reserves) {
+                (None, _) => None,
+                (Some(CreationFee { token, amount }), Some((account_id, _, _)))
+                    if token == token_a_name || token == token_b_name => {
+                    let balance = self.get_balance(account_id, token);
+                    let balance = balance.checked_sub(*amount).ok_or(Error::CreationFeeNotPaid)?;
+                    Some(((account_id.clone(), token.clone()), balance))
+                }
+                (Some(_), _) => return Err(Error::CreationFeeNotPaid),
+            };
+            let initial_reserves = builder.initial_reserves.take();
+            let (token_a_amount, token_b_amount) = initial_reserves.as_ref()
+                .map_or(Default::default(), |(_, token_a_amount, token_b_amount)| (*token_a_amount, *token_b_amount));
+            if self.min_initial_reserves > T::default()
+                && (token_a_amount < self.min_initial_reserves || token_b_amount < self.min_initial_reserves) {
+                return Err(Error::InsufficientInitialLiquidity);
+            }
+
+            let mut amm = builder.build()?;
+            let key = pool_key(&amm.token_names.0, &amm.token_names.1, amm.fees);
+            if self.pools.contains_key(&key) {
+                return Err(Error::PoolAlreadyExists);
+            }
+            if let Some((account_id, token_a_amount, token_b_amount)) = initial_reserves {
+                amm.get_free_tokens(account_id.clone(), token_a_amount, token_b_amount)?;
+                amm.deposit(account_id, token_a_amount, token_b_amount)?;
+            }
+            if let (Some(CreationFee { token, amount }), Some((key, balance))) = (&self.creation_fee, fee_balance) {
+                let treasury_balance = add(self.get_treasury_balance(token), *amount)?;
+                self.balances.insert(key, balance);
+                self.treasury.insert(token.clone(), treasury_balance);
+            }
+            Ok(self.pools.entry(key).or_insert(amm))
+        }
+
+        pub fn get_pool(&self, token_a_name: &str, token_b_name: &str, fees: u32) -> Option<&Amm<T, Id>> {
+            self.pools.get(&pool_key(token_a_name, token_b_name, fees))
+        }
+
+        pub fn get_pool_mut(&mut self, token_a_name: &str, token_b_name: &str, fees: u32) -> Option<&mut Amm<T, Id>> {
+            self.pools.get_mut(&pool_key(token_a_name, token_b_name, fees))
+        }
+
+        /// Quotes swapping `amount_in` of `token_in` for `token_out` in every
+        /// fee tier of the pair and returns the tier paying the most, with
+        /// its output. Ties go to the lower tier. Fails with
+        /// `Error::InsufficientLiquidity` if no tier can fill the trade.
+        pub fn get_best_quote(&self, token_in: &str, token_out: &str, amount_in: T) -> Result<(u32, T), Error> {
+            if token_in == token_out {
+                return Err(Error::InvalidTokenPair);
+            }
+            let mut best_quote: Option<(u32, T)> = None;
+            for ((_, _, fees), amm) in self.get_tiers(token_in, token_out) {
+                let amount_out = match amm.token_names.0 == token_in {
+                    true => amm.get_swap_amount_for_token_b(amount_in),
+                    false => amm.get_swap_amount_for_token_a(amount_in),
+                };
+                if let Ok(amount_out) = amount_out {
+                    if best_quote.is_none_or(|(_, best_amount_out)| amount_out > best_amount_out) {
+                        best_quote = Some((*fees, amount_out));
+                    }
+                }
+            }
+            best_quote.ok_or(Error::InsufficientLiquidity)
+        }
+
+        fn get_tiers(&self, token_a_name: &str, token_b_name: &str) -> impl Iterator<Item = (&PoolKey, &Amm<T, Id>)> {
+            self.pools.range(pool_key(token_a_name, token_b_name, 0)..=pool_key(token_a_name, token_b_name, u32::MAX))
+        }
+
+        /// Fee tiers with a pool for the pair, in ascending order
+        pub fn get_fee_tiers(&self, token_a_name: &str, token_b_name: &str) -> Vec<u32> {
+            self.get_tiers(token_a_name, token_b_name).map(|((_, _, fees), _)| *fees).collect()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = (&PoolKey, &Amm<T, Id>)> {
+            self.pools.iter()
+        }
+
+        pub fn iter_mut(&mut self) -> impl Iterator<Item = (&PoolKey, &mut Amm<T, Id>)> {
+            self.pools.iter_mut()
+        }
+
+        /// Audits every pool, each of which keeps its own token ledger
+        pub fn audit(&self) -> Result<Vec<(&PoolKey, AuditReport<T>)>, Error> {
+            self.pools.iter().map(|(pair, amm)| Ok((pair, amm.audit()?))).collect()
+        }
+
+        pub fn len(&self) -> usize {
+            self.pools.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.pools.is_empty()
+        }
+    }
+
+    impl<T: Amount, Id: AccountId> AutomatedMarketMaker<T, Id> for Amm<T, Id> {
+        fn deposit(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T) -> Result<T, Error> {
+            Amm::deposit(self, account_id, token_a_amount, token_b_amount)
+        }
+
+        fn withdraw(&mut self, account_id: Id, share: T) -> Result<(T, T), Error> {
+            Amm::withdraw(self, account_id, share)
+        }
+
+        fn swap_token_a_for_token_b(&mut self, account_id: Id, token_a_amount: T, min_token_b: T)
+            -> Result<T, Error> {
+            Amm::swap_token_a_for_token_b(self, account_id, token_a_amount, min_token_b)
+        }
+
+        fn swap_token_b_for_token_a(&mut self, account_id: Id, token_b_amount: T, min_token_a: T)
+            -> Result<T, Error> {
+            Amm::swap_token_b_for_token_a(self, account_id, token_b_amount, min_token_a)
+        }
+
+        fn get_swap_amount_for_token_b(&self, token_a_amount: T) -> Result<T, Error> {
+            Amm::get_swap_amount_for_token_b(self, token_a_amount)
+        }
+
+        fn get_swap_amount_for_token_a(&self, token_b_amount: T) -> Result<T, Error> {
+            Amm::get_swap_amount_for_token_a(self, token_b_amount)
+        }
+
+        fn get_withdraw_amount(&self, share: T) -> Result<(T, T), Error> {
+            Amm::get_withdraw_amount(self, share)
+        }
+
+        fn get_pool_info(&self) -> (T, T, T, u32) {
+            Amm::get_pool_info(self)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::cell::Cell;
+
+        type Amm = super::Amm<u128>;
+        type PoolState = super::PoolState<u128>;
+        fn get_account_id() -> String {
+            String::from("account-1")
+        }
+
+        /// Deterministic xorshift generator so randomized checks are reproducible
+        struct Rng(u64);
+
+        impl Rng {
+            fn next(&mut self, low: u32, high: u32) -> u32 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                low + (self.0 % u64::from(high - low)) as u32
+            }
+        }
+
+        /// Builds a pool on `curve` with random fees and reserves small enough
+        /// for `u128` math
+        fn random_pool(rng: &mut Rng, curve: Box<dyn Curve<u128>>) -> Amm {
+            let mut amm = Amm::with_curve(rng.next(0, 100), curve);
+            let (token_a_amount, token_b_amount) = (u128::from(rng.next(10, 500)), u128::from(rng.next(10, 500)));
+            amm.get_free_tokens(String::from("lp"), token_a_amount, token_b_amount).unwrap();
+            amm.deposit(String::from("lp"), token_a_amount, token_b_amount).unwrap();
+            amm
+        }
+
+        /// Swaps `amount` of A for B and the proceeds back, asserting no A is gained
+        fn check_swap_round_trip(amm: &mut Amm, amount: u128) {
+            amm.get_free_tokens(get_account_id(), amount, 0).unwrap();
+            let before = amm.get_account_balance(get_account_id());
+            let Ok(token_b_amount) = amm.swap_token_a_for_token_b(get_account_id(), amount, 0) else {
+                return;
+            };
+            if token_b_amount > 0 {
+                amm.swap_token_b_for_token_a(get_account_id(), token_b_amount, 0).unwrap();
+            }
+            let after = amm.get_account_balance(get_account_id());
+            assert!(after.0 <= before.0, "swap round trip of {amount} returned {after:?} from {before:?}");
+            assert!(after.1 <= before.1);
+        }
+
+        /// Deposits at the pool ratio and withdraws the minted shares, asserting no token is gained
+        fn check_deposit_round_trip(amm: &mut Amm, token_a_amount: u128) {
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            let token_b_amount = token_a_amount * token_b_pool_balance / token_a_pool_balance;
+            amm.get_free_tokens(get_account_id(), token_a_amount, token_b_amount).unwrap();
+            let before = amm.get_account_balance(get_account_id());
+            let Ok(shares) = amm.deposit(get_account_id(), token_a_amount, token_b_amount) else {
+                return;
+            };
+            amm.withdraw(get_account_id(), shares).unwrap();
+            let after = amm.get_account_balance(get_account_id());
+            assert!(after.0 <= before.0 && after.1 <= before.1 && after.2 <= before.2,
+                "deposit round trip of {token_a_amount} returned {after:?} from {before:?}");
+        }
+
+        #[test]
+        fn test_no_free_money_round_trips() {
+            let mut rng = Rng(0x5eed);
+            for _ in 0..500 {
+                let curves: [Box<dyn Curve<u128>>; 3] =
+                    [Box::new(ConstantProduct), Box::new(ConstantSum), Box::new(Weighted::new(80, 20).unwrap())];
+                for curve in curves {
+                    let mut amm = random_pool(&mut rng, curve);
+                    let amount = u128::from(rng.next(1, 500));
+                    check_swap_round_trip(&mut amm, amount);
+                    let amount = u128::from(rng.next(1, 500));
+                    check_deposit_round_trip(&mut amm, amount);
+                }
+            }
+        }
+
+        #[test]
+        fn test_rounding_never_decreases_k() {
+            let mut rng = Rng(0xc0ffee);
+            for _ in 0..200 {
+                let mut amm = random_pool(&mut rng, Box::new(ConstantProduct));
+                amm.set_withdrawal_fee(rng.next(0, 100));
+                amm.get_free_tokens(get_account_id(), 100_000, 100_000).unwrap();
+                for _ in 0..20 {
+                    let (token_a_before, token_b_before, shares_before, _) = amm.get_pool_info();
+                    let amount = u128::from(rng.next(1, 300));
+                    let _ = match rng.next(0, 4) {
+                        0 => amm.swap_token_a_for_token_b(get_account_id(), amount, 0).map(|_| ()),
+                        1 => amm.swap_token_b_for_token_a(get_account_id(), amount, 0).map(|_| ()),
+                        2 => amm.deposit_for_shares(get_account_id(), amount, u128::MAX, u128::MAX).map(|_| ()),
+                        _ => amm.withdraw(String::from("lp"), amount.min(shares_before)).map(|_| ()),
+                    };
+                    let (token_a_after, token_b_after, shares_after, _) = amm.get_pool_info();
+                    if shares_after == shares_before {
+                        assert!(token_a_after * token_b_after >= token_a_before * token_b_before);
+                    } else {
+                        assert!(token_a_after * shares_before >= token_a_before * shares_after);
+                        assert!(token_b_after * shares_before >= token_b_before * shares_after);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_verify_invariants() {
+            let mut amm = Amm::new(30);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.set_insurance_fee_share(2_000).unwrap();
+            amm.get_free_tokens(get_account_id(), 1_000, 2_000).unwrap();
+            amm.deposit(get_account_id(), 100, 200).unwrap();
+            amm.swap_token_a_for_token_b(get_account_id(), 50, 0).unwrap();
+            amm.withdraw(get_account_id(), 1_000).unwrap();
+            assert_eq!(amm.verify_invariants(), Ok(()));
+
+            amm.total_pool_shares += 1;
+            assert_eq!(amm.verify_invariants(), Err(Error::InvariantViolation));
+            let report = amm.audit().unwrap();
+            assert_eq!(report.account_shares + 1, report.total_pool_shares);
+            assert_eq!(report.token_a.is_balanced(), Ok(true));
+            amm.total_pool_shares -= 1;
+            amm.token_b_pool_balance += 1;
+            assert_eq!(amm.verify_invariants(), Err(Error::InvariantViolation));
+            let report = amm.audit().unwrap();
+            assert_eq!(report.token_b.total_held(), Ok(report.token_b.total_supply + 1));
+            assert!(report.token_a.accrued_fees > 0 && report.token_b.accrued_fees == 0);
+        }
+
+        #[test]
+        fn test_constructor() {
+            let amm = Amm::new(0);
+            assert_eq!(amm.get_account_balance(get_account_id()), (0, 0, 0));
+            assert_eq!(amm.get_pool_info(), (0, 0, 0, 0));
+        }
+
+        #[test]
+        fn test_builder() {
+            let amm = AmmBuilder::<u128>::new()
+                .fees(3)
+                .precision(1_000)
+                .token_names("USDC", "WETH")
+                .token_config(TokenConfig { token_a_decimals: 6, token_b_decimals: 18 })
+                .initial_reserves(get_account_id(), 2_000, 1)
+                .build()
+                .unwrap();
+            assert_eq!(amm.get_pool_info(), (2_000, 1, 100_000, 3));
+            assert_eq!(amm.get_account_balance(get_account_id()), (0, 0, 100_000));
+            assert_eq!(amm.get_token_names(), ("USDC", "WETH"));
+            assert_eq!(amm.get_token_config().token_b_decimals, 18);
+            assert_eq!(amm.verify_invariants(), Ok(()));
+
+            let amm = AmmBuilder::<u64>::new().initial_reserves(get_account_id(), 0, 1).build();
+            assert_eq!(amm.err(), Some(Error::ZeroAmount));
+        }
+
+        #[test]
+        fn test_pool_registry() {
+            let mut registry = PoolRegistry::<u128>::new();
+            registry.create_pool(AmmBuilder::new().fees(3).token_names("WETH", "USDC")).unwrap();
+            registry.create_pool(AmmBuilder::new().token_names("WETH", "DAI")).unwrap();
+            assert_eq!(registry.create_pool(AmmBuilder::new().fees(3).token_names("USDC", "WETH")).err(),
+                Some(Error::PoolAlreadyExists));
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("DAI", "DAI")).err(),
+                Some(Error::InvalidTokenPair));
+            assert_eq!(registry.len(), 2);
+
+            let pool = registry.get_pool_mut("USDC", "WETH", 3).unwrap();
+            assert_eq!(pool.get_token_names(), ("WETH", "USDC"));
+            pool.get_free_tokens(get_account_id(), 10, 20).unwrap();
+            pool.deposit(get_account_id(), 10, 20).unwrap();
+            assert_eq!(registry.get_pool("WETH", "USDC", 3).unwrap().get_pool_info().3, 3);
+            assert!(registry.get_pool("WETH", "USDC", 0).is_none());
+            assert!(registry.get_pool("USDC", "DAI", 0).is_none());
+
+            let pools: Vec<&PoolKey> = registry.iter().map(|(key, _)| key).collect();
+            assert_eq!(pools, [
+                &(String::from("DAI"), String::from("WETH"), 0),
+                &(String::from("USDC"), String::from("WETH"), 3),
+            ]);
+        }
+
+        #[test]
+        fn test_pool_creation_fee() {
+            let mut registry = PoolRegistry::<u128>::new();
+            registry.set_creation_fee(Some(CreationFee { token: String::from("USDC"), amount: 50 }));
+            registry.set_min_initial_reserves(100);
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("WETH", "USDC")).err(),
+                Some(Error::CreationFeeNotPaid));
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("WETH", "DAI")
+                .initial_reserves(get_account_id(), 100, 200)).err(), Some(Error::CreationFeeNotPaid));
+            registry.get_free_tokens(get_account_id(), "USDC", 40).unwrap();
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("WETH", "USDC")
+                .initial_reserves(get_account_id(), 100, 200)).err(), Some(Error::CreationFeeNotPaid));
+            registry.get_free_tokens(get_account_id(), "USDC", 20).unwrap();
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("WETH", "USDC")
+                .initial_reserves(get_account_id(), 100, 99)).err(), Some(Error::InsufficientInitialLiquidity));
+            assert_eq!(registry.get_treasury_balance("USDC"), 0);
+            assert_eq!(registry.get_balance(&get_account_id(), "USDC"), 60);
+
+            // the fee comes out of the registry balance, not the seed
+            let pool = registry.create_pool(AmmBuilder::new().token_names("WETH", "USDC")
+                .initial_reserves(get_account_id(), 100, 200)).unwrap();
+            assert_eq!(pool.get_pool_info().1, 200);
+            assert_eq!(pool.get_total_supply(), (100, 200));
+            assert!(pool.audit().unwrap().is_balanced().unwrap());
+            assert_eq!(registry.get_balance(&get_account_id(), "USDC"), 10);
+            registry.get_free_tokens(get_account_id(), "USDC", 40).unwrap();
+            assert_eq!(registry.create_pool(AmmBuilder::new().token_names("USDC", "WETH")
+                .initial_reserves(get_account_id(), 200, 100)).err(), Some(Error::PoolAlreadyExists));
+            assert_eq!(registry.get_treasury_balance("USDC"), 50);
+            assert_eq!(registry.get_balance(&get_account_id(), "USDC"), 50);
+        }
+
+        #[test]
+        fn test_pool_registry_fee_tiers() {
+            let mut registry = PoolRegistry::<u128>::new();
+            for (fees, token_a_amount, token_b_amount) in [(1, 1_000, 2_000), (3, 100_000, 200_000), (10, 1_000_000, 2_000_000)] {
+                registry.create_pool(AmmBuilder::new().fees(fees).token_names("WETH", "USDC")
+                    .initial_reserves(get_account_id(), token_a_amount, token_b_amount)).unwrap();
+            }
+            registry.create_pool(AmmBuilder::new().fees(30).token_names("USDC", "WETH")).unwrap();
+            assert_eq!(registry.len(), 4);
+
+            assert_eq!(registry.get_best_quote("WETH", "USDC", 10), Ok((1, 17)));
+            assert_eq!(registry.get_best_quote("WETH", "USDC", 200), Ok((3, 397)));
+            assert_eq!(registry.get_best_quote("WETH", "USDC", 1_000), Ok((10, 1_978)));
+            assert_eq!(registry.get_best_quote("USDC", "WETH", 100_000), Ok((10, 47_165)));
+            assert_eq!(registry.get_best_quote("WETH", "DAI", 10), Err(Error::InsufficientLiquidity));
+            assert_eq!(registry.get_best_quote("WETH", "WETH", 10), Err(Error::InvalidTokenPair));
+        }
+
+        #[test]
+        fn test_pool_state() {
+            let mut amm = Amm::new(30);
+            assert!(!amm.pool_state().is_active);
+            amm.set_withdrawal_fee(50);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            assert_eq!(amm.pool_state(), super::PoolState {
+                token_a_pool_balance: 10,
+                token_b_pool_balance: 20,
+                total_pool_shares: share,
+                fees: 30,
+                token_config: TokenConfig::default(),
+                withdrawal_fee_bps: 50,
+                insurance_fee_share_bps: 0,
+                insurance_fund: InsuranceFund::default(),
+                protocol_fee_share_bps: 0,
+                protocol_fees: (0, 0),
+                fee_mode: FeeMode::Compound,
+                token_a_fee_balance: 0,
+                token_b_fee_balance: 0,
+                deposit_caps: DepositCaps::default(),
+                tvl_cap: None,
+                deposits_paused: false,
+                swap_access_mode: SwapAccessMode::Open,
+                price_band: None,
+                lot_sizes: (0, 0),
+                weights: (1, 1),
+                is_active: true,
+            });
+        }
+
+        #[test]
+        fn test_amm_view() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+
+            let view = amm.view();
+            assert_eq!(view.get_pool_info(), (50, 100, share, 3));
+            assert_eq!(view.get_account_balance(get_account_id()), (50, 100, share));
+            assert_eq!(view.get_swap_amount_for_token_b(10), amm.get_swap_amount_for_token_b(10));
+            assert_eq!(view.pool_state(), amm.pool_state());
+            assert!(view.audit().unwrap().is_balanced().unwrap());
+        }
+
+        #[test]
+        fn test_get_free_tokens() {
+            let mut amm = Amm::new(100);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            assert_eq!(amm.get_account_balance(get_account_id()), (100, 200, 0));
+        }
+
+        #[test]
+        fn test_total_supply() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 10, 0).unwrap();
+            amm.deposit(get_account_id(), 10, 20).unwrap();
+            assert_eq!(amm.get_total_supply(), (110, 200));
+
+            assert_eq!(amm.burn_tokens(String::from("account-2"), 11, 0), Err(Error::InsufficientAmount));
+            amm.burn_tokens(get_account_id(), 40, 80).unwrap();
+            assert_eq!(amm.get_total_supply(), (70, 120));
+            assert_eq!(amm.get_account_balance(get_account_id()), (50, 100, 100_000_000));
+        }
+
+        #[test]
+        fn test_overflow() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            assert_eq!(amm.get_free_tokens(get_account_id(), u128::MAX, 0), Err(Error::Overflow));
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+
+            amm.get_free_tokens(String::from("whale"), u128::MAX / 2, u128::MAX / 2).unwrap();
+            assert_eq!(amm.deposit(String::from("whale"), u128::MAX / 4, u128::MAX / 2), Err(Error::Overflow));
+            assert_eq!(amm.get_pool_info(), (10, 20, share, 0));
+            assert_eq!(amm.get_account_balance(String::from("whale")), (u128::MAX / 2, u128::MAX / 2, 0));
+        }
+
+        #[test]
+        fn test_mul_div() {
+            assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Down), Ok(u128::MAX));
+            assert_eq!(mul_div::<u128>(1 << 100, 1 << 100, 1 << 90, Rounding::Down), Ok(1 << 110));
+            assert_eq!(mul_div::<u128>(1 << 100, 3, 1 << 99, Rounding::Up), Ok(6));
+            assert_eq!(mul_div(u128::MAX, 3, 7, Rounding::Down), Ok(u128::MAX / 7 * 3 + 1));
+            assert_eq!(mul_div(u128::MAX, 3, 7, Rounding::Up), Ok(u128::MAX / 7 * 3 + 2));
+            assert_eq!(mul_div(u128::MAX, 2, 1, Rounding::Down), Err(Error::Overflow));
+            assert_eq!(mul_div::<u128>(1, 1, 0, Rounding::Up), Err(Error::Overflow));
+        }
+
+        #[test]
+        fn test_large_balances() {
+            let unit = 10u128.pow(18);
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000_000 * unit, 2_000_000 * unit).unwrap();
+            let share = amm.deposit(get_account_id(), 500_000 * unit, 1_000_000 * unit).unwrap();
+            assert_eq!(amm.deposit(get_account_id(), 500_000 * unit, 1_000_000 * unit), Ok(share));
+
+            let token_b_amount = amm.swap_token_a_for_token_b(get_account_id(), 1_000 * unit, 0).unwrap();
+            assert!(token_b_amount > 1_990 * unit && token_b_amount < 2_000 * unit);
+        }
+
+        #[test]
+        fn test_narrow_amount_types() {
+            let mut amm = super::Amm::<u64>::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 500_000, 1_000_000).unwrap();
+            let token_b_amount = amm.swap_token_a_for_token_b(get_account_id(), 1_000, 0).unwrap();
+            assert_eq!(token_b_amount, 1_990);
+
+            let mut amm = super::Amm::<u32>::new(0);
+            amm.get_free_tokens(get_account_id(), u32::MAX, u32::MAX).unwrap();
+            amm.deposit(get_account_id(), u32::MAX / 2, u32::MAX / 2).unwrap();
+            assert_eq!(amm.get_free_tokens(get_account_id(), u32::MAX, 0), Err(Error::Overflow));
+        }
+
+        #[test]
+        fn test_token_decimals() {
+            let usdc = 10u128.pow(6);
+            let eth = 10u128.pow(18);
+            let config = TokenConfig { token_a_decimals: 6, token_b_decimals: 18 };
+            let mut amm = Amm::with_token_config(0, config);
+            assert_eq!(amm.normalize_amount(Token::A, 3 * usdc), Ok(3 * eth));
+            assert_eq!(amm.normalize_amount(Token::B, 3 * eth), Ok(3 * eth));
+
+            amm.get_free_tokens(get_account_id(), 10_000 * usdc, 10 * eth).unwrap();
+            let share = amm.deposit(get_account_id(), 2_000 * usdc, eth).unwrap();
+            assert_eq!(amm.deposit(get_account_id(), 2_000 * usdc, eth), Ok(share));
+
+            let token_b_amount = amm.get_swap_amount_for_token_b(20 * usdc).unwrap();
+            assert!(token_b_amount > eth / 101 && token_b_amount < eth / 100);
+            let imbalanced_share = amm.deposit(get_account_id(), 2_000 * usdc, 2 * eth).unwrap();
+            assert!(imbalanced_share > share * 14 / 10 && imbalanced_share < share * 3 / 2);
+        }
+
+        #[test]
+        fn test_token_decimals_constant_sum() {
+            let usdc = 10u128.pow(6);
+            let dai = 10u128.pow(18);
+            let mut amm: Amm = AmmBuilder::new()
+                .token_config(TokenConfig { token_a_decimals: 6, token_b_decimals: 18 })
+                .curve(Box::new(ConstantSum))
+                .build()
+                .unwrap();
+            amm.get_free_tokens(get_account_id(), 2_000 * usdc, 2_000 * dai).unwrap();
+            amm.deposit(get_account_id(), 1_000 * usdc, 1_000 * dai).unwrap();
+
+            assert_eq!(amm.get_tvl(Token::A), Ok(2_000 * usdc));
+            assert_eq!(amm.get_tvl(Token::B), Ok(2_000 * dai));
+            assert_eq!(amm.spot_price(Token::A), Ok(u128::from(PRECISION) * 10u128.pow(12)));
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 10 * usdc, 0), Ok(10 * dai));
+            assert_eq!(amm.swap_token_b_for_token_a(get_account_id(), 10 * dai, 0), Ok(10 * usdc));
+        }
+
+        #[test]
+        fn test_integer_account_ids() {
+            let mut amm = super::Amm::<u128, u32>::new(0);
+            amm.get_free_tokens(1, 100, 200).unwrap();
+            let share = amm.deposit(1, 10, 20).unwrap();
+            amm.get_free_tokens(2, 10, 0).unwrap();
+            let token_b_amount = amm.swap_token_a_for_token_b(2, 10, 0).unwrap();
+            assert_eq!(amm.get_account_balance(2), (0, token_b_amount, 0));
+            assert_eq!(amm.get_account_balance(1), (90, 180, share));
+        }
+
+        #[test]
+        fn test_account_registry() {
+            let mut accounts = AccountRegistry::new();
+            let lp = accounts.intern("lp").unwrap();
+            let trader = accounts.intern("trader").unwrap();
+            assert_eq!(accounts.intern("lp"), Ok(lp));
+            assert_eq!(accounts.get_handle("trader"), Some(trader));
+            assert_eq!(accounts.get_handle("unknown"), None);
+            assert_eq!(accounts.get_name(trader), Some("trader"));
+            assert_eq!(accounts.len(), 2);
+
+            let mut amm = super::Amm::<u128, AccountHandle>::new(0);
+            amm.get_free_tokens(lp, 100, 200).unwrap();
+            amm.deposit(lp, 100, 200).unwrap();
+            amm.get_free_tokens(trader, 10, 0).unwrap();
+            let token_b_amount = amm.swap_token_a_for_token_b(trader, 10, 0).unwrap();
+            assert_eq!(amm.get_account_balance(trader), (0, token_b_amount, 0));
+        }
+
+        #[test]
+        fn test_zero_liquidity() {
+            let amm = Amm::new(100);
+            let res = amm.get_token_a_swap_amount_out(4);
+            assert_eq!(res, Err(Error::ZeroLiquidity));
+        }
+
+        #[test]
+        fn test_deposit() {
+            let mut amm = Amm::new(100);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(
+                get_account_id(),
+                10,
+                20
+            ).unwrap();
+            assert_eq!(share, 100_000_000);
+            assert_eq!(amm.get_pool_info(), (10, 20, share, 100));
+            assert_eq!(amm.get_account_balance(get_account_id()), (90, 180, share));
+        }
+
+        #[test]
+        fn test_threshold_policy() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            amm.deposit(get_account_id(), 40, 80).unwrap();
+            amm.redenominate_shares(get_account_id(), Redenomination::Merge(10_000_000)).unwrap();
+            assert_eq!(amm.deposit(String::from("account-2"), 2, 4), Err(Error::ThresholdNotReached));
+
+            amm.set_threshold_policy(ThresholdPolicy::RoundUp);
+            assert_eq!(amm.deposit(String::from("account-2"), 2, 4), Ok(1));
+            assert_eq!(amm.get_pool_info(), (42, 84, 11, 0));
+        }
+
+        #[test]
+        fn test_threshold_policy_accrue() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.set_threshold_policy(ThresholdPolicy::Accrue);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            amm.deposit(get_account_id(), 40, 80).unwrap();
+            amm.redenominate_shares(get_account_id(), Redenomination::Merge(10_000_000)).unwrap();
+
+            assert_eq!(amm.deposit(String::from("account-2"), 2, 4), Ok(0));
+            assert_eq!(amm.get_deposit_credit(String::from("account-2")), (2, 4));
+            assert_eq!(amm.get_account_balance(String::from("account-2")), (98, 196, 0));
+            assert_eq!(amm.get_pool_info(), (40, 80, 10, 0));
+
+            assert_eq!(amm.deposit(String::from("account-2"), 2, 4), Ok(1));
+            assert_eq!(amm.get_deposit_credit(String::from("account-2")), (0, 0));
+            assert_eq!(amm.get_account_balance(String::from("account-2")), (96, 192, 1));
+            assert_eq!(amm.get_pool_info(), (44, 88, 11, 0));
+        }
+
+        #[test]
+        fn test_deposit_for_shares() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 15).unwrap();
+
+            assert_eq!(amm.deposit_for_shares(String::from("account-2"), share / 4, 2, 4), Err(Error::SlippageExceeded));
+            assert_eq!(amm.deposit_for_shares(String::from("account-2"), share / 4, 3, 4), Ok((3, 4)));
+            assert_eq!(amm.get_account_balance(String::from("account-2")), (97, 196, share / 4));
+            assert_eq!(amm.get_pool_info(), (13, 19, share + share / 4, 0));
+        }
+
+        #[test]
+        fn test_withdraw() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(
+                get_account_id(),
+                10,
+                20
+            ).unwrap();
+            assert_eq!(amm.withdraw(get_account_id(),share / 5).unwrap(), (2, 4));
+            assert_eq!(amm.get_account_balance(get_account_id()), (92, 184, 4 * share / 5));
+            assert_eq!(amm.get_pool_info(), (8, 16, 4 * share / 5, 0));
+        }
+
+        #[test]
+        fn test_withdrawal_fee() {
+            let mut amm = Amm::new(0);
+            amm.set_withdrawal_fee(500);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(
+                get_account_id(),
+                10,
+                20
+            ).unwrap();
+            assert_eq!(amm.get_withdraw_amount(share / 2).unwrap(), (4, 9));
+            assert_eq!(amm.withdraw(get_account_id(), share / 2).unwrap(), (4, 9));
+            assert_eq!(amm.get_pool_info(), (6, 11, share / 2, 0));
+
+            // nobody is left to receive the fee of the last withdrawal
+            assert_eq!(amm.withdraw(get_account_id(), share / 2).unwrap(), (6, 11));
+            assert_eq!(amm.get_pool_info(), (0, 0, 0, 0));
+        }
+
+        #[test]
+        fn test_withdrawal_fee_decay() {
+            let now = Rc::new(Cell::new(1_000));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.set_withdrawal_fee(1_000);
+            amm.set_withdrawal_fee_decay(100);
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 1_000, 1_000).unwrap();
+            let share = amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            amm.deposit(String::from("account-2"), 1_000, 1_000).unwrap();
+
+            assert_eq!(amm.get_account_withdraw_amount(get_account_id(), share / 2), Ok((450, 450)));
+            now.set(1_075);
+            assert_eq!(amm.get_account_withdraw_amount(get_account_id(), share / 2), Ok((487, 487)));
+            assert_eq!(amm.get_withdraw_amount(share / 2), Ok((450, 450)));
+            assert_eq!(amm.withdraw(get_account_id(), share / 2), Ok((487, 487)));
+            // past the decay period, including the fee left behind above
+            now.set(1_100);
+            assert_eq!(amm.get_account_withdraw_amount(String::from("account-2"), share / 2), Ok((504, 504)));
+        }
+
+        #[test]
+        fn test_deposit_caps() {
+            let mut amm = Amm::new(0);
+            amm.set_deposit_caps(DepositCaps {
+                max_token_a_pool_balance: Some(15),
+                max_account_shares: Some(120_000_000),
+                ..Default::default()
+            });
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+
+            assert_eq!(amm.deposit(String::from("account-2"), 10, 20), Err(Error::PoolCapExceeded));
+            assert_eq!(amm.deposit(get_account_id(), 5, 10), Err(Error::AccountCapExceeded));
+            assert_eq!(amm.deposit(String::from("account-2"), 5, 10), Ok(share / 2));
+            assert_eq!(amm.get_pool_info(), (15, 30, share + share / 2, 0));
+        }
+
+        #[test]
+        fn test_withdraw_to() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            amm.deposit(String::from("account-2"), 10, 20).unwrap();
+
+            assert_eq!(amm.withdraw_to(get_account_id(), share, Token::B, 31), Err(Error::SlippageExceeded));
+            assert_eq!(amm.withdraw_to(get_account_id(), share, Token::B, 30), Ok(30));
+            assert_eq!(amm.get_account_balance(get_account_id()), (90, 210, 0));
+            assert_eq!(amm.get_pool_info(), (20, 10, share, 0));
+        }
+
+        #[test]
+        fn test_withdraw_to_partial_fill() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 100).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 100).unwrap();
+            let share = amm.deposit(get_account_id(), 100, 100).unwrap();
+            amm.deposit(String::from("account-2"), 100, 100).unwrap();
+            let price = u128::from(PRECISION);
+            amm.set_price_band(Some(PriceBand { min_price: price * 9 / 10, max_price: price * 11 / 10 }));
+
+            assert_eq!(amm.withdraw_to(get_account_id(), share, Token::A, 150), Err(Error::SlippageExceeded));
+            assert_eq!(amm.get_account_balance(get_account_id()), (0, 0, share));
+            assert_eq!(amm.get_pool_info(), (200, 200, share * 2, 0));
+
+            let amount_out = amm.withdraw_to(get_account_id(), share, Token::A, 0).unwrap();
+            let (token_a_balance, token_b_balance, _) = amm.get_account_balance(get_account_id());
+            assert_eq!(token_a_balance, amount_out);
+            assert!(amount_out < 150 && token_b_balance > 0);
+        }
+
+        #[test]
+        fn test_tvl_cap() {
+            let mut amm = Amm::new(0);
+            amm.set_tvl_cap(Some(TvlCap { numeraire: Token::B, max_value: 60, action: TvlCapAction::Reject }));
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            assert_eq!(amm.get_tvl(Token::B), Ok(40));
+
+            assert_eq!(amm.deposit(get_account_id(), 10, 20), Err(Error::TvlCapExceeded));
+            assert_eq!(amm.deposit(get_account_id(), 5, 10), Ok(share / 2));
+            assert!(!amm.pool_state().deposits_paused);
+        }
+
+        #[test]
+        fn test_tvl_cap_pause() {
+            let mut amm = Amm::new(0);
+            amm.set_tvl_cap(Some(TvlCap { numeraire: Token::B, max_value: 60, action: TvlCapAction::Pause }));
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+
+            assert_eq!(amm.deposit(get_account_id(), 10, 20), Err(Error::DepositsPaused));
+            assert_eq!(amm.deposit(get_account_id(), 5, 10), Err(Error::DepositsPaused));
+            assert!(amm.pool_state().deposits_paused);
+
+            amm.resume_deposits();
+            assert_eq!(amm.deposit(get_account_id(), 5, 10), Ok(share / 2));
+        }
+
+        #[test]
+        fn test_tvl_cap_prices_through_curve() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut amm = Amm::with_curve(0, Box::new(Weighted::new(80, 20).unwrap()));
+            amm.set_event_sink(Some(Box::new(events.clone())));
+            amm.set_tvl_cap(Some(TvlCap { numeraire: Token::B, max_value: 100, action: TvlCapAction::Pause }));
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            amm.deposit(get_account_id(), 80, 20).unwrap();
+            assert_eq!(amm.get_tvl(Token::B), Ok(100));
+
+            assert_eq!(amm.deposit(get_account_id(), 8, 2), Err(Error::DepositsPaused));
+            assert_eq!(events.borrow().last(), Some(&Event::DepositsPaused { tvl: 110 }));
+        }
+
+        #[test]
+        fn test_swap() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(
+                get_account_id(),
+                50,
+                100
+            ).unwrap();
+            let token_b_amount = amm.swap_token_a_for_token_b(
+                get_account_id(),
+                50,
+                50
+            ).unwrap();
+            assert_eq!(token_b_amount, 50);
+            assert_eq!(amm.get_pool_info(), (100, 50, share, 0));
+            assert_eq!(amm.get_account_balance(get_account_id()), (0, 150, share));
+        }
+
+        #[test]
+        fn test_swap_access_list() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+
+            amm.set_swap_access_mode(SwapAccessMode::Allowlist);
+            amm.add_to_swap_access_list(get_account_id());
+            assert!(amm.swap_token_a_for_token_b(get_account_id(), 10, 0).is_ok());
+            assert_eq!(amm.swap_token_a_for_token_b(String::from("account-2"), 10, 0), Err(Error::SwapNotPermitted));
+
+            amm.set_swap_access_mode(SwapAccessMode::Blocklist);
+            assert_eq!(amm.swap_token_b_for_token_a(get_account_id(), 10, 0), Err(Error::SwapNotPermitted));
+            assert!(amm.swap_token_b_for_token_a(String::from("account-2"), 10, 0).is_ok());
+            assert!(amm.withdraw(get_account_id(), share / 2).is_ok());
+
+            amm.remove_from_swap_access_list(get_account_id());
+            assert!(amm.swap_token_b_for_token_a(get_account_id(), 10, 0).is_ok());
+        }
+
+        #[test]
+        fn test_price_band() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            amm.deposit(get_account_id(), 100, 100).unwrap();
+            let price = u128::from(PRECISION);
+            amm.set_price_band(Some(PriceBand { min_price: price * 9 / 10, max_price: price * 11 / 10 }));
+
+            let token_b_amount = amm.swap_token_a_for_token_b(get_account_id(), 50, 0).unwrap();
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            assert_eq!((token_a_pool_balance, token_b_pool_balance), (105, 100 - token_b_amount));
+            assert_eq!(amm.get_account_balance(get_account_id()).0, 895);
+            assert!(token_b_pool_balance * price / token_a_pool_balance >= price * 9 / 10);
+            let mut result = Ok(0);
+            for _ in 0..10 {
+                result = amm.swap_token_a_for_token_b(get_account_id(), 10, 0);
+                if result.is_err() {
+                    break;
+                }
+            }
+            assert_eq!(result, Err(Error::PriceBandReached));
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            assert!(token_b_pool_balance * price / token_a_pool_balance >= price * 9 / 10);
+
+            assert!(amm.swap_token_b_for_token_a(get_account_id(), 50, 0).is_ok());
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            assert!(token_b_pool_balance * price / token_a_pool_balance <= price * 11 / 10);
+        }
+
+        #[test]
+        fn test_lot_sizes() {
+            let mut amm = Amm::new(0);
+            amm.set_lot_sizes(10, 0);
+            assert_eq!(amm.round_to_lot(Token::A, 25, Rounding::Down), Ok(20));
+            assert_eq!(amm.round_to_lot(Token::A, 25, Rounding::Up), Ok(30));
+            assert_eq!(amm.round_to_lot(Token::B, 25, Rounding::Up), Ok(25));
+
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            assert_eq!(amm.deposit(get_account_id(), 105, 105), Err(Error::InvalidLotSize));
+            amm.deposit(get_account_id(), 100, 100).unwrap();
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 15, 0), Err(Error::InvalidLotSize));
+            assert!(amm.swap_token_a_for_token_b(get_account_id(), 20, 0).is_ok());
+            assert!(amm.swap_token_b_for_token_a(get_account_id(), 15, 0).is_ok());
+
+            let price = u128::from(PRECISION);
+            amm.set_price_band(Some(PriceBand { min_price: price * 7 / 10, max_price: price }));
+            let token_a_amount = amm.get_fillable_amount(Token::A, 50).unwrap();
+            assert!(token_a_amount > 0 && token_a_amount < 50 && token_a_amount.is_multiple_of(10));
+        }
+
+        #[test]
+        fn test_lot_sizes_every_entry_point() {
+            let mut amm = Amm::new(0);
+            amm.set_lot_sizes(10, 10);
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            let share = amm.deposit(get_account_id(), 100, 100).unwrap();
+
+            assert_eq!(amm.deposit_for_shares(get_account_id(), share / 20, 100, 100), Err(Error::InvalidLotSize));
+            assert_eq!(amm.deposit_for_shares(get_account_id(), share / 10, 100, 100), Ok((10, 10)));
+            assert_eq!(amm.deposit_with_a(get_account_id(), 15, 100), Err(Error::InvalidLotSize));
+            assert_eq!(amm.deposit_single(get_account_id(), Token::A, 25, 0), Err(Error::InvalidLotSize));
+            assert_eq!(amm.flash_swap(get_account_id(), Token::A, 10, |_, _| Ok((15, 0))), Err(Error::InvalidLotSize));
+            assert_eq!(amm.get_account_balance(get_account_id()), (890, 890, share + share / 10));
+        }
+
+        /// Test curve paying out half the input of either token
+        struct Halving;
+
+        impl Curve<u128> for Halving {
+            fn amount_out(&self, _reserves: (u128, u128), _token_in: Token, amount_in: u128, _fees: u32)
+                -> Result<u128, Error> {
+                Ok(amount_in / 2)
+            }
+
+            fn shares_for_deposit(&self, reserves: (u128, u128), amounts: (u128, u128), total_shares: u128)
+                -> Result<u128, Error> {
+                Ok(total_shares * amounts.0 / reserves.0)
+            }
+
+            fn invariant(&self, reserves: (u128, u128)) -> Result<u128, Error> {
+                Ok(reserves.0 + 2 * reserves.1)
+            }
+        }
+
+        #[test]
+        fn test_curve() {
+            let mut amm = Amm::with_curve(30, Box::new(Halving));
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            let share = amm.deposit(get_account_id(), 100, 100).unwrap();
+            assert_eq!(amm.deposit(get_account_id(), 100, 1), Ok(share));
+            assert_eq!(amm.get_swap_amount_for_token_b(50), Ok(25));
+            assert_eq!(amm.swap_token_b_for_token_a(get_account_id(), 10, 0), Ok(5));
+
+            let amm = Amm::with_curve(30, Box::new(ConstantProduct));
+            assert_eq!(amm.get_swap_amount_for_token_b(50), Err(Error::ZeroLiquidity));
+        }
+
+        #[test]
+        fn test_constant_sum_curve() {
+            let mut amm = AmmBuilder::<u128>::new()
+                .curve(Box::new(ConstantSum))
+                .initial_reserves(String::from("lp"), 1_000, 1_000)
+                .build()
+                .unwrap();
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            assert_eq!(amm.get_swap_amount_for_token_b(500), Ok(500));
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 900, 0), Ok(900));
+            assert_eq!(amm.get_tvl(Token::A), Ok(2_000));
+
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 100, 0), Err(Error::InsufficientLiquidity));
+            assert_eq!(amm.swap_token_b_for_token_a(get_account_id(), 1_000, 0), Ok(1_000));
+
+            let (token_a_pool_balance, token_b_pool_balance, total_shares, _) = amm.get_pool_info();
+            assert_eq!((token_a_pool_balance, token_b_pool_balance), (900, 1_100));
+            assert_eq!(amm.deposit(get_account_id(), 150, 50), Ok(total_shares / 10));
+        }
+
+        #[test]
+        fn test_weighted_curve() {
+            assert_eq!(Weighted::new(80, 0), Err(Error::InvalidWeight));
+            let build = |curve: Box<dyn Curve<u128>>| AmmBuilder::<u128>::new()
+                .fees(3)
+                .curve(curve)
+                .initial_reserves(String::from("lp"), 800_000, 200_000)
+                .build()
+                .unwrap();
+
+            let even = build(Box::new(Weighted::new(50, 50).unwrap()));
+            let constant_product = build(Box::new(ConstantProduct));
+            let quote = constant_product.get_swap_amount_for_token_b(10_000).unwrap();
+            assert!((quote - 1..=quote).contains(&even.get_swap_amount_for_token_b(10_000).unwrap()));
+
+            let mut amm = build(Box::new(Weighted::new(80, 20).unwrap()));
+            assert_eq!(amm.get_weights(), (80, 20));
+            assert_eq!(amm.pool_state().weights, (80, 20));
+            assert_eq!(amm.get_tvl(Token::A), Ok(1_000_000));
+            assert_eq!(amm.get_tvl(Token::B), Ok(1_000_000));
+            // spot price is 1:1, so a small trade gets close to its input less the fee
+            assert_eq!(amm.get_swap_amount_for_token_b(100), Ok(97));
+            assert_eq!(amm.get_swap_amount_for_token_b(10_000), Ok(9_665));
+
+            amm.get_free_tokens(get_account_id(), 10_000, 10_000).unwrap();
+            assert_eq!(amm.swap_token_b_for_token_a(get_account_id(), 10_000, 0), Ok(9_669));
+            assert_eq!(amm.verify_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn test_dyn_automated_market_maker() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let pool: &mut dyn AutomatedMarketMaker = &mut amm;
+            let share = pool.deposit(get_account_id(), 50, 100).unwrap();
+            assert_eq!(pool.get_swap_amount_for_token_b(50), Ok(50));
+            assert_eq!(pool.swap_token_a_for_token_b(get_account_id(), 50, 50), Ok(50));
+            assert_eq!(pool.get_pool_info(), (100, 50, share, 0));
+        }
+
+        /// Rejects swaps above a size limit and counts completed swaps
+        struct SwapLimit {
+            max_amount_in: u128,
+            swaps: Rc<Cell<u128>>,
+        }
+
+        impl PoolHooks<u128> for SwapLimit {
+            fn before_swap(&mut self, _pool: &PoolState, _account_id: &String, _token_in: Token, amount_in: u128)
+                -> Result<(), Error> {
+                match amount_in > self.max_amount_in {
+                    true => Err(Error::HookRejected),
+                    false => Ok(()),
+                }
+            }
+
+            fn after_swap(&mut self, pool: &PoolState, _account_id: &String, _token_in: Token, _amount_in: u128, amount_out: u128) {
+                assert_eq!(pool.token_b_pool_balance, 100 - amount_out);
+                self.swaps.set(self.swaps.get() + 1);
+            }
+        }
+
+        #[test]
+        fn test_pool_hooks() {
+            let swaps = Rc::new(Cell::new(0));
+            let mut amm = Amm::new(0);
+            amm.set_hooks(Some(Box::new(SwapLimit { max_amount_in: 20, swaps: swaps.clone() })));
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 30, 0), Err(Error::HookRejected));
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 10, 0), Ok(16));
+            assert_eq!(amm.get_pool_info(), (60, 84, share, 0));
+            assert_eq!(swaps.get(), 1);
+        }
+
+        #[test]
+        fn test_transfer_shares() {
+            let mut amm = Amm::new(100);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("trader"), 100, 0).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+            amm.swap_token_a_for_token_b(String::from("trader"), 50, 0).unwrap();
+
+            assert_eq!(amm.transfer_shares(get_account_id(), String::from("account-2"), share + 1), Err(Error::InsufficientAmount));
+            assert_eq!(amm.transfer_shares(get_account_id(), String::from("account-2"), 0), Err(Error::ZeroAmount));
+            amm.set_deposit_caps(DepositCaps { max_account_shares: Some(share / 4), ..Default::default() });
+            assert_eq!(amm.transfer_shares(get_account_id(), String::from("account-2"), share / 2), Err(Error::AccountCapExceeded));
+            amm.set_deposit_caps(DepositCaps::default());
+
+            amm.transfer_shares(get_account_id(), String::from("account-2"), share / 2).unwrap();
+            assert_eq!(amm.get_account_balance(get_account_id()).2, share - share / 2);
+            assert_eq!(amm.get_account_balance(String::from("account-2")).2, share / 2);
+            assert_eq!(amm.get_unclaimed_fees(get_account_id()), Ok((5, 0)));
+            assert_eq!(amm.get_unclaimed_fees(String::from("account-2")), Ok((0, 0)));
+            assert_eq!(amm.withdraw(String::from("account-2"), share / 2), Ok((47, 26)));
+        }
+
+        #[test]
+        fn test_share_allowances() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+            let router = String::from("router");
+
+            assert_eq!(amm.transfer_shares_from(router.clone(), get_account_id(), router.clone(), 1), Err(Error::InsufficientAllowance));
+            amm.approve_shares(get_account_id(), router.clone(), share / 2);
+            assert_eq!(amm.get_share_allowance(get_account_id(), router.clone()), share / 2);
+            assert_eq!(amm.transfer_shares_from(router.clone(), get_account_id(), router.clone(), share / 2 + 1),
+                Err(Error::InsufficientAllowance));
+
+            amm.transfer_shares_from(router.clone(), get_account_id(), String::from("vault"), share / 4).unwrap();
+            assert_eq!(amm.get_account_balance(String::from("vault")).2, share / 4);
+            assert_eq!(amm.get_share_allowance(get_account_id(), router.clone()), share / 2 - share / 4);
+            amm.approve_shares(get_account_id(), router.clone(), 0);
+            assert_eq!(amm.transfer_shares_from(router.clone(), get_account_id(), router, 1), Err(Error::InsufficientAllowance));
+        }
+
+        struct ManualClock(Rc<Cell<u64>>);
+
+        impl Clock for ManualClock {
+            fn now(&self) -> u64 {
+                self.0.get()
+            }
+        }
+
+        #[test]
+        fn test_time_locked_deposit() {
+            let now = Rc::new(Cell::new(1_000));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit_locked(get_account_id(), 50, 100, 600).unwrap();
+            amm.deposit_locked(get_account_id(), 10, 20, 60).unwrap();
+            assert_eq!(amm.get_unlock_time(get_account_id()), Some(1_600));
+            assert_eq!(amm.get_unlock_time(String::from("account-2")), None);
+
+            now.set(1_599);
+            assert_eq!(amm.withdraw(get_account_id(), share), Err(Error::SharesLocked));
+            assert_eq!(amm.transfer_shares(get_account_id(), String::from("account-2"), share), Err(Error::SharesLocked));
+            now.set(1_600);
+            assert_eq!(amm.withdraw(get_account_id(), share), Ok((50, 100)));
+        }
+
+        #[test]
+        fn test_liquidity_mining_rewards() {
+            let now = Rc::new(Cell::new(0));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.set_owner(String::from("owner"));
+            amm.get_free_reward_tokens(String::from("owner"), 1_000).unwrap();
+            assert_eq!(amm.fund_rewards(get_account_id(), 1_000), Err(Error::Unauthorized));
+            assert_eq!(amm.fund_rewards(String::from("owner"), 1_001), Err(Error::InsufficientAmount));
+            amm.fund_rewards(String::from("owner"), 1_000).unwrap();
+            assert_eq!(amm.get_reward_balance(String::from("owner")), 0);
+            amm.set_reward_rate(10).unwrap();
+
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            amm.deposit(get_account_id(), 50, 100).unwrap();
+            now.set(10);
+            assert_eq!(amm.pending_rewards(get_account_id()), Ok(100));
+            amm.deposit(String::from("account-2"), 50, 100).unwrap();
+
+            now.set(20);
+            assert_eq!(amm.pending_rewards(get_account_id()), Ok(150));
+            assert_eq!(amm.pending_rewards(String::from("account-2")), Ok(50));
+            assert_eq!(amm.claim_rewards(get_account_id()), Ok(150));
+            assert_eq!(amm.get_reward_balance(get_account_id()), 150);
+            assert_eq!(amm.pending_rewards(get_account_id()), Ok(0));
+
+            now.set(1_000);
+            assert_eq!(amm.pending_rewards(get_account_id()), Ok(400));
+            assert_eq!(amm.claim_rewards(String::from("account-2")), Ok(450));
+            assert_eq!(amm.get_reward_reserve(), 0);
+            let reward_token = amm.audit().unwrap().reward_token;
+            assert_eq!((reward_token.total_supply, reward_token.accrued_fees), (1_000, 400));
+            assert!(reward_token.is_balanced().unwrap());
+        }
+
+        #[test]
+        fn test_imbalanced_deposit() {
+            let mut amm = Amm::new(10);
+            amm.get_free_tokens(get_account_id(), 200_000, 200_000).unwrap();
+            let share = amm.deposit(get_account_id(), 100_000, 100_000).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 2_000, 2_000).unwrap();
+
+            let off_by_one = amm.deposit(String::from("account-2"), 1_000, 999).unwrap();
+            assert_eq!(off_by_one, share * 999 / 100_000);
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            let single_sided = amm.deposit(String::from("account-2"), 1_000, 1).unwrap();
+            assert!(single_sided < share * 500 / 100_000 && single_sided > share * 490 / 100_000);
+
+            let (token_a_amount, token_b_amount) = amm.withdraw(String::from("account-2"), single_sided).unwrap();
+            assert!(token_a_amount + token_b_amount < 1_000);
+            assert!(token_a_pool_balance < amm.get_pool_info().0 && token_b_pool_balance > amm.get_pool_info().1);
+        }
+
+        #[test]
+        fn test_deposit_with_a() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 1_000, 1_000).unwrap();
+            let share = amm.deposit(get_account_id(), 300, 700).unwrap();
+
+            assert_eq!(amm.quote_deposit_b_for_a(100), Ok(234));
+            assert_eq!(amm.deposit_with_a(get_account_id(), 100, 233), Err(Error::SlippageExceeded));
+            let (shares, token_b_amount) = amm.deposit_with_a(get_account_id(), 100, 234).unwrap();
+            assert_eq!((shares, token_b_amount), (share / 3, 234));
+            assert_eq!(amm.get_account_balance(get_account_id()), (600, 66, share + shares));
+        }
+
+        #[test]
+        fn test_deposit_single() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 100_000, 200_000).unwrap();
+            let share = amm.deposit(get_account_id(), 100_000, 200_000).unwrap();
+            amm.get_free_tokens(String::from("zapper"), 1_000, 0).unwrap();
+
+            assert_eq!(amm.deposit_single(String::from("zapper"), Token::A, 1_000, share / 100), Err(Error::SlippageExceeded));
+            assert_eq!(amm.get_account_balance(String::from("zapper")), (1_000, 0, 0));
+            let (shares, token_a_dust, token_b_dust) = amm.deposit_single(String::from("zapper"), Token::A, 1_000, 0).unwrap();
+            assert!(shares > share * 49 / 10_000);
+            assert_eq!(amm.get_account_balance(String::from("zapper")), (token_a_dust, token_b_dust, shares));
+            assert!(token_a_dust <= 5 && token_b_dust <= 1);
+            assert_eq!(amm.deposit_single(String::from("zapper"), Token::B, 1, 0), Err(Error::ZeroAmount));
+        }
+
+        #[test]
+        fn test_deposit_single_is_atomic() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 100_000, 200_000).unwrap();
+            amm.deposit(get_account_id(), 100_000, 200_000).unwrap();
+            amm.get_free_tokens(String::from("zapper"), 1_000, 0).unwrap();
+            amm.set_deposit_caps(DepositCaps { max_account_shares: Some(1), ..Default::default() });
+            let pool_info = amm.get_pool_info();
+
+            assert_eq!(amm.deposit_single(String::from("zapper"), Token::A, 1_000, 0), Err(Error::AccountCapExceeded));
+            assert_eq!(amm.get_account_balance(String::from("zapper")), (1_000, 0, 0));
+            assert_eq!(amm.get_pool_info(), pool_info);
+            assert_eq!(amm.verify_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn test_slippage() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(
+                get_account_id(),
+                50,
+                100
+            ).unwrap();
+            let token_b_amount = amm.swap_token_a_for_token_b(
+                get_account_id(),
+                50,
+                51
+            );
+            assert_eq!(token_b_amount, Err(Error::SlippageExceeded));
+            assert_eq!(amm.get_pool_info(), (50, 100, share, 0));
+            assert_eq!(amm.get_account_balance(get_account_id()), (50, 100, share));
+        }
+
+        #[test]
+        fn test_swap_with_tolerance() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 1_100, 2_100).unwrap();
+            amm.deposit(get_account_id(), 1_000, 2_000).unwrap();
+            assert_eq!(amm.get_spot_amount_out(Token::A, 10), Ok(20));
+            assert_eq!(amm.swap_with_tolerance(get_account_id(), Token::A, 10, 400), Err(Error::SlippageExceeded));
+            assert_eq!(amm.swap_with_tolerance(get_account_id(), Token::A, 10, 500), Ok(19));
+            assert!(amm.swap_with_tolerance(get_account_id(), Token::B, 50, 20_000).is_ok());
+        }
+
+        #[test]
+        fn test_price_queries() {
+            let mut amm = Amm::new(3);
+            assert_eq!(amm.spot_price(Token::A), Err(Error::ZeroLiquidity));
+            amm.get_free_tokens(get_account_id(), 1_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 2_000_000).unwrap();
+            assert_eq!(amm.spot_price(Token::A), Ok(2 * PRECISION as u128));
+            assert_eq!(amm.spot_price(Token::B), Ok(PRECISION as u128 / 2));
+
+            assert_eq!(amm.execution_price(Token::A, 0), Err(Error::ZeroAmount));
+            let execution_price = amm.execution_price(Token::A, 1_000).unwrap();
+            assert!(execution_price < 2 * PRECISION as u128 * 997 / 1000);
+            assert!(amm.price_impact(Token::A, 1_000).unwrap() <= 11);
+            assert_eq!(amm.price_impact(Token::A, 1_000_000).unwrap(), 4_993);
+            assert_eq!(amm.view().price_impact(Token::B, 100), amm.price_impact(Token::B, 100));
+        }
+
+        #[test]
+        fn test_previews() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            let preview = amm.preview_deposit(get_account_id(), 1_000, 1_000).unwrap();
+            assert_eq!(preview.balance_before, (2_000, 2_000, 0));
+            assert_eq!(preview.balance_after, (1_000, 1_000, preview.result));
+            assert_eq!(amm.get_pool_info(), (0, 0, 0, 3));
+            assert_eq!(amm.deposit(get_account_id(), 1_000, 1_000), Ok(preview.result));
+
+            let pool_state = amm.pool_state();
+            let preview = amm.preview_swap(get_account_id(), Token::A, 100, 0).unwrap();
+            assert_eq!(preview.balance_after, (900, 1_000 + preview.result, preview.balance_before.2));
+            assert_eq!(amm.preview_swap(get_account_id(), Token::B, 100, 100), Err(Error::SlippageExceeded));
+            let preview_withdraw = amm.preview_withdraw(get_account_id(), preview.balance_before.2).unwrap();
+            assert_eq!(preview_withdraw.result, (1_000, 1_000));
+            assert_eq!(amm.pool_state(), pool_state);
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 100, 0), Ok(preview.result));
+        }
+
+        #[test]
+        fn test_simulate_survives_panic() {
+            let mut amm = Amm::new(3);
+            let events = Rc::new(RefCell::new(Vec::new()));
+            amm.set_event_sink(Some(Box::new(events.clone())));
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            let pool_state = amm.pool_state();
+            let event_count = events.borrow().len();
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                amm.simulate(|amm| -> Result<(), Error> {
+                    amm.swap_token_a_for_token_b(get_account_id(), 100, 0)?;
+                    panic!("operation failed mid-simulation");
+                })
+            }));
+            assert!(result.is_err());
+            assert_eq!(amm.pool_state(), pool_state);
+            assert_eq!(events.borrow().len(), event_count);
+
+            // the event sink is still attached
+            amm.swap_token_a_for_token_b(get_account_id(), 100, 0).unwrap();
+            assert_eq!(events.borrow().len(), event_count + 1);
+        }
+
+        #[test]
+        fn test_flash_swap() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            let arbitrageur = String::from("arbitrageur");
+            amm.get_free_tokens(arbitrageur.clone(), 50, 0).unwrap();
+            let pool_state = amm.pool_state();
+
+            // output is usable before paying, but the callback's error undoes it
+            let result = amm.flash_swap(arbitrageur.clone(), Token::B, 10_000, |amm, account_id| {
+                assert_eq!(amm.get_account_balance(account_id.clone()), (50, 10_000, 0));
+                Err(Error::InsufficientAmount)
+            });
+            assert_eq!(result, Err(Error::InsufficientAmount));
+            assert_eq!(amm.pool_state(), pool_state);
+            assert_eq!(amm.get_account_balance(arbitrageur.clone()), (50, 0, 0));
+
+            // repaying in the same token needs the fee on top
+            let repay = |amount: u128| move |_: &mut Amm, _: &String| Ok((0, amount));
+            let repay_a = |amount: u128| move |_: &mut Amm, _: &String| Ok((amount, 0));
+            assert_eq!(amm.flash_swap(arbitrageur.clone(), Token::B, 10_000, repay(10_000)), Err(Error::InsufficientRepayment));
+            assert_eq!(amm.flash_swap(arbitrageur.clone(), Token::B, 10_000, repay(10_031)), Err(Error::InsufficientAmount));
+            assert_eq!(amm.pool_state(), pool_state);
+
+            // a repayment in the other token is priced by the curve
+            let result = amm.flash_swap(arbitrageur.clone(), Token::B, 10, repay_a(20));
+            assert_eq!(result, Ok((20, 0)));
+            assert_eq!(amm.get_account_balance(arbitrageur.clone()), (30, 10, 0));
+            assert_eq!(amm.flash_swap(arbitrageur, Token::A, 2_000_000, repay(0)), Err(Error::InsufficientLiquidity));
+            assert!(amm.audit().unwrap().is_balanced().unwrap());
+        }
+
+        #[test]
+        fn test_flash_swap_repaid_through_the_pool() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            let attacker = String::from("attacker");
+            amm.get_free_tokens(attacker.clone(), 200_000, 200_000).unwrap();
+            let pool_state = amm.pool_state();
+
+            // adding liquidity raises the invariant but repays nothing
+            let result = amm.flash_swap(attacker.clone(), Token::B, 100_000, |amm, account_id| {
+                amm.deposit(account_id.clone(), 100_000, 90_000)?;
+                Ok((0, 0))
+            });
+            assert_eq!(result, Err(Error::Reentrancy));
+            let result = amm.flash_swap(attacker.clone(), Token::B, 100_000, |amm, account_id| {
+                amm.flash_swap(account_id.clone(), Token::A, 1_000, |_, _| Ok((0, 0)))?;
+                Ok((0, 0))
+            });
+            assert_eq!(result, Err(Error::Reentrancy));
+            let result = amm.flash_swap(attacker.clone(), Token::B, 100_000, |amm, account_id| {
+                amm.swap_token_a_for_token_b(account_id.clone(), 100_000, 0)?;
+                Ok((0, 0))
+            });
+            assert_eq!(result, Err(Error::Reentrancy));
+
+            assert_eq!(amm.pool_state(), pool_state);
+            assert_eq!(amm.get_account_balance(attacker), (200_000, 200_000, 0));
+        }
+
+        #[test]
+        fn test_flash_swap_callback_cannot_reenter() {
+            let mut amm = Amm::new(3);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 1_100_000, 1_100_000).unwrap();
+            let share = amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            amm.swap_token_a_for_token_b(get_account_id(), 10_000, 0).unwrap();
+            let pool_state = amm.pool_state();
+            let balance = amm.get_account_balance(get_account_id());
+
+            type Operation = fn(&mut Amm, &String) -> Result<(), Error>;
+            let operations: Vec<Operation> = vec![
+                |amm, account_id| amm.withdraw(account_id.clone(), 1).map(|_| ()),
+                |amm, account_id| amm.claim_fees(account_id.clone()).map(|_| ()),
+                |amm, account_id| amm.place_limit_order(account_id.clone(), Token::A, 100, 200).map(|_| ()),
+                |amm, account_id| amm.transfer_shares(account_id.clone(), String::from("account-2"), 1),
+            ];
+            for operation in operations {
+                let result = amm.flash_swap(get_account_id(), Token::B, 1_000, |amm, account_id| {
+                    operation(amm, account_id)?;
+                    Ok((0, 1_100))
+                });
+                assert_eq!(result, Err(Error::Reentrancy));
+                assert_eq!(amm.pool_state(), pool_state);
+                assert_eq!(amm.get_account_balance(get_account_id()), balance);
+            }
+            assert_eq!(amm.get_account_balance(get_account_id()).2, share);
+            amm.withdraw(get_account_id(), 1).unwrap();
+        }
+
+        #[test]
+        fn test_events() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut amm = Amm::new(3);
+            amm.set_event_sink(Some(Box::new(events.clone())));
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            let share = amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            let amount_out = amm.swap_token_a_for_token_b(get_account_id(), 100, 0).unwrap();
+            amm.preview_withdraw(get_account_id(), share).unwrap();
+            assert!(amm.flash_swap(get_account_id(), Token::A, 10, |amm, account_id| {
+                amm.burn_tokens(account_id.clone(), 1, 0)?;
+                Ok((0, 0))
+            }).is_err());
+            assert_eq!(*events.borrow(), vec![
+                Event::TokensIssued { account_id: get_account_id(), token_a_amount: 2_000, token_b_amount: 2_000 },
+                Event::Deposited {
+                    account_id: get_account_id(),
+                    token_a_amount: 1_000,
+                    token_b_amount: 1_000,
+                    shares: share,
+                },
+                Event::Swapped { account_id: get_account_id(), token_in: Token::A, amount_in: 100, amount_out },
+            ]);
+
+            events.borrow_mut().clear();
+            amm.flash_swap(get_account_id(), Token::A, 10, |_, _| Ok((11, 0))).unwrap();
+            amm.transfer_shares(get_account_id(), String::from("account-2"), share).unwrap();
+            assert_eq!(*events.borrow(), vec![
+                Event::FlashSwapped {
+                    account_id: get_account_id(),
+                    token_out: Token::A,
+                    amount_out: 10,
+                    token_a_repaid: 11,
+                    token_b_repaid: 0,
+                },
+                Event::SharesTransferred { from: get_account_id(), to: String::from("account-2"), share },
+            ]);
+        }
+
+        #[test]
+        fn test_share_and_admin_events() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.set_clock(Box::new(ManualClock(Rc::new(Cell::new(100)))));
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.set_event_sink(Some(Box::new(events.clone())));
+            let share = amm.deposit_locked(get_account_id(), 10, 20, 50).unwrap();
+            amm.approve_shares(get_account_id(), String::from("spender"), share);
+            amm.commit_swap(get_account_id(), 7);
+            let total_pool_shares = amm.redenominate_shares(get_account_id(), Redenomination::Split(2)).unwrap();
+            amm.set_tvl_cap(Some(TvlCap { numeraire: Token::B, max_value: 60, action: TvlCapAction::Pause }));
+            assert_eq!(amm.deposit(get_account_id(), 10, 20), Err(Error::DepositsPaused));
+
+            assert_eq!(events.borrow()[1..], [
+                Event::SharesLocked { account_id: get_account_id(), unlock_time: 150 },
+                Event::SharesApproved { owner: get_account_id(), spender: String::from("spender"), share },
+                Event::SwapCommitted { account_id: get_account_id(), commitment: 7 },
+                Event::SharesRedenominated { redenomination: Redenomination::Split(2), total_pool_shares },
+                Event::DepositsPaused { tvl: 80 },
+            ]);
+        }
+
+        #[test]
+        fn test_history() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.get_free_tokens(get_account_id(), 1, 0).unwrap();
+            amm.set_history_enabled(true);
+            amm.get_free_tokens(get_account_id(), 1_999, 2_000).unwrap();
+            let share = amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            assert!(amm.flash_swap(get_account_id(), Token::A, 10, |_, _| Err(Error::InsufficientAmount)).is_err());
+            amm.transfer_shares(get_account_id(), String::from("account-2"), share / 2).unwrap();
+
+            let history = amm.get_history(&get_account_id(), 0..10);
+            assert_eq!(history.len(), 3);
+            assert_eq!(history.iter().map(|entry| entry.sequence).collect::<Vec<_>>(), vec![0, 1, 2]);
+            assert_eq!(history[0].balance, (2_000, 2_000, 0));
+            assert_eq!(history[1].balance, (1_000, 1_000, share));
+            assert_eq!(history[2].balance, (1_000, 1_000, share - share / 2));
+            assert_eq!(amm.get_history(&get_account_id(), 1..2), &history[1..2]);
+            assert_eq!(amm.get_history(&get_account_id(), 5..7), &[]);
+
+            let history = amm.get_history(&String::from("account-2"), 0..usize::MAX);
+            assert_eq!(history.len(), 1);
+            assert_eq!((history[0].sequence, history[0].balance), (2, (0, 0, share / 2)));
+
+            let instructions = vec![
+                SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 0 },
+                SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 1_000 },
+            ];
+            assert!(amm.batch_swap(get_account_id(), instructions).is_err());
+            assert_eq!(amm.get_history(&get_account_id(), 0..usize::MAX).len(), 3);
+
+            amm.redenominate_shares(get_account_id(), Redenomination::Split(2)).unwrap();
+            let history = amm.get_history(&get_account_id(), 3..usize::MAX);
+            assert_eq!((history[0].sequence, history[0].balance), (3, (1_000, 1_000, (share - share / 2) * 2)));
+            let history = amm.get_history(&String::from("account-2"), 1..usize::MAX);
+            assert_eq!((history[0].sequence, history[0].balance), (3, (0, 0, share / 2 * 2)));
+        }
+
+        #[test]
+        fn test_quote_swap() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            assert_eq!(amm.quote_swap(Token::A, 0), Err(Error::ZeroAmount));
+
+            let quote = amm.quote_swap(Token::A, 10_000).unwrap();
+            assert_eq!(quote.amount_in, 10_000);
+            assert_eq!(quote.fee_paid, 30);
+            assert_eq!(quote.price_before, PRECISION as u128);
+            assert_eq!(quote.price_impact_bps, amm.price_impact(Token::A, 10_000).unwrap());
+
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 10_000, 0), Ok(quote.amount_out));
+            assert_eq!(amm.spot_price(Token::A), Ok(quote.price_after));
+            assert!(quote.price_after < quote.price_before);
+        }
+
+        #[test]
+        fn test_batch_swap() {
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            let pool_info = amm.get_pool_info();
+            let instructions = vec![
+                SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 0 },
+                SwapInstruction { token_in: Token::B, amount_in: 50, min_out: 0 },
+                SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 1_000 },
+            ];
+            assert_eq!(amm.batch_swap(get_account_id(), instructions.clone()), Err(Error::SlippageExceeded));
+            assert_eq!(amm.get_pool_info(), pool_info);
+            assert_eq!(amm.get_account_balance(get_account_id()).0, 1_000);
+
+            let token_b_amount = amm.get_swap_amount_for_token_b(100).unwrap();
+            let amounts_out = amm.batch_swap(get_account_id(), instructions[..2].to_vec()).unwrap();
+            assert_eq!(amounts_out[0], token_b_amount);
+            assert_eq!(amm.get_account_balance(get_account_id()).0, 900 + amounts_out[1]);
+            assert_eq!(amm.batch_swap(get_account_id(), vec![]), Ok(vec![]));
+        }
+
+        #[test]
+        fn test_batch_swap_events() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut amm = Amm::new(3);
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+            amm.set_event_sink(Some(Box::new(events.clone())));
+            let instructions = vec![
+                SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 0 },
+                SwapInstruction { token_in: Token::B, amount_in: 50, min_out: 1_000 },
+            ];
+            assert_eq!(amm.batch_swap(get_account_id(), instructions.clone()), Err(Error::SlippageExceeded));
+            assert!(events.borrow().is_empty());
+
+            amm.batch_swap(get_account_id(), instructions[..1].to_vec()).unwrap();
+            assert!(matches!(events.borrow()[..], [Event::Swapped { .. }]));
+        }
+
+        #[test]
+        fn test_limit_orders() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            let maker = String::from("maker");
+            amm.get_free_tokens(maker.clone(), 20_000, 0).unwrap();
+
+            // sell 20_000 A for 1.02 B each, above the spot price of 1
+            let order_id = amm.place_limit_order(maker.clone(), Token::A, 20_000, 20_400).unwrap();
+            assert_eq!(amm.get_account_balance(maker.clone()), (0, 0, 0));
+            assert_eq!(amm.get_open_orders(&maker).len(), 1);
+            assert_eq!(amm.audit().unwrap().token_a.limit_order_escrow, 20_000);
+            assert_eq!(amm.cancel_limit_order(get_account_id(), order_id), Err(Error::Unauthorized));
+
+            // pushing the price of A to about 1.04 B fills the order in part
+            amm.get_free_tokens(String::from("taker"), 0, 20_000).unwrap();
+            amm.swap_token_b_for_token_a(String::from("taker"), 20_000, 0).unwrap();
+            let order = amm.get_limit_order(order_id).unwrap().clone();
+            assert!(order.remaining > 0 && order.remaining < 20_000);
+            assert!(order.amount_out * 20_000 >= (20_000 - order.remaining) * 20_400);
+            assert_eq!(amm.get_account_balance(maker.clone()), (0, order.amount_out, 0));
+            assert!(amm.audit().unwrap().is_balanced().unwrap());
+
+            assert_eq!(amm.cancel_limit_order(maker.clone(), order_id), Ok(order.remaining));
+            assert_eq!(amm.get_account_balance(maker.clone()), (order.remaining, order.amount_out, 0));
+            assert_eq!(amm.cancel_limit_order(maker.clone(), order_id), Err(Error::OrderNotFound));
+
+            // an order at or below the spot price fills straight away
+            let order_id = amm.place_limit_order(maker.clone(), Token::A, 100, 90).unwrap();
+            assert_eq!(amm.get_limit_order(order_id), None);
+            assert!(amm.get_open_orders(&maker).is_empty());
+            assert_eq!(amm.place_limit_order(maker.clone(), Token::B, 0, 1), Err(Error::ZeroAmount));
+
+            // an order its owner may no longer swap stalls instead of being retried
+            let taker = String::from("taker");
+            let order_id = amm.place_limit_order(maker.clone(), Token::B, 1_000, 1_050).unwrap();
+            assert_eq!(amm.get_limit_order(order_id).unwrap().remaining, 1_000);
+            amm.set_swap_access_mode(SwapAccessMode::Allowlist);
+            amm.add_to_swap_access_list(taker.clone());
+            amm.get_free_tokens(taker.clone(), 80_000, 0).unwrap();
+            amm.swap_token_a_for_token_b(taker.clone(), 70_000, 0).unwrap();
+            assert!(amm.get_limit_order(order_id).unwrap().stalled);
+            amm.add_to_swap_access_list(maker.clone());
+            amm.swap_token_a_for_token_b(taker, 10_000, 0).unwrap();
+            assert_eq!(amm.get_limit_order(order_id).unwrap().remaining, 1_000);
+            assert_eq!(amm.resume_limit_order(get_account_id(), order_id), Err(Error::Unauthorized));
+            amm.resume_limit_order(maker, order_id).unwrap();
+            assert_eq!(amm.get_limit_order(order_id), None);
+            assert!(amm.audit().unwrap().is_balanced().unwrap());
+        }
+
+        #[test]
+        fn test_deadlines() {
+            let now = Rc::new(Cell::new(100));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            assert!(amm.deposit_with_deadline(get_account_id(), 1_000, 1_000, 100).is_ok());
+            assert!(amm.swap_with_deadline(get_account_id(), Token::A, 10, 0, 100).is_ok());
+
+            now.set(101);
+            let pool_info = amm.get_pool_info();
+            assert_eq!(amm.deposit_with_deadline(get_account_id(), 100, 100, 100), Err(Error::Expired));
+            assert_eq!(amm.swap_with_deadline(get_account_id(), Token::B, 10, 0, 100), Err(Error::Expired));
+            assert_eq!(amm.get_pool_info(), pool_info);
+        }
+
+        #[test]
+        fn test_commit_reveal_swap() {
+            let now = Rc::new(Cell::new(100));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.set_reveal_delay(5);
+            amm.get_free_tokens(get_account_id(), 2_000, 2_000).unwrap();
+            amm.deposit(get_account_id(), 1_000, 1_000).unwrap();
+
+            let instruction = SwapInstruction { token_in: Token::A, amount_in: 100, min_out: 90 };
+            assert_eq!(amm.reveal_swap(get_account_id(), instruction, 7), Err(Error::CommitmentMismatch));
+            amm.commit_swap(get_account_id(), Amm::get_swap_commitment(&get_account_id(), &instruction, 7));
+            assert_eq!(amm.reveal_swap(get_account_id(), instruction, 7), Err(Error::RevealTooEarly));
+
+            now.set(105);
+            assert_eq!(amm.reveal_swap(get_account_id(), instruction, 8), Err(Error::CommitmentMismatch));
+            let changed = SwapInstruction { min_out: 0, ..instruction };
+            assert_eq!(amm.reveal_swap(get_account_id(), changed, 7), Err(Error::CommitmentMismatch));
+            assert_eq!(amm.get_account_balance(get_account_id()).0, 1_000);
+            assert_eq!(amm.reveal_swap(get_account_id(), instruction, 7), Ok(90));
+            assert_eq!(amm.reveal_swap(get_account_id(), instruction, 7), Err(Error::CommitmentMismatch));
+        }
+
+        #[test]
+        fn test_price_oracle() {
+            let now = Rc::new(Cell::new(1_000));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            assert_eq!(amm.consult(10), Err(Error::InsufficientHistory));
+
+            amm.swap_token_a_for_token_b(get_account_id(), 1, 0).unwrap();
+            let price = amm.consult(0).unwrap();
+            assert_eq!(amm.consult(10), Err(Error::InsufficientHistory));
+            now.set(1_100);
+            assert_eq!(amm.consult(100), Ok(price));
+
+            // a short spike barely moves the average
+            amm.swap_token_b_for_token_a(get_account_id(), 400_000, 0).unwrap();
+            let spike_price = amm.consult(0).unwrap();
+            assert!(spike_price > price * 19 / 10);
+            now.set(1_101);
+            amm.swap_token_a_for_token_b(get_account_id(), 285_000, 0).unwrap();
+            now.set(1_200);
+            let average = amm.consult(200).unwrap();
+            assert!(average > price && average < price * 11 / 10);
+            assert_eq!(amm.consult(201), Err(Error::InsufficientHistory));
+            now.set(1_200 + (1 << 32));
+            assert_eq!(amm.consult(1 << 32), Ok(amm.consult(0).unwrap()));
+        }
+
+        #[test]
+        fn test_price_oracle_wraps() {
+            let now = Rc::new(Cell::new(0));
+            let mut amm = super::Amm::<u32>::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            amm.swap_token_a_for_token_b(get_account_id(), 1, 0).unwrap();
+            let first_price = amm.consult(0).unwrap();
+
+            // the cumulative price passes u32::MAX well before this
+            now.set(10_000);
+            amm.swap_token_b_for_token_a(get_account_id(), 1, 0).unwrap();
+            let last_price = amm.consult(0).unwrap();
+            now.set(10_010);
+            assert_eq!(amm.consult(10), Ok(last_price));
+            let average = amm.consult(4_000).unwrap();
+            assert!(average >= first_price.min(last_price) && average <= first_price.max(last_price));
+            now.set(1 << 33);
+            assert_eq!(amm.consult(1 << 32), Err(Error::Overflow));
+        }
+
+        #[test]
+        fn test_dynamic_fee() {
+            let mut amm = Amm::new(3);
+            amm.set_dynamic_fee(Some(DynamicFee { min_fee: 11, max_fee: 10, window: 4, max_spread_bps: 1_000 }));
+            assert_eq!(amm.get_effective_fee(), 3);
+
+            amm.set_dynamic_fee(Some(DynamicFee { min_fee: 1, max_fee: 11, window: 3, max_spread_bps: 1_000 }));
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            assert_eq!(amm.get_pool_info().3, 1);
+            amm.swap_token_a_for_token_b(get_account_id(), 1_000, 0).unwrap();
+            assert_eq!(amm.get_pool_info().3, 1);
+            amm.swap_token_b_for_token_a(get_account_id(), 26_000, 0).unwrap();
+            assert_eq!(amm.get_pool_info().3, 6);
+            assert_eq!(amm.pool_state().fees, 6);
+            amm.swap_token_a_for_token_b(get_account_id(), 100_000, 0).unwrap();
+            assert_eq!(amm.get_effective_fee(), 11);
+
+            for _ in 0..3 {
+                amm.swap_token_a_for_token_b(get_account_id(), 1, 0).unwrap();
+            }
+            assert_eq!(amm.get_effective_fee(), 1);
+            amm.set_dynamic_fee(None);
+            assert_eq!(amm.get_effective_fee(), 3);
+        }
+
+        #[test]
+        fn test_fees() {
+            let mut amm = Amm::new(100);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.deposit(
+                get_account_id(),
+                50,
                 100
             ).unwrap();
             let token_b_amount = amm.get_swap_amount_for_token_b(50).unwrap();
-            assert_eq!(token_b_amount, 48);
+            assert_eq!(token_b_amount, 47);
+        }
+
+        #[test]
+        fn test_insurance_fund() {
+            let mut amm = Amm::new(100);
+            amm.set_insurance_fee_share(5_000).unwrap();
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+            assert_eq!(amm.swap_token_a_for_token_b(get_account_id(), 50, 0).unwrap(), 47);
+            assert_eq!(amm.get_pool_info(), (98, 53, share, 100));
+            assert_eq!(amm.get_insurance_fund().token_a_balance, 2);
+
+            assert_eq!(amm.recapitalize_pool(get_account_id(), 2, 0), Err(Error::Unauthorized));
+            amm.set_owner(String::from("governance"));
+            assert_eq!(amm.recapitalize_pool(get_account_id(), 2, 0), Err(Error::Unauthorized));
+            assert_eq!(amm.recapitalize_pool(String::from("governance"), 3, 0), Err(Error::InsufficientAmount));
+            amm.recapitalize_pool(String::from("governance"), 2, 0).unwrap();
+            assert_eq!(amm.get_pool_info(), (100, 53, share, 100));
+            assert_eq!(amm.get_insurance_fund(), InsuranceFund {
+                token_a_inflow: 2,
+                token_a_outflow: 2,
+                ..Default::default()
+            });
+
+            // a share of the whole fee is out of range and keeps the current share
+            assert_eq!(amm.set_insurance_fee_share(10_000), Err(Error::InvalidFeeShare));
+            amm.get_free_tokens(get_account_id(), 50, 0).unwrap();
+            amm.swap_token_a_for_token_b(get_account_id(), 50, 0).unwrap();
+            assert!(amm.get_insurance_fund().token_a_inflow > 2);
+        }
+
+        #[test]
+        fn test_protocol_fees() {
+            let mut amm = Amm::new(100);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.set_insurance_fee_share(2_000).unwrap();
+            amm.set_protocol_fee_share(9_000).unwrap();
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("trader"), 100, 0).unwrap();
+            amm.deposit(get_account_id(), 50, 100).unwrap();
+
+            assert_eq!(amm.swap_token_a_for_token_b(String::from("trader"), 50, 0).unwrap(), 47);
+            assert_eq!(amm.get_insurance_fund().token_a_balance, 1);
+            assert_eq!(amm.get_protocol_fees(), (4, 0));
+            assert_eq!(amm.get_unclaimed_fees(get_account_id()), Ok((0, 0)));
+
+            assert_eq!(amm.claim_protocol_fees(String::from("treasury")), Err(Error::Unauthorized));
+            amm.set_owner(String::from("treasury"));
+            assert_eq!(amm.claim_protocol_fees(get_account_id()), Err(Error::Unauthorized));
+            assert_eq!(amm.claim_protocol_fees(String::from("treasury")), Ok((4, 0)));
+            assert_eq!(amm.get_account_balance(String::from("treasury")), (4, 0, 0));
+            assert_eq!(amm.get_protocol_fees(), (0, 0));
+            assert!(amm.audit().unwrap().is_balanced().unwrap());
+
+            assert_eq!(amm.set_protocol_fee_share(10_000), Err(Error::InvalidFeeShare));
+            amm.get_free_tokens(String::from("trader"), 50, 0).unwrap();
+            amm.swap_token_a_for_token_b(String::from("trader"), 50, 0).unwrap();
+            assert!(amm.get_protocol_fees().0 > 0);
+        }
+
+        #[test]
+        fn test_fee_mode_accumulate() {
+            let mut amm = Amm::new(100);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("trader"), 50, 0).unwrap();
+            let share = amm.deposit(get_account_id(), 50, 100).unwrap();
+
+            assert_eq!(amm.swap_token_a_for_token_b(String::from("trader"), 50, 0).unwrap(), 47);
+            assert_eq!(amm.get_pool_info(), (95, 53, share, 100));
+            assert_eq!(amm.get_unclaimed_fees(get_account_id()), Ok((5, 0)));
+
+            assert_eq!(amm.claim_fees(get_account_id()), Ok((5, 0)));
+            assert_eq!(amm.get_account_balance(get_account_id()), (55, 100, share));
+            assert_eq!(amm.get_unclaimed_fees(get_account_id()), Ok((0, 0)));
+            assert_eq!(amm.pool_state().token_a_fee_balance, 0);
+        }
+
+        #[test]
+        fn test_fee_mode_accumulate_late_depositor() {
+            let mut amm = Amm::new(100);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("trader"), 10, 0).unwrap();
+            amm.deposit(get_account_id(), 10, 20).unwrap();
+            amm.swap_token_a_for_token_b(String::from("trader"), 10, 0).unwrap();
+
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            amm.deposit(String::from("account-2"), token_a_pool_balance, token_b_pool_balance).unwrap();
+            assert_eq!(amm.get_unclaimed_fees(get_account_id()), Ok((1, 0)));
+            assert_eq!(amm.get_unclaimed_fees(String::from("account-2")), Ok((0, 0)));
+        }
+
+        #[test]
+        fn test_withdraw_percent() {
+            let mut amm = Amm::new(0);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 30, 60).unwrap();
+
+            assert_eq!(amm.withdraw_percent(get_account_id(), 10_001, 0, 0), Err(Error::InvalidShare));
+            assert_eq!(amm.withdraw_percent(get_account_id(), 5_000, 16, 0), Err(Error::SlippageExceeded));
+            assert_eq!(amm.withdraw_percent(get_account_id(), 5_000, 15, 30), Ok((15, 30)));
+            assert_eq!(amm.get_account_balance(get_account_id()).2, share - share / 2);
+            assert_eq!(amm.withdraw_percent(get_account_id(), 10_000, 0, 0), Ok((15, 30)));
+            assert_eq!(amm.get_account_balance(get_account_id()), (100, 200, 0));
+            assert_eq!(amm.withdraw_percent(get_account_id(), 10_000, 0, 0), Err(Error::ZeroLiquidity));
+        }
+
+        #[test]
+        fn test_withdraw_all() {
+            let mut amm = Amm::new(100);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("trader"), 10, 0).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            amm.swap_token_a_for_token_b(String::from("trader"), 10, 0).unwrap();
+
+            assert_eq!(amm.withdraw_all(get_account_id()).unwrap(), Settlement {
+                shares_redeemed: share,
+                token_a_withdrawn: 19,
+                token_b_withdrawn: 11,
+                token_a_fees: 1,
+                token_b_fees: 0,
+                rewards: 0,
+            });
+            assert_eq!(amm.get_account_balance(get_account_id()), (110, 191, 0));
+            assert_eq!(amm.get_pool_info(), (0, 0, 0, 100));
+            assert_eq!(amm.withdraw_all(get_account_id()).unwrap(), Settlement::default());
+        }
+
+        #[test]
+        fn test_withdraw_all_settles_rewards_and_locks() {
+            let now = Rc::new(Cell::new(0));
+            let mut amm = Amm::new(0);
+            amm.set_clock(Box::new(ManualClock(now.clone())));
+            amm.set_owner(String::from("owner"));
+            amm.get_free_reward_tokens(String::from("owner"), 1_000).unwrap();
+            amm.fund_rewards(String::from("owner"), 1_000).unwrap();
+            amm.set_reward_rate(10).unwrap();
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit_locked(get_account_id(), 10, 20, 50).unwrap();
+
+            now.set(49);
+            assert_eq!(amm.withdraw_all(get_account_id()), Err(Error::SharesLocked));
+            now.set(50);
+            assert_eq!(amm.withdraw_all(get_account_id()).unwrap(), Settlement {
+                shares_redeemed: share,
+                token_a_withdrawn: 10,
+                token_b_withdrawn: 20,
+                rewards: 500,
+                ..Default::default()
+            });
+            assert_eq!(amm.get_reward_balance(get_account_id()), 500);
+            assert_eq!(amm.get_unlock_time(get_account_id()), None);
+        }
+
+        #[test]
+        fn test_redenominate_shares() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            amm.get_free_tokens(String::from("account-2"), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            amm.deposit(String::from("account-2"), 5, 10).unwrap();
+
+            let total = amm.redenominate_shares(get_account_id(), Redenomination::Merge(1_000_000)).unwrap();
+            assert_eq!(total, 150);
+            assert_eq!(amm.get_account_balance(get_account_id()), (90, 180, 100));
+            assert_eq!(amm.get_pool_info(), (15, 30, 150, 0));
+
+            assert_eq!(amm.redenominate_shares(get_account_id(), Redenomination::Split(10)).unwrap(), 1500);
+            assert_eq!(amm.get_account_balance(get_account_id()).2, share / 100_000);
+        }
+
+        #[test]
+        fn test_redenominate_shares_invalid_factor() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            assert_eq!(amm.redenominate_shares(get_account_id(), Redenomination::Merge(0)), Err(Error::InvalidFactor));
+            assert_eq!(amm.redenominate_shares(get_account_id(), Redenomination::Split(u128::MAX)), Err(Error::Overflow));
+            assert_eq!(amm.redenominate_shares(get_account_id(), Redenomination::Merge(share + 1)), Err(Error::InvalidFactor));
+            assert_eq!(amm.redenominate_shares(String::from("account-2"), Redenomination::Split(2)), Err(Error::Unauthorized));
+            assert_eq!(amm.get_pool_info(), (10, 20, share, 0));
+        }
+
+        #[test]
+        fn test_redenominate_shares_rescales_allowances_and_caps() {
+            let mut amm = Amm::new(0);
+            amm.set_owner(get_account_id());
+            amm.get_free_tokens(get_account_id(), 100, 200).unwrap();
+            let share = amm.deposit(get_account_id(), 10, 20).unwrap();
+            let share = share + amm.deposit(get_account_id(), 5, 10).unwrap();
+            amm.approve_shares(get_account_id(), String::from("spender"), share / 2);
+            amm.approve_shares(get_account_id(), String::from("dust"), 1);
+            amm.set_deposit_caps(DepositCaps { max_account_shares: Some(share * 2), ..Default::default() });
+
+            amm.redenominate_shares(get_account_id(), Redenomination::Merge(1_000)).unwrap();
+            assert_eq!(amm.get_share_allowance(get_account_id(), String::from("spender")), share / 2 / 1_000);
+            assert_eq!(amm.get_share_allowance(get_account_id(), String::from("dust")), 0);
+            assert_eq!(amm.get_deposit_caps().max_account_shares, Some(share * 2 / 1_000));
+            let positions = amm.list_positions(&get_account_id());
+            assert_eq!(positions.len(), 2);
+            assert_eq!(positions.iter().map(|(_, position)| position.shares).sum::<u128>(), share / 1_000);
+        }
+
+        #[test]
+        fn test_positions() {
+            let mut amm = Amm::new(3);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            let bob = String::from("bob");
+            amm.get_free_tokens(get_account_id(), 2_000_000, 2_000_000).unwrap();
+            let first_shares = amm.deposit(get_account_id(), 1_000_000, 1_000_000).unwrap();
+            let first_prices = (amm.spot_price(Token::A).unwrap(), amm.spot_price(Token::B).unwrap());
+            amm.swap_token_a_for_token_b(get_account_id(), 100_000, 0).unwrap();
+            let (token_a_pool_balance, token_b_pool_balance, _, _) = amm.get_pool_info();
+            let second_shares = amm.deposit(get_account_id(), token_a_pool_balance / 10, token_b_pool_balance / 10).unwrap();
+
+            // every deposit opens its own position at the price it entered
+            let positions = amm.list_positions(&get_account_id());
+            assert_eq!(positions.iter().map(|(position_id, _)| *position_id).collect::<Vec<_>>(), vec![0, 1]);
+            assert_eq!(positions[0].1.shares, first_shares);
+            assert_eq!(positions[0].1.entry_prices, first_prices);
+            assert_eq!(positions[1].1.shares, second_shares);
+            assert!(positions[1].1.entry_prices.0 < first_prices.0);
+            let (token_a_fees, _) = amm.get_position_fees(0).unwrap();
+            assert!(token_a_fees > 0);
+            assert_eq!(amm.get_position_fees(1), Ok((0, 0)));
+
+            // fungible transfers draw on the oldest position
+            amm.transfer_shares(get_account_id(), bob.clone(), first_shares / 2).unwrap();
+            assert_eq!(amm.get_position(0).unwrap().shares, first_shares - first_shares / 2);
+            assert_eq!(amm.get_position(0).unwrap().accrued_fees.0, token_a_fees);
+            let (position_id, position) = amm.list_positions(&bob)[0];
+            assert_eq!((position_id, position.shares, position.entry_prices), (2, first_shares / 2, first_prices));
+
+            // whole positions change hands by id
+            assert_eq!(amm.transfer_position(bob.clone(), get_account_id(), 1), Err(Error::Unauthorized));
+            assert_eq!(amm.transfer_position(get_account_id(), bob.clone(), 1), Ok(second_shares));
+            assert_eq!(amm.get_position(1).unwrap().owner, bob);
+            assert_eq!(amm.get_account_balance(bob.clone()).2, first_shares / 2 + second_shares);
+
+            assert_eq!(amm.withdraw_position(bob.clone(), 0, 1), Err(Error::Unauthorized));
+            assert_eq!(amm.withdraw_position(get_account_id(), 0, first_shares), Err(Error::InsufficientAmount));
+            amm.withdraw_position(bob.clone(), 1, second_shares).unwrap();
+            assert_eq!(amm.get_position(1), None);
+            assert_eq!(amm.get_position_fees(1), Err(Error::PositionNotFound));
+            assert_eq!(amm.list_positions(&bob).len(), 1);
+            assert_eq!(amm.get_account_balance(bob.clone()).2, first_shares / 2);
+
+            // claimed fees no longer show on the claimant's positions
+            amm.swap_token_a_for_token_b(get_account_id(), 100_000, 0).unwrap();
+            let (token_a_fees, _) = amm.get_position_fees(0).unwrap();
+            let (claimed_a, _) = amm.claim_fees(get_account_id()).unwrap();
+            assert!(claimed_a >= token_a_fees);
+            assert_eq!(amm.get_position_fees(0), Ok((0, 0)));
+            assert!(amm.get_position_fees(2).unwrap().0 > 0);
+            assert!(amm.audit().unwrap().is_balanced().unwrap());
+        }
+    }
+}
+
+
+pub mod multi_asset {
+    use std::collections::HashMap;
+    use crate::amm::{add, mul, mul_div, sub, ConstantProduct, ConstantSum, Curve, Rounding, Token, Weighted};
+    use crate::{AccountId, Amount, AutomatedMarketMaker, Error, PRECISION};
+
+    /// How a swap between two assets of a `MultiAssetPool` is priced. A swap
+    /// only moves the two reserves involved, so it prices like a two-token
+    /// pool of those reserves on the matching `Curve`.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub enum BasketCurve {
+        /// Constant product of the two reserves
+        #[default]
+        ConstantProduct,
+        /// Assets pegged 1:1, as in a stable 3-pool
+        ConstantSum,
+        /// Weighted basket with one weight per asset, in asset order
+        Weighted(Vec<u32>),
+    }
+
+    /// Pool over any number of assets, with proportional deposits and
+    /// withdrawals and swaps between any two of them. `Amm` stays a
+    /// two-token pool: fee splits, caps, lot sizes, hooks and events are
+    /// only available there.
+    pub struct MultiAssetPool<T: Amount = u128, Id: AccountId = String> {
+        fees: u32,
+        curve: BasketCurve,
+        token_names: Vec<String>,
+        reserves: Vec<T>,
+        total_pool_shares: T,
+        user_balances: HashMap<Id, Vec<T>>,
+        user_pool_shares: HashMap<Id, T>,
+    }
+
+    impl<T: Amount, Id: AccountId> MultiAssetPool<T, Id> {
+        /// Creates an empty pool of the named assets charging `fees` per
+        /// mille on swaps. Needs at least two distinct assets.
+        pub fn new(fees: u32, token_names: &[&str]) -> Result<Self, Error> {
+            Self::with_curve(fees, token_names, BasketCurve::ConstantProduct)
+        }
+
+        /// `new` pricing swaps on `curve`. Weighted baskets need a non-zero
+        /// weight for every asset.
+        pub fn with_curve(fees: u32, token_names: &[&str], curve: BasketCurve) -> Result<Self, Error> {
+            if let BasketCurve::Weighted(weights) = &curve {
+                if weights.len() != token_names.len() {
+                    return Err(Error::AssetCountMismatch);
+                }
+                if weights.contains(&0) {
+                    return Err(Error::InvalidWeight);
+                }
+            }
+            let distinct = token_names.iter().enumerate()
+                .all(|(i, name)| !token_names[..i].contains(name));
+            if token_names.len() < 2 || !distinct {
+                return Err(Error::InvalidTokenPair);
+            }
+            Ok(Self {
+                fees: if fees >= 1000 { 0 } else { fees },
+                curve,
+                token_names: token_names.iter().map(|name| String::from(*name)).collect(),
+                reserves: vec![T::default(); token_names.len()],
+                total_pool_shares: T::default(),
+                user_balances: HashMap::new(),
+                user_pool_shares: HashMap::new(),
+            })
+        }
+
+        pub fn get_token_index(&self, token: &str) -> Result<usize, Error> {
+            self.token_names.iter().position(|name| name == token).ok_or(Error::UnknownToken)
+        }
+
+        pub fn get_reserves(&self) -> &[T] {
+            &self.reserves
+        }
+
+        pub fn get_total_pool_shares(&self) -> T {
+            self.total_pool_shares
+        }
+
+        pub fn get_account_balance(&self, account_id: &Id, token: &str) -> Result<T, Error> {
+            let index = self.get_token_index(token)?;
+            Ok(self.user_balances.get(account_id).map_or(T::default(), |balances| balances[index]))
+        }
+
+        pub fn get_account_shares(&self, account_id: &Id) -> T {
+            self.user_pool_shares.get(account_id).copied().unwrap_or_default()
+        }
+
+        pub fn get_free_tokens(&mut self, account_id: Id, token: &str, amount: T) -> Result<(), Error> {
+            let index = self.get_token_index(token)?;
+            let asset_count = self.token_names.len();
+            let balances = self.user_balances.entry(account_id)
+                .or_insert_with(|| vec![T::default(); asset_count]);
+            balances[index] = add(balances[index], amount)?;
+            Ok(())
+        }
+
+        /// Deposits `amounts`, given in asset order, in proportion to the
+        /// reserves and returns the minted shares
+        pub fn deposit(&mut self, account_id: Id, amounts: &[T]) -> Result<T, Error> {
+            if amounts.len() != self.reserves.len() {
+                return Err(Error::AssetCountMismatch);
+            }
+            if amounts.contains(&T::default()) {
+                return Err(Error::ZeroAmount);
+            }
+            let balances = self.user_balances.get(&account_id).ok_or(Error::InsufficientAmount)?;
+            if amounts.iter().zip(balances).any(|(amount, balance)| amount > balance) {
+                return Err(Error::InsufficientAmount);
+            }
+
+            let shares = if self.total_pool_shares == T::default() {
+                mul(T::from(100), T::from(PRECISION))?
+            } else {
+                let shares = amounts.iter().zip(&self.reserves)
+                    .map(|(amount, reserve)| mul_div(self.total_pool_shares, *amount, *reserve, Rounding::Down))
+                    .collect::<Result<Vec<T>, Error>>()?;
+                if shares.iter().any(|share| *share != shares[0]) {
+                    return Err(Error::NonEquivalentValue);
+                }
+                shares[0]
+            };
+            if shares == T::default() {
+                return Err(Error::ThresholdNotReached);
+            }
+
+            let reserves = self.reserves.iter().zip(amounts)
+                .map(|(reserve, amount)| add(*reserve, *amount))
+                .collect::<Result<Vec<T>, Error>>()?;
+            let balances = balances.iter().zip(amounts)
+                .map(|(balance, amount)| sub(*balance, *amount))
+                .collect::<Result<Vec<T>, Error>>()?;
+            let total_pool_shares = add(self.total_pool_shares, shares)?;
+            let account_shares = add(self.get_account_shares(&account_id), shares)?;
+
+            self.reserves = reserves;
+            self.total_pool_shares = total_pool_shares;
+            self.user_balances.insert(account_id.clone(), balances);
+            self.user_pool_shares.insert(account_id, account_shares);
+            Ok(shares)
+        }
+
+        /// Amounts of every asset, in asset order, that redeeming `share` pays out
+        pub fn get_withdraw_amount(&self, share: T) -> Result<Vec<T>, Error> {
+            if share > self.total_pool_shares {
+                return Err(Error::InvalidShare);
+            }
+            self.reserves.iter()
+                .map(|reserve| mul_div(*reserve, share, self.total_pool_shares, Rounding::Down))
+                .collect()
+        }
+
+        pub fn withdraw(&mut self, account_id: Id, share: T) -> Result<Vec<T>, Error> {
+            let account_shares = self.get_account_shares(&account_id);
+            if share == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            if share > account_shares {
+                return Err(Error::InsufficientAmount);
+            }
+            let amounts = self.get_withdraw_amount(share)?;
+
+            let reserves = self.reserves.iter().zip(&amounts)
+                .map(|(reserve, amount)| sub(*reserve, *amount))
+                .collect::<Result<Vec<T>, Error>>()?;
+            let asset_count = self.token_names.len();
+            let balances = self.user_balances.get(&account_id).cloned()
+                .unwrap_or_else(|| vec![T::default(); asset_count]);
+            let balances = balances.iter().zip(&amounts)
+                .map(|(balance, amount)| add(*balance, *amount))
+                .collect::<Result<Vec<T>, Error>>()?;
+            let total_pool_shares = sub(self.total_pool_shares, share)?;
+
+            self.reserves = reserves;
+            self.total_pool_shares = total_pool_shares;
+            self.user_balances.insert(account_id.clone(), balances);
+            self.user_pool_shares.insert(account_id, sub(account_shares, share)?);
+            Ok(amounts)
+        }
+
+        pub fn get_swap_amount(&self, token_in: &str, token_out: &str, amount_in: T) -> Result<T, Error> {
+            let (index_in, index_out) = (self.get_token_index(token_in)?, self.get_token_index(token_out)?);
+            if index_in == index_out {
+                return Err(Error::InvalidTokenPair);
+            }
+            let (reserve_in, reserve_out) = (self.reserves[index_in], self.reserves[index_out]);
+            if reserve_in == T::default() || reserve_out == T::default() {
+                return Err(Error::ZeroLiquidity);
+            }
+
+            let reserves = (reserve_in, reserve_out);
+            match &self.curve {
+                BasketCurve::ConstantProduct => ConstantProduct.amount_out(reserves, Token::A, amount_in, self.fees),
+                BasketCurve::ConstantSum => ConstantSum.amount_out(reserves, Token::A, amount_in, self.fees),
+                BasketCurve::Weighted(weights) => Weighted::new(weights[index_in], weights[index_out])?
+                    .amount_out(reserves, Token::A, amount_in, self.fees),
+            }
+        }
+
+        pub fn swap(&mut self, account_id: Id, token_in: &str, token_out: &str, amount_in: T, min_out: T)
+            -> Result<T, Error> {
+            let (index_in, index_out) = (self.get_token_index(token_in)?, self.get_token_index(token_out)?);
+            let balance_in = self.get_account_balance(&account_id, token_in)?;
+            if amount_in == T::default() {
+                return Err(Error::ZeroAmount);
+            }
+            if amount_in > balance_in {
+                return Err(Error::InsufficientAmount);
+            }
+            let amount_out = self.get_swap_amount(token_in, token_out, amount_in)?;
+            if amount_out < min_out {
+                return Err(Error::SlippageExceeded);
+            }
+
+            let reserve_in = add(self.reserves[index_in], amount_in)?;
+            let reserve_out = sub(self.reserves[index_out], amount_out)?;
+            let balances = self.user_balances.get_mut(&account_id).ok_or(Error::InsufficientAmount)?;
+            let balance_out = add(balances[index_out], amount_out)?;
+
+            balances[index_in] = sub(balance_in, amount_in)?;
+            balances[index_out] = balance_out;
+            self.reserves[index_in] = reserve_in;
+            self.reserves[index_out] = reserve_out;
+            Ok(amount_out)
+        }
+    }
+
+    /// Token A and token B are the first two assets. Deposits and
+    /// withdrawals move every asset, so they fail with
+    /// `Error::AssetCountMismatch` on pools of more than two.
+    impl<T: Amount, Id: AccountId> AutomatedMarketMaker<T, Id> for MultiAssetPool<T, Id> {
+        fn deposit(&mut self, account_id: Id, token_a_amount: T, token_b_amount: T) -> Result<T, Error> {
+            MultiAssetPool::deposit(self, account_id, &[token_a_amount, token_b_amount])
+        }
+
+        fn withdraw(&mut self, account_id: Id, share: T) -> Result<(T, T), Error> {
+            if self.reserves.len() != 2 {
+                return Err(Error::AssetCountMismatch);
+            }
+            let amounts = MultiAssetPool::withdraw(self, account_id, share)?;
+            Ok((amounts[0], amounts[1]))
+        }
+
+        fn swap_token_a_for_token_b(&mut self, account_id: Id, token_a_amount: T, min_token_b: T)
+            -> Result<T, Error> {
+            let (token_a, token_b) = (self.token_names[0].clone(), self.token_names[1].clone());
+            self.swap(account_id, &token_a, &token_b, token_a_amount, min_token_b)
+        }
+
+        fn swap_token_b_for_token_a(&mut self, account_id: Id, token_b_amount: T, min_token_a: T)
+            -> Result<T, Error> {
+            let (token_a, token_b) = (self.token_names[0].clone(), self.token_names[1].clone());
+            self.swap(account_id, &token_b, &token_a, token_b_amount, min_token_a)
+        }
+
+        fn get_swap_amount_for_token_b(&self, token_a_amount: T) -> Result<T, Error> {
+            self.get_swap_amount(&self.token_names[0], &self.token_names[1], token_a_amount)
+        }
+
+        fn get_swap_amount_for_token_a(&self, token_b_amount: T) -> Result<T, Error> {
+            self.get_swap_amount(&self.token_names[1], &self.token_names[0], token_b_amount)
+        }
+
+        fn get_withdraw_amount(&self, share: T) -> Result<(T, T), Error> {
+            if self.reserves.len() != 2 {
+                return Err(Error::AssetCountMismatch);
+            }
+            let amounts = MultiAssetPool::get_withdraw_amount(self, share)?;
+            Ok((amounts[0], amounts[1]))
+        }
+
+        fn get_pool_info(&self) -> (T, T, T, u32) {
+            (self.reserves[0], self.reserves[1], self.total_pool_shares, self.fees)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        type MultiAssetPool = super::MultiAssetPool<u128>;
+
+        fn get_account_id() -> String {
+            String::from("account-1")
+        }
+
+        fn three_pool() -> MultiAssetPool {
+            let mut pool = MultiAssetPool::new(3, &["USDC", "USDT", "DAI"]).unwrap();
+            for token in ["USDC", "USDT", "DAI"] {
+                pool.get_free_tokens(get_account_id(), token, 1_000_000).unwrap();
+            }
+            pool
+        }
+
+        #[test]
+        fn test_constructor() {
+            assert!(MultiAssetPool::new(0, &["USDC"]).is_err());
+            assert!(MultiAssetPool::new(0, &["USDC", "DAI", "USDC"]).is_err());
+            let pool = MultiAssetPool::new(0, &["USDC", "USDT", "DAI"]).unwrap();
+            assert_eq!(pool.get_token_index("DAI"), Ok(2));
+            assert_eq!(pool.get_token_index("WETH"), Err(Error::UnknownToken));
+        }
+
+        #[test]
+        fn test_deposit_and_withdraw() {
+            let mut pool = three_pool();
+            let share = pool.deposit(get_account_id(), &[100_000, 100_000, 200_000]).unwrap();
+            assert_eq!(pool.deposit(get_account_id(), &[10_000, 10_000, 20_000]), Ok(share / 10));
+            assert_eq!(pool.deposit(get_account_id(), &[10_000, 10_000, 10_000]), Err(Error::NonEquivalentValue));
+            assert_eq!(pool.deposit(get_account_id(), &[10_000, 10_000]), Err(Error::AssetCountMismatch));
+
+            assert_eq!(pool.withdraw(get_account_id(), share / 10), Ok(vec![10_000, 10_000, 20_000]));
+            assert_eq!(pool.get_reserves(), [100_000, 100_000, 200_000]);
+            assert_eq!(pool.get_account_balance(&get_account_id(), "DAI"), Ok(800_000));
+        }
+
+        #[test]
+        fn test_swap() {
+            let mut pool = three_pool();
+            pool.deposit(get_account_id(), &[100_000, 100_000, 100_000]).unwrap();
+            let amount_out = pool.get_swap_amount("USDC", "DAI", 1_000).unwrap();
+            assert_eq!(pool.swap(get_account_id(), "USDC", "DAI", 1_000, amount_out + 1), Err(Error::SlippageExceeded));
+            assert_eq!(pool.swap(get_account_id(), "USDC", "DAI", 1_000, amount_out), Ok(amount_out));
+            assert_eq!(pool.get_reserves(), [101_000, 100_000, 100_000 - amount_out]);
+            assert!(101_000 * (100_000 - amount_out) >= 100_000 * 100_000);
+            assert_eq!(pool.swap(get_account_id(), "USDC", "USDC", 1_000, 0), Err(Error::InvalidTokenPair));
+        }
+
+        #[test]
+        fn test_automated_market_maker() {
+            let mut pool = MultiAssetPool::new(3, &["USDC", "DAI"]).unwrap();
+            pool.get_free_tokens(get_account_id(), "USDC", 200_000).unwrap();
+            pool.get_free_tokens(get_account_id(), "DAI", 200_000).unwrap();
+            let pool: &mut dyn AutomatedMarketMaker = &mut pool;
+            let share = pool.deposit(get_account_id(), 100_000, 100_000).unwrap();
+            let amount_out = pool.get_swap_amount_for_token_b(1_000).unwrap();
+            assert_eq!(pool.swap_token_a_for_token_b(get_account_id(), 1_000, 0), Ok(amount_out));
+            assert_eq!(pool.get_pool_info(), (101_000, 100_000 - amount_out, share, 3));
+            assert_eq!(pool.withdraw(get_account_id(), share), Ok((101_000, 100_000 - amount_out)));
+
+            let mut pool = three_pool();
+            pool.deposit(get_account_id(), &[100_000, 100_000, 100_000]).unwrap();
+            let pool: &mut dyn AutomatedMarketMaker = &mut pool;
+            assert!(pool.swap_token_b_for_token_a(get_account_id(), 1_000, 0).is_ok());
+            assert_eq!(pool.deposit(get_account_id(), 1_000, 1_000), Err(Error::AssetCountMismatch));
+            assert_eq!(pool.withdraw(get_account_id(), 1), Err(Error::AssetCountMismatch));
+        }
+
+        #[test]
+        fn test_basket_curves() {
+            assert!(MultiAssetPool::with_curve(0, &["WETH", "WBTC", "DAI"], BasketCurve::Weighted(vec![50, 25])).is_err());
+            assert!(MultiAssetPool::with_curve(0, &["WETH", "WBTC", "DAI"], BasketCurve::Weighted(vec![50, 25, 0])).is_err());
+
+            // a stable 3-pool trades at par less the fee
+            let mut pool = MultiAssetPool::with_curve(3, &["USDC", "USDT", "DAI"], BasketCurve::ConstantSum).unwrap();
+            for token in ["USDC", "USDT", "DAI"] {
+                pool.get_free_tokens(get_account_id(), token, 1_000_000).unwrap();
+            }
+            pool.deposit(get_account_id(), &[100_000, 100_000, 100_000]).unwrap();
+            assert_eq!(pool.swap(get_account_id(), "USDC", "DAI", 10_000, 0), Ok(9_970));
+            assert_eq!(pool.get_swap_amount("USDT", "DAI", 100_000), Err(Error::InsufficientLiquidity));
+
+            // a weighted basket prices each pair by the two assets' weights
+            let weights = vec![50, 25, 25];
+            let mut pool = MultiAssetPool::with_curve(3, &["WETH", "WBTC", "DAI"], BasketCurve::Weighted(weights)).unwrap();
+            for token in ["WETH", "WBTC", "DAI"] {
+                pool.get_free_tokens(get_account_id(), token, 1_000_000).unwrap();
+            }
+            pool.deposit(get_account_id(), &[200_000, 100_000, 100_000]).unwrap();
+            let expected = Weighted::new(50, 25).unwrap().amount_out((200_000, 100_000), Token::A, 1_000, 3);
+            assert_eq!(pool.get_swap_amount("WETH", "DAI", 1_000), expected);
+            assert_eq!(pool.swap(get_account_id(), "WETH", "DAI", 1_000, 0), expected);
+        }
+    }
+}
+
+pub mod concentrated {
+    use std::collections::{BTreeMap, HashMap};
+    use crate::amm::{add, mul_div, sub, Rounding, Token};
+    use crate::{AccountId, AutomatedMarketMaker, Error};
+
+    //sqrt prices are Q64.64 fixed point
+    const Q64: u128 = 1 << 64;
+
+    pub const MIN_TICK: i32 = -400_000;
+    pub const MAX_TICK: i32 = 400_000;
+
+    /// Square root of the price `1.0001^tick` in Q64.64
+    pub fn sqrt_price_at_tick(tick: i32) -> u128 {
+        (1.0001f64.powf(f64::from(tick) / 2.0) * Q64 as f64) as u128
+    }
+
+    fn tick_at_sqrt_price(sqrt_price: u128) -> i32 {
+        ((sqrt_price as f64 / Q64 as f64).ln() * 2.0 / 1.0001f64.ln()).floor() as i32
+    }
+
+    /// Token A needed to move `liquidity` between two sqrt prices
+    fn token_a_delta(sqrt_lower: u128, sqrt_upper: u128, liquidity: u128, rounding: Rounding) -> Result<u128, Error> {
+        let scaled = mul_div(liquidity, sub(sqrt_upper, sqrt_lower)?, sqrt_upper, rounding)?;
+        mul_div(scaled, Q64, sqrt_lower, rounding)
+    }
+
+    /// Token B needed to move `liquidity` between two sqrt prices
+    fn token_b_delta(sqrt_lower: u128, sqrt_upper: u128, liquidity: u128, rounding: Rounding) -> Result<u128, Error> {
+        mul_div(liquidity, sub(sqrt_upper, sqrt_lower)?, Q64, rounding)
+    }
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    struct TickInfo {
+        liquidity_gross: u128,
+        liquidity_net: i128,
+        fee_growth_outside: (u128, u128),
+    }
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    struct Position {
+        liquidity: u128,
+        fee_growth_inside_last: (u128, u128),
+        fees_owed: (u128, u128),
+    }
+
+    /// Pool where liquidity providers place liquidity in a price range
+    /// `[lower_tick, upper_tick)` and only earn fees while the price is
+    /// inside it. Initialized ticks are kept in an ordered map, which serves
+    /// as the tick bitmap when a swap looks for the next tick to cross.
+    pub struct ConcentratedPool<Id: AccountId = String> {
+        fees: u32,
+        tick_spacing: i32,
+        sqrt_price: u128,
+        tick: i32,
+        liquidity: u128,
+        fee_growth_global: (u128, u128),
+        ticks: BTreeMap<i32, TickInfo>,
+        positions: HashMap<(Id, i32, i32), Position>,
+        token_a_pool_balance: u128,
+        token_b_pool_balance: u128,
+        user_balances: HashMap<Id, (u128, u128)>,
+    }
+
+    impl<Id: AccountId> ConcentratedPool<Id> {
+        /// Creates an empty pool charging `fees` per mille on swaps, with the
+        /// price starting at `1.0001^initial_tick`
+        pub fn new(fees: u32, tick_spacing: i32, initial_tick: i32) -> Result<Self, Error> {
+            if tick_spacing <= 0 || !(MIN_TICK..MAX_TICK).contains(&initial_tick) {
+                return Err(Error::InvalidTickRange);
+            }
+            Ok(Self {
+                fees: if fees >= 1000 { 0 } else { fees },
+                tick_spacing,
+                sqrt_price: sqrt_price_at_tick(initial_tick),
+                tick: initial_tick,
+                liquidity: 0,
+                fee_growth_global: (0, 0),
+                ticks: BTreeMap::new(),
+                positions: HashMap::new(),
+                token_a_pool_balance: 0,
+                token_b_pool_balance: 0,
+                user_balances: HashMap::new(),
+            })
+        }
+
+        pub fn get_tick(&self) -> i32 {
+            self.tick
+        }
+
+        pub fn get_sqrt_price(&self) -> u128 {
+            self.sqrt_price
+        }
+
+        /// Liquidity active at the current price
+        pub fn get_liquidity(&self) -> u128 {
+            self.liquidity
+        }
+
+        pub fn get_pool_balance(&self) -> (u128, u128) {
+            (self.token_a_pool_balance, self.token_b_pool_balance)
+        }
+
+        pub fn get_account_balance(&self, account_id: &Id) -> (u128, u128) {
+            self.user_balances.get(account_id).copied().unwrap_or_default()
+        }
+
+        pub fn get_position_liquidity(&self, account_id: &Id, lower_tick: i32, upper_tick: i32) -> u128 {
+            self.positions.get(&(account_id.clone(), lower_tick, upper_tick)).map_or(0, |position| position.liquidity)
+        }
+
+        pub fn get_free_tokens(&mut self, account_id: Id, token_a_amount: u128, token_b_amount: u128)
+            -> Result<(), Error> {
+            let (token_a_balance, token_b_balance) = self.get_account_balance(&account_id);
+            let balances = (add(token_a_balance, token_a_amount)?, add(token_b_balance, token_b_amount)?);
+            self.user_balances.insert(account_id, balances);
+            Ok(())
+        }
+
+        /// Widest range the tick spacing allows, where positions opened
+        /// through `AutomatedMarketMaker` are placed
+        pub fn get_full_range(&self) -> (i32, i32) {
+            (MIN_TICK - MIN_TICK % self.tick_spacing, MAX_TICK - MAX_TICK % self.tick_spacing)
+        }
+
+        /// Most liquidity in the range that `token_a_amount` and
+        /// `token_b_amount` can back at the current price
+        pub fn get_liquidity_for_amounts(&self, lower_tick: i32, upper_tick: i32, token_a_amount: u128,
+            token_b_amount: u128) -> Result<u128, Error> {
+            self.is_valid_range(lower_tick, upper_tick)?;
+            let (sqrt_lower, sqrt_upper) = (sqrt_price_at_tick(lower_tick), sqrt_price_at_tick(upper_tick));
+            let liquidity_for_a = |sqrt_lower: u128| {
+                let scaled = mul_div(token_a_amount, sqrt_lower, Q64, Rounding::Down)?;
+                mul_div(scaled, sqrt_upper, sub(sqrt_upper, sqrt_lower)?, Rounding::Down)
+            };
+            let liquidity_for_b = |sqrt_upper: u128| {
+                mul_div(token_b_amount, Q64, sub(sqrt_upper, sqrt_lower)?, Rounding::Down)
+            };
+            let mut liquidity = if self.tick < lower_tick {
+                liquidity_for_a(sqrt_lower)?
+            } else if self.tick >= upper_tick {
+                liquidity_for_b(sqrt_upper)?
+            } else {
+                liquidity_for_a(self.sqrt_price)?.min(liquidity_for_b(self.sqrt_price)?)
+            };
+            // the amounts a deposit takes are rounded up
+            loop {
+                let (token_a_needed, token_b_needed) =
+                    self.get_amounts_for_liquidity(lower_tick, upper_tick, liquidity, Rounding::Up)?;
+                if token_a_needed <= token_a_amount && token_b_needed <= token_b_amount {
+                    return Ok(liquidity);
+                }
+                liquidity = liquidity.saturating_sub(liquidity / 1_000_000 + 1);
+            }
+        }
+
+        fn is_valid_range(&self, lower_tick: i32, upper_tick: i32) -> Result<(), Error> {
+            if lower_tick >= upper_tick || lower_tick < MIN_TICK || upper_tick > MAX_TICK
+                || lower_tick % self.tick_spacing != 0 || upper_tick % self.tick_spacing != 0 {
+                return Err(Error::InvalidTickRange);
+            }
+            Ok(())
+        }
+
+        /// Token amounts backing `liquidity` in the range at the current price
+        pub fn get_amounts_for_liquidity(&self, lower_tick: i32, upper_tick: i32, liquidity: u128, rounding: Rounding)
+            -> Result<(u128, u128), Error> {
+            self.is_valid_range(lower_tick, upper_tick)?;
+            let (sqrt_lower, sqrt_upper) = (sqrt_price_at_tick(lower_tick), sqrt_price_at_tick(upper_tick));
+            if self.tick < lower_tick {
+                Ok((token_a_delta(sqrt_lower, sqrt_upper, liquidity, rounding)?, 0))
+            } else if self.tick >= upper_tick {
+                Ok((0, token_b_delta(sqrt_lower, sqrt_upper, liquidity, rounding)?))
+            } else {
+                Ok((
+                    token_a_delta(self.sqrt_price, sqrt_upper, liquidity, rounding)?,
+                    token_b_delta(sqrt_lower, self.sqrt_price, liquidity, rounding)?,
+                ))
+            }
+        }
+
+        fn get_fee_growth_inside(&self, lower_tick: i32, upper_tick: i32) -> (u128, u128) {
+            let (global_a, global_b) = self.fee_growth_global;
+            let outside = |tick: i32| self.ticks.get(&tick).map_or((0, 0), |info| info.fee_growth_outside);
+            let (lower_a, lower_b) = outside(lower_tick);
+            let (upper_a, upper_b) = outside(upper_tick);
+            let (below_a, below_b) = match self.tick >= lower_tick {
+                true => (lower_a, lower_b),
+                false => (global_a.wrapping_sub(lower_a), global_b.wrapping_sub(lower_b)),
+            };
+            let (above_a, above_b) = match self.tick < upper_tick {
+                true => (upper_a, upper_b),
+                false => (global_a.wrapping_sub(upper_a), global_b.wrapping_sub(upper_b)),
+            };
+            (
+                global_a.wrapping_sub(below_a).wrapping_sub(above_a),
+                global_b.wrapping_sub(below_b).wrapping_sub(above_b),
+            )
+        }
+
+        /// Applies a liquidity change to a position and its boundary ticks,
+        /// moving fees earned since the last update into the position
+        fn update_position(&mut self, account_id: &Id, lower_tick: i32, upper_tick: i32, liquidity_delta: i128)
+            -> Result<Position, Error> {
+            for (tick, net_sign) in [(lower_tick, 1), (upper_tick, -1)] {
+                let fee_growth_global = self.fee_growth_global;
+                let current_tick = self.tick;
+                let info = self.ticks.entry(tick).or_insert_with(|| TickInfo {
+                    fee_growth_outside: if tick <= current_tick { fee_growth_global } else { (0, 0) },
+                    ..Default::default()
+                });
+                info.liquidity_gross = info.liquidity_gross.checked_add_signed(liquidity_delta).ok_or(Error::Overflow)?;
+                info.liquidity_net = info.liquidity_net.checked_add(net_sign * liquidity_delta).ok_or(Error::Overflow)?;
+            }
+
+            let fee_growth_inside = self.get_fee_growth_inside(lower_tick, upper_tick);
+            let key = (account_id.clone(), lower_tick, upper_tick);
+            let mut position = self.positions.get(&key).copied().unwrap_or_default();
+            let earned = |inside: u128, last: u128| mul_div(inside.wrapping_sub(last), position.liquidity, Q64, Rounding::Down);
+            position.fees_owed = (
+                add(position.fees_owed.0, earned(fee_growth_inside.0, position.fee_growth_inside_last.0)?)?,
+                add(position.fees_owed.1, earned(fee_growth_inside.1, position.fee_growth_inside_last.1)?)?,
+            );
+            position.fee_growth_inside_last = fee_growth_inside;
+            position.liquidity = position.liquidity.checked_add_signed(liquidity_delta).ok_or(Error::Overflow)?;
+
+            for tick in [lower_tick, upper_tick] {
+                if self.ticks.get(&tick).is_some_and(|info| info.liquidity_gross == 0) {
+                    self.ticks.remove(&tick);
+                }
+            }
+            if (lower_tick..upper_tick).contains(&self.tick) {
+                self.liquidity = self.liquidity.checked_add_signed(liquidity_delta).ok_or(Error::Overflow)?;
+            }
+            self.positions.insert(key, position);
+            Ok(position)
+        }
+
+        /// Adds `liquidity` to the account's position in the range, taking
+        /// the token amounts it requires, rounded up
+        pub fn deposit(&mut self, account_id: Id, lower_tick: i32, upper_tick: i32, liquidity: u128,
+            max_token_a: u128, max_token_b: u128) -> Result<(u128, u128), Error> {
+            if liquidity == 0 {
+                return Err(Error::ZeroAmount);
+            }
+            let (token_a_amount, token_b_amount) =
+                self.get_amounts_for_liquidity(lower_tick, upper_tick, liquidity, Rounding::Up)?;
+            if token_a_amount > max_token_a || token_b_amount > max_token_b {
+                return Err(Error::SlippageExceeded);
+            }
+            let (token_a_balance, token_b_balance) = self.get_account_balance(&account_id);
+            if token_a_amount > token_a_balance || token_b_amount > token_b_balance {
+                return Err(Error::InsufficientAmount);
+            }
+            let liquidity_delta = i128::try_from(liquidity).map_err(|_| Error::Overflow)?;
+            let token_a_pool_balance = add(self.token_a_pool_balance, token_a_amount)?;
+            let token_b_pool_balance = add(self.token_b_pool_balance, token_b_amount)?;
+
+            self.update_position(&account_id, lower_tick, upper_tick, liquidity_delta)?;
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = token_b_pool_balance;
+            self.user_balances.insert(account_id, (token_a_balance - token_a_amount, token_b_balance - token_b_amount));
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        /// Removes `liquidity` from the account's position in the range and
+        /// pays out the backing tokens, rounded down, together with every fee
+        /// the position has earned
+        pub fn withdraw(&mut self, account_id: Id, lower_tick: i32, upper_tick: i32, liquidity: u128)
+            -> Result<(u128, u128), Error> {
+            if liquidity > self.get_position_liquidity(&account_id, lower_tick, upper_tick) {
+                return Err(Error::InsufficientAmount);
+            }
+            let (token_a_amount, token_b_amount) =
+                self.get_amounts_for_liquidity(lower_tick, upper_tick, liquidity, Rounding::Down)?;
+            let liquidity_delta = i128::try_from(liquidity).map_err(|_| Error::Overflow)?;
+
+            let mut position = self.update_position(&account_id, lower_tick, upper_tick, -liquidity_delta)?;
+            let token_a_amount = add(token_a_amount, position.fees_owed.0)?;
+            let token_b_amount = add(token_b_amount, position.fees_owed.1)?;
+            let key = (account_id.clone(), lower_tick, upper_tick);
+            position.fees_owed = (0, 0);
+            match position.liquidity {
+                0 => self.positions.remove(&key),
+                _ => self.positions.insert(key, position),
+            };
+
+            let (token_a_balance, token_b_balance) = self.get_account_balance(&account_id);
+            self.token_a_pool_balance = sub(self.token_a_pool_balance, token_a_amount)?;
+            self.token_b_pool_balance = sub(self.token_b_pool_balance, token_b_amount)?;
+            self.user_balances.insert(account_id, (add(token_a_balance, token_a_amount)?, add(token_b_balance, token_b_amount)?));
+            Ok((token_a_amount, token_b_amount))
+        }
+
+        /// Walks the swap across initialized ticks without changing the pool.
+        /// Returns the output, the final price, tick and liquidity, the fee
+        /// growth and the ticks crossed.
+        fn simulate_swap(&self, token_in: Token, amount_in: u128) -> Result<SwapOutcome, Error> {
+            let mut outcome = SwapOutcome {
+                amount_out: 0,
+                sqrt_price: self.sqrt_price,
+                tick: self.tick,
+                liquidity: self.liquidity,
+                fee_growth_global: self.fee_growth_global,
+                crossed_ticks: Vec::new(),
+            };
+            let mut remaining = amount_in;
+            while remaining > 0 {
+                let next_tick = match token_in {
+                    Token::A => self.ticks.range(..=outcome.tick).next_back(),
+                    Token::B => self.ticks.range(outcome.tick + 1..).next(),
+                };
+                let Some((&next_tick, info)) = next_tick else {
+                    return Err(Error::InsufficientLiquidity);
+                };
+                let sqrt_target = sqrt_price_at_tick(next_tick);
+                let liquidity = outcome.liquidity;
+
+                let net_remaining = mul_div(remaining, u128::from(1000 - self.fees), 1000, Rounding::Down)?;
+                let max_in = match (liquidity, token_in) {
+                    (0, _) => 0,
+                    (_, Token::A) => token_a_delta(sqrt_target, outcome.sqrt_price, liquidity, Rounding::Up)?,
+                    (_, Token::B) => token_b_delta(outcome.sqrt_price, sqrt_target, liquidity, Rounding::Up)?,
+                };
+                let (step_in, step_fee, sqrt_next) = if net_remaining >= max_in {
+                    let step_fee = mul_div(max_in, u128::from(self.fees), u128::from(1000 - self.fees), Rounding::Up)?;
+                    (max_in, step_fee, sqrt_target)
+                } else {
+                    let sqrt_next = match token_in {
+                        Token::A => {
+                            let denominator = add(liquidity, mul_div(net_remaining, outcome.sqrt_price, Q64, Rounding::Down)?)?;
+                            mul_div(liquidity, outcome.sqrt_price, denominator, Rounding::Up)?
+                        }
+                        Token::B => add(outcome.sqrt_price, mul_div(net_remaining, Q64, liquidity, Rounding::Down)?)?,
+                    };
+                    (net_remaining, remaining - net_remaining, sqrt_next)
+                };
+                let step_out = match (liquidity, token_in) {
+                    (0, _) => 0,
+                    (_, Token::A) => token_b_delta(sqrt_next, outcome.sqrt_price, liquidity, Rounding::Down)?,
+                    (_, Token::B) => token_a_delta(outcome.sqrt_price, sqrt_next, liquidity, Rounding::Down)?,
+                };
+
+                remaining = sub(remaining, add(step_in, step_fee)?)?;
+                outcome.amount_out = add(outcome.amount_out, step_out)?;
+                if liquidity > 0 {
+                    let growth = mul_div(step_fee, Q64, liquidity, Rounding::Down)?;
+                    match token_in {
+                        Token::A => outcome.fee_growth_global.0 = outcome.fee_growth_global.0.wrapping_add(growth),
+                        Token::B => outcome.fee_growth_global.1 = outcome.fee_growth_global.1.wrapping_add(growth),
+                    }
+                }
+                outcome.sqrt_price = sqrt_next;
+
+                if sqrt_next == sqrt_target {
+                    outcome.crossed_ticks.push(next_tick);
+                    outcome.liquidity = match token_in {
+                        Token::A => liquidity.checked_add_signed(-info.liquidity_net),
+                        Token::B => liquidity.checked_add_signed(info.liquidity_net),
+                    }.ok_or(Error::Overflow)?;
+                    outcome.tick = match token_in {
+                        Token::A => next_tick - 1,
+                        Token::B => next_tick,
+                    };
+                } else {
+                    let tick = tick_at_sqrt_price(sqrt_next);
+                    outcome.tick = match token_in {
+                        Token::A => tick.clamp(next_tick, outcome.tick),
+                        Token::B => tick.clamp(outcome.tick, next_tick - 1),
+                    };
+                }
+            }
+            Ok(outcome)
+        }
+
+        pub fn get_swap_amount(&self, token_in: Token, amount_in: u128) -> Result<u128, Error> {
+            Ok(self.simulate_swap(token_in, amount_in)?.amount_out)
+        }
+
+        /// Swaps `amount_in` of `token_in`, crossing ticks as the price moves.
+        /// Fails with `Error::InsufficientLiquidity` if the initialized ranges
+        /// run out before the input is used up.
+        pub fn swap(&mut self, account_id: Id, token_in: Token, amount_in: u128, min_out: u128) -> Result<u128, Error> {
+            let (token_a_balance, token_b_balance) = self.get_account_balance(&account_id);
+            let balance_in = match token_in {
+                Token::A => token_a_balance,
+                Token::B => token_b_balance,
+            };
+            if amount_in == 0 {
+                return Err(Error::ZeroAmount);
+            }
+            if amount_in > balance_in {
+                return Err(Error::InsufficientAmount);
+            }
+            let outcome = self.simulate_swap(token_in, amount_in)?;
+            if outcome.amount_out < min_out {
+                return Err(Error::SlippageExceeded);
+            }
+
+            let (balances, token_a_pool_balance, token_b_pool_balance) = match token_in {
+                Token::A => (
+                    (token_a_balance - amount_in, add(token_b_balance, outcome.amount_out)?),
+                    add(self.token_a_pool_balance, amount_in)?,
+                    sub(self.token_b_pool_balance, outcome.amount_out)?,
+                ),
+                Token::B => (
+                    (add(token_a_balance, outcome.amount_out)?, token_b_balance - amount_in),
+                    sub(self.token_a_pool_balance, outcome.amount_out)?,
+                    add(self.token_b_pool_balance, amount_in)?,
+                ),
+            };
+            for tick in &outcome.crossed_ticks {
+                if let Some(info) = self.ticks.get_mut(tick) {
+                    info.fee_growth_outside = (
+                        outcome.fee_growth_global.0.wrapping_sub(info.fee_growth_outside.0),
+                        outcome.fee_growth_global.1.wrapping_sub(info.fee_growth_outside.1),
+                    );
+                }
+            }
+            self.sqrt_price = outcome.sqrt_price;
+            self.tick = outcome.tick;
+            self.liquidity = outcome.liquidity;
+            self.fee_growth_global = outcome.fee_growth_global;
+            self.token_a_pool_balance = token_a_pool_balance;
+            self.token_b_pool_balance = token_b_pool_balance;
+            self.user_balances.insert(account_id, balances);
+            Ok(outcome.amount_out)
+        }
+    }
+
+    /// Deposits and withdrawals go to the account's full range position and
+    /// count liquidity as shares. The pool info reports the liquidity active
+    /// at the current price in place of the total shares.
+    impl<Id: AccountId> AutomatedMarketMaker<u128, Id> for ConcentratedPool<Id> {
+        fn deposit(&mut self, account_id: Id, token_a_amount: u128, token_b_amount: u128) -> Result<u128, Error> {
+            let (lower_tick, upper_tick) = self.get_full_range();
+            let liquidity = self.get_liquidity_for_amounts(lower_tick, upper_tick, token_a_amount, token_b_amount)?;
+            ConcentratedPool::deposit(self, account_id, lower_tick, upper_tick, liquidity, token_a_amount, token_b_amount)?;
+            Ok(liquidity)
+        }
+
+        fn withdraw(&mut self, account_id: Id, share: u128) -> Result<(u128, u128), Error> {
+            let (lower_tick, upper_tick) = self.get_full_range();
+            ConcentratedPool::withdraw(self, account_id, lower_tick, upper_tick, share)
+        }
+
+        fn swap_token_a_for_token_b(&mut self, account_id: Id, token_a_amount: u128, min_token_b: u128)
+            -> Result<u128, Error> {
+            self.swap(account_id, Token::A, token_a_amount, min_token_b)
+        }
+
+        fn swap_token_b_for_token_a(&mut self, account_id: Id, token_b_amount: u128, min_token_a: u128)
+            -> Result<u128, Error> {
+            self.swap(account_id, Token::B, token_b_amount, min_token_a)
+        }
+
+        fn get_swap_amount_for_token_b(&self, token_a_amount: u128) -> Result<u128, Error> {
+            self.get_swap_amount(Token::A, token_a_amount)
+        }
+
+        fn get_swap_amount_for_token_a(&self, token_b_amount: u128) -> Result<u128, Error> {
+            self.get_swap_amount(Token::B, token_b_amount)
+        }
+
+        /// Token amounts backing `share` of full range liquidity, before fees
+        fn get_withdraw_amount(&self, share: u128) -> Result<(u128, u128), Error> {
+            let (lower_tick, upper_tick) = self.get_full_range();
+            self.get_amounts_for_liquidity(lower_tick, upper_tick, share, Rounding::Down)
+        }
+
+        fn get_pool_info(&self) -> (u128, u128, u128, u32) {
+            (self.token_a_pool_balance, self.token_b_pool_balance, self.liquidity, self.fees)
+        }
+    }
+
+    struct SwapOutcome {
+        amount_out: u128,
+        sqrt_price: u128,
+        tick: i32,
+        liquidity: u128,
+        fee_growth_global: (u128, u128),
+        crossed_ticks: Vec<i32>,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        type ConcentratedPool = super::ConcentratedPool<String>;
+
+        fn get_account_id() -> String {
+            String::from("account-1")
+        }
+
+        fn funded_pool(fees: u32) -> ConcentratedPool {
+            let mut pool = ConcentratedPool::new(fees, 10, 0).unwrap();
+            pool.get_free_tokens(String::from("lp"), 1_000_000_000, 1_000_000_000).unwrap();
+            pool.get_free_tokens(get_account_id(), 1_000_000_000, 1_000_000_000).unwrap();
+            pool
+        }
+
+        #[test]
+        fn test_deposit_amounts_by_range() {
+            let mut pool = funded_pool(0);
+            assert_eq!(pool.deposit(String::from("lp"), 10, 5, 1, u128::MAX, u128::MAX), Err(Error::InvalidTickRange));
+            assert_eq!(pool.deposit(String::from("lp"), -15, 10, 1, u128::MAX, u128::MAX), Err(Error::InvalidTickRange));
+
+            let (token_a_amount, token_b_amount) = pool.deposit(String::from("lp"), -1_000, 1_000, 1_000_000, u128::MAX, u128::MAX).unwrap();
+            assert!(token_a_amount > 0 && token_b_amount > 0);
+            assert!(token_a_amount.abs_diff(token_b_amount) <= 1);
+            assert_eq!(pool.get_liquidity(), 1_000_000);
+
+            let (token_a_amount, token_b_amount) = pool.deposit(String::from("lp"), 1_000, 2_000, 1_000_000, u128::MAX, u128::MAX).unwrap();
+            assert!(token_a_amount > 0 && token_b_amount == 0);
+            let (token_a_amount, token_b_amount) = pool.deposit(String::from("lp"), -2_000, -1_000, 1_000_000, u128::MAX, u128::MAX).unwrap();
+            assert!(token_a_amount == 0 && token_b_amount > 0);
+            assert_eq!(pool.get_liquidity(), 1_000_000);
+            assert_eq!(pool.deposit(String::from("lp"), -10, 10, 1_000_000, 0, 0), Err(Error::SlippageExceeded));
+        }
+
+        #[test]
+        fn test_automated_market_maker() {
+            let mut pool = funded_pool(3);
+            let (lower_tick, upper_tick) = pool.get_full_range();
+            assert_eq!((lower_tick, upper_tick), (-400_000, 400_000));
+            let pool: &mut dyn AutomatedMarketMaker = &mut pool;
+
+            let liquidity = pool.deposit(String::from("lp"), 1_000_000, 1_000_000).unwrap();
+            let (token_a_pool_balance, token_b_pool_balance, active_liquidity, fees) = pool.get_pool_info();
+            assert!(token_a_pool_balance <= 1_000_000 && token_a_pool_balance > 999_000);
+            assert!(token_b_pool_balance <= 1_000_000 && token_b_pool_balance > 999_000);
+            assert_eq!((active_liquidity, fees), (liquidity, 3));
+
+            let amount_out = pool.get_swap_amount_for_token_b(10_000).unwrap();
+            assert_eq!(pool.swap_token_a_for_token_b(get_account_id(), 10_000, 0), Ok(amount_out));
+            assert!(amount_out > 9_800 && amount_out < 9_970);
+            let (token_a_amount, token_b_amount) = pool.withdraw(String::from("lp"), liquidity).unwrap();
+            assert!(token_a_amount > token_a_pool_balance && token_b_amount < token_b_pool_balance);
+        }
+
+        #[test]
+        fn test_swap_within_range() {
+            let mut pool = funded_pool(3);
+            pool.deposit(String::from("lp"), -6_000, 6_000, 10_000_000, u128::MAX, u128::MAX).unwrap();
+            let quote = pool.get_swap_amount(Token::A, 10_000).unwrap();
+            assert_eq!(pool.swap(get_account_id(), Token::A, 10_000, quote + 1), Err(Error::SlippageExceeded));
+            assert_eq!(pool.swap(get_account_id(), Token::A, 10_000, quote), Ok(quote));
+            assert!(quote > 9_900 && quote < 9_970);
+            assert!(pool.get_tick() < 0);
+            assert_eq!(pool.swap(get_account_id(), Token::B, 1_000_000_000, 0), Err(Error::InsufficientLiquidity));
+        }
+
+        #[test]
+        fn test_swap_crosses_ticks() {
+            let mut pool = funded_pool(0);
+            pool.deposit(String::from("lp"), -100, 100, 1_000_000, u128::MAX, u128::MAX).unwrap();
+            pool.deposit(String::from("lp"), 100, 1_000, 1_000_000, u128::MAX, u128::MAX).unwrap();
+
+            pool.swap(get_account_id(), Token::B, 10_000, 0).unwrap();
+            assert!(pool.get_tick() >= 100 && pool.get_tick() < 1_000);
+            assert_eq!(pool.get_liquidity(), 1_000_000);
+
+            let (token_a_amount, _) = pool.withdraw(String::from("lp"), -100, 100, 1_000_000).unwrap();
+            assert_eq!(token_a_amount, 0);
+            assert_eq!(pool.get_liquidity(), 1_000_000);
+        }
+
+        #[test]
+        fn test_fees_stay_with_in_range_positions() {
+            let mut pool = funded_pool(10);
+            pool.deposit(String::from("lp"), -1_000, 1_000, 10_000_000, u128::MAX, u128::MAX).unwrap();
+            pool.get_free_tokens(String::from("idle"), 1_000_000, 1_000_000).unwrap();
+            let deposited = pool.deposit(String::from("idle"), 2_000, 3_000, 10_000_000, u128::MAX, u128::MAX).unwrap();
+
+            for _ in 0..10 {
+                let token_b_amount = pool.swap(get_account_id(), Token::A, 10_000, 0).unwrap();
+                pool.swap(get_account_id(), Token::B, token_b_amount, 0).unwrap();
+            }
+            let (token_a_amount, token_b_amount) = pool.withdraw(String::from("lp"), -1_000, 1_000, 10_000_000).unwrap();
+            assert!(token_a_amount + token_b_amount > 2 * 487_703);
+            let (token_a_amount, token_b_amount) = pool.withdraw(String::from("idle"), 2_000, 3_000, 10_000_000).unwrap();
+            assert!(token_a_amount <= deposited.0 && token_a_amount + 2 >= deposited.0);
+            assert_eq!(token_b_amount, 0);
+
+            let total = |pool: &ConcentratedPool| {
+                let accounts = ["lp", "idle", "account-1"].map(|account| pool.get_account_balance(&String::from(account)));
+                let (token_a_pool_balance, token_b_pool_balance) = pool.get_pool_balance();
+                (accounts.iter().map(|balance| balance.0).sum::<u128>() + token_a_pool_balance,
+                    accounts.iter().map(|balance| balance.1).sum::<u128>() + token_b_pool_balance)
+            };
+            assert_eq!(total(&pool), (2_001_000_000, 2_001_000_000));
+        }
+    }
+}
+
+pub mod vault {
+    use std::collections::HashMap;
+    use crate::amm::{add, mul_div, sub, Amm, Rounding, Token};
+    use crate::{AccountId, Amount, Error};
+
+    /// Auto-compounding vault. Users move pool shares into the vault's own
+    /// pool account and receive vault shares; `harvest` claims the fees the
+    /// vault's pool shares earned and deposits them back, so every vault
+    /// share redeems for a growing number of pool shares. Best used with
+    /// pools in `FeeMode::Accumulate`, where fees are claimable.
+    pub struct Vault<T: Amount = u128, Id: AccountId = String> {
+        account_id: Id,
+        total_shares: T,
+        user_shares: HashMap<Id, T>,
+    }
+
+    impl<T: Amount, Id: AccountId> Vault<T, Id> {
+        /// Creates an empty vault holding its pool shares under `account_id`,
+        /// which must not be used by anyone else in the pool
+        pub fn new(account_id: Id) -> Self {
+            Self {
+                account_id,
+                total_shares: T::default(),
+                user_shares: HashMap::new(),
+            }
+        }
+
+        pub fn get_account_id(&self) -> &Id {
+            &self.account_id
+        }
+
+        pub fn get_total_shares(&self) -> T {
+            self.total_shares
+        }
+
+        pub fn get_vault_shares(&self, account_id: &Id) -> T {
+            self.user_shares.get(account_id).copied().unwrap_or_default()
+        }
+
+        /// Pool shares held by the vault
+        pub fn get_pool_shares(&self, amm: &Amm<T, Id>) -> T {
+            amm.get_account_balance(self.account_id.clone()).2
+        }
+
+        /// Pool shares `vault_shares` currently redeem for, rounded down
+        pub fn get_redeem_amount(&self, amm: &Amm<T, Id>, vault_shares: T) -> Result<T, Error> {
+            if vault_shares > self.total_shares {
+                return Err(Error::InvalidShare);
+            }
+            if vault_shares == T::default() {
+                return Ok(T::default());
+            }
+            mul_div(self.get_pool_shares(amm), vault_shares, self.total_shares, Rounding::Down)
+        }
+
+        /// Moves `pool_shares` from the account into the vault and mints
+        /// vault shares at the current exchange rate, rounded down
+        pub fn deposit(&mut self, amm: &mut Amm<T, Id>, account_id: Id, pool_shares: T) -> Result<T, Error> {
+            let pool_shares_held = self.get_pool_shares(amm);
+            let vault_shares = match self.total_shares == T::default() {
+                true => pool_shares,
+                false => mul_div(self.total_shares, pool_shares, pool_shares_held, Rounding::Down)?,
+            };
+            if vault_shares == T::default() {
+                return Err(Error::ThresholdNotReached);
+            }
+            let account_shares = add(self.get_vault_shares(&account_id), vault_shares)?;
+            let total_shares = add(self.total_shares, vault_shares)?;
+
+            amm.transfer_shares(account_id.clone(), self.account_id.clone(), pool_shares)?;
+            self.user_shares.insert(account_id, account_shares);
+            self.total_shares = total_shares;
+            Ok(vault_shares)
+        }
+
+        /// Burns `vault_shares` and moves the pool shares they redeem for back
+        /// to the account
+        pub fn withdraw(&mut self, amm: &mut Amm<T, Id>, account_id: Id, vault_shares: T) -> Result<T, Error> {
+            let account_shares = self.get_vault_shares(&account_id);
+            if vault_shares > account_shares {
+                return Err(Error::InsufficientAmount);
+            }
+            let pool_shares = self.get_redeem_amount(amm, vault_shares)?;
+            let account_shares = sub(account_shares, vault_shares)?;
+            let total_shares = sub(self.total_shares, vault_shares)?;
+
+            if pool_shares > T::default() {
+                amm.transfer_shares(self.account_id.clone(), account_id.clone(), pool_shares)?;
+            }
+            match account_shares == T::default() {
+                true => self.user_shares.remove(&account_id),
+                false => self.user_shares.insert(account_id, account_shares),
+            };
+            self.total_shares = total_shares;
+            Ok(pool_shares)
+        }
+
+        /// Claims the vault's fees and rewards and deposits the fee tokens
+        /// back into the pool, single-sided if only one token was earned.
+        /// Rewards stay in the vault's reward balance. Returns the pool
+        /// shares minted. Fails with `Error::SlippageExceeded`, leaving the
+        /// claimed tokens with the vault, if the deposit would mint fewer
+        /// than `min_shares`.
+        pub fn harvest(&mut self, amm: &mut Amm<T, Id>, min_shares: T) -> Result<T, Error> {
+            amm.claim_fees(self.account_id.clone())?;
+            amm.claim_rewards(self.account_id.clone())?;
+            let (token_a_amount, token_b_amount, _) = amm.get_account_balance(self.account_id.clone());
+            let zero = T::default();
+            match (token_a_amount > zero, token_b_amount > zero) {
+                (true, true) => {
+                    let preview = amm.preview_deposit(self.account_id.clone(), token_a_amount, token_b_amount)?;
+                    if preview.result < min_shares {
+                        return Err(Error::SlippageExceeded);
+                    }
+                    amm.deposit(self.account_id.clone(), token_a_amount, token_b_amount)
+                }
+                (true, false) if token_a_amount > T::from(1) => {
+                    Ok(amm.deposit_single(self.account_id.clone(), Token::A, token_a_amount, min_shares)?.0)
+                }
+                (false, true) if token_b_amount > T::from(1) => {
+                    Ok(amm.deposit_single(self.account_id.clone(), Token::B, token_b_amount, min_shares)?.0)
+                }
+                _ => Ok(zero),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::amm::FeeMode;
+
+        type Amm = crate::amm::Amm<u128>;
+        type Vault = super::Vault<u128>;
+
+        fn get_account_id() -> String {
+            String::from("account-1")
+        }
+
+        #[test]
+        fn test_vault_compounds_fees() {
+            let mut amm = Amm::new(10);
+            amm.set_fee_mode(FeeMode::Accumulate);
+            amm.get_free_tokens(get_account_id(), 100_000, 100_000).unwrap();
+            amm.get_free_tokens(String::from("trader"), 10_000, 10_000).unwrap();
+            let share = amm.deposit(get_account_id(), 100_000, 100_000).unwrap();
+
+            let mut vault = Vault::new(String::from("vault"));
+            assert_eq!(vault.deposit(&mut amm, get_account_id(), share / 2), Ok(share / 2));
+            assert_eq!(vault.get_pool_shares(&amm), share / 2);
+            assert_eq!(vault.withdraw(&mut amm, get_account_id(), share), Err(Error::InsufficientAmount));
+
+            let token_b_amount = amm.swap_token_a_for_token_b(String::from("trader"), 10_000, 0).unwrap();
+            amm.swap_token_b_for_token_a(String::from("trader"), token_b_amount, 0).unwrap();
+            assert_eq!(vault.harvest(&mut amm, share), Err(Error::SlippageExceeded));
+            let minted = vault.harvest(&mut amm, 1).unwrap();
+            assert!(minted > 0);
+            assert_eq!(vault.get_pool_shares(&amm), share / 2 + minted);
+            assert_eq!(vault.harvest(&mut amm, 0), Ok(0));
+
+            let pool_shares = vault.withdraw(&mut amm, get_account_id(), share / 2).unwrap();
+            assert_eq!(pool_shares, share / 2 + minted);
+            assert_eq!(amm.get_account_balance(get_account_id()).2, share + minted);
+            assert_eq!(vault.get_total_shares(), 0);
         }
     }
 }
 
+pub mod router {
+    use crate::amm::{add, div, mul, sub, Amm, PoolRegistry, SwapCheckpoint};
+    use crate::{AccountId, Amount, Error};
+
+    //state of every pool touched so far, keyed by pair and fee tier
+    type Checkpoints<T, Id> = Vec<((String, String), u32, SwapCheckpoint<T, Id>)>;
+
+    /// Executes swaps that hop through several pools of a `PoolRegistry`.
+    /// Each pool keeps its own token ledger, so an intermediate token is
+    /// moved between pools by burning it in one pool and issuing it in the
+    /// next.
+    pub struct Router<'a, T: Amount = u128, Id: AccountId = String> {
+        registry: &'a mut PoolRegistry<T, Id>,
+        split_parts: u32,
+    }
+
+    impl<'a, T: Amount, Id: AccountId> Router<'a, T, Id> {
+        pub fn new(registry: &'a mut PoolRegistry<T, Id>) -> Self {
+            Self { registry, split_parts: DEFAULT_SPLIT_PARTS }
+        }
+
+        /// Number of chunks split orders are divided into. Zero is ignored.
+        pub fn set_split_parts(&mut self, parts: u32) {
+            if parts > 0 {
+                self.split_parts = parts;
+            }
+        }
+
+        pub fn get_split_parts(&self) -> u32 {
+            self.split_parts
+        }
+
+        /// Output of swapping `amount_in` along `path`, trading each hop in
+        /// the pool of the matching entry of `fee_tiers`
+        pub fn get_swap_amount(&self, path: &[&str], fee_tiers: &[u32], amount_in: T) -> Result<T, Error> {
+            is_valid_route(path, fee_tiers)?;
+            let mut amount = amount_in;
+            for (tokens, fees) in path.windows(2).zip(fee_tiers) {
+                let amm = self.registry.get_pool(tokens[0], tokens[1], *fees).ok_or(Error::InvalidTokenPair)?;
+                amount = match amm.get_token_names().0 == tokens[0] {
+                    true => amm.get_swap_amount_for_token_b(amount)?,
+                    false => amm.get_swap_amount_for_token_a(amount)?,
+                };
+            }
+            Ok(amount)
+        }
+
+        /// Swaps `amount_in` of the first token of `path`, held by the account
+        /// in the first pool, into the last token, credited to the account in
+        /// the last pool. Only the final output is checked against `min_out`.
+        /// If any hop fails every pool is restored to its state before the
+        /// swap.
+        pub fn swap(&mut self, account_id: Id, path: &[&str], fee_tiers: &[u32], amount_in: T, min_out: T)
+            -> Result<T, Error> {
+            is_valid_route(path, fee_tiers)?;
+            let mut checkpoints = Vec::new();
+            let result = self.execute(&account_id, path, fee_tiers, amount_in, &mut checkpoints)
+                .and_then(|amount_out| match amount_out < min_out {
+                    true => Err(Error::SlippageExceeded),
+                    false => Ok(amount_out),
+                });
+            self.settle(checkpoints, result.is_ok());
+            result
+        }
+
+        /// Splits `amount_in` into `split_parts` equal chunks and routes each chunk
+        /// to the fee tier of the pair where it adds the most output given the
+        /// chunks already routed there. Tiers that cannot quote are skipped.
+        pub fn get_split_quote(&self, token_in: &str, token_out: &str, amount_in: T) -> Result<SplitQuote<T>, Error> {
+            if token_in == token_out {
+                return Err(Error::InvalidTokenPair);
+            }
+            let parts = T::from(self.split_parts);
+            let chunk = div(amount_in, parts)?;
+            let last_chunk = add(chunk, sub(amount_in, mul(chunk, parts)?)?)?;
+            let pools: Vec<(u32, &Amm<T, Id>)> = self.registry.get_fee_tiers(token_in, token_out).into_iter()
+                .filter_map(|fees| self.registry.get_pool(token_in, token_out, fees).map(|amm| (fees, amm)))
+                .collect();
+            let quote = |amm: &Amm<T, Id>, amount: T| match amount == T::default() {
+                true => Ok(T::default()),
+                false if amm.get_token_names().0 == token_in => amm.get_swap_amount_for_token_b(amount),
+                false => amm.get_swap_amount_for_token_a(amount),
+            };
+
+            let mut splits: Vec<Split<T>> = pools.iter()
+                .map(|(fees, _)| Split { fees: *fees, amount_in: T::default(), amount_out: T::default() })
+                .collect();
+            let mut remaining = amount_in;
+            while remaining > T::default() {
+                let chunk = if remaining == last_chunk { last_chunk } else { chunk.min(remaining) };
+                let mut best: Option<(usize, T)> = None;
+                for (index, (_, amm)) in pools.iter().enumerate() {
+                    let Ok(amount_out) = quote(amm, add(splits[index].amount_in, chunk)?) else {
+                        continue;
+                    };
+                    if best.is_none_or(|(best_index, best_amount_out)| {
+                        amount_out.checked_sub(splits[index].amount_out) > best_amount_out.checked_sub(splits[best_index].amount_out)
+                    }) {
+                        best = Some((index, amount_out));
+                    }
+                }
+                let (index, amount_out) = best.ok_or(Error::InsufficientLiquidity)?;
+                splits[index].amount_in = add(splits[index].amount_in, chunk)?;
+                splits[index].amount_out = amount_out;
+                remaining = sub(remaining, chunk)?;
+            }
+
+            splits.retain(|split| split.amount_in > T::default());
+            let amount_out = splits.iter().try_fold(T::default(), |total, split| add(total, split.amount_out))?;
+            Ok(SplitQuote { splits, amount_out })
+        }
+
+        /// Executes the split from `get_split_quote`. The account's input is
+        /// held, and its output credited, in the pool of the `ledger_fees`
+        /// tier; chunks routed to other tiers are moved there and back. If any
+        /// part fails, or the total output is below `min_out`, every pool is
+        /// restored to its state before the swap.
+        pub fn swap_split(&mut self, account_id: Id, token_in: &str, token_out: &str, ledger_fees: u32, amount_in: T,
+            min_out: T) -> Result<SplitQuote<T>, Error> {
+            let quote = self.get_split_quote(token_in, token_out, amount_in)?;
+            if quote.amount_out < min_out {
+                return Err(Error::SlippageExceeded);
+            }
+            let mut checkpoints = Vec::new();
+            let result = self.execute_split(&account_id, token_in, token_out, ledger_fees, quote, &mut checkpoints)
+                .and_then(|executed| match executed.amount_out < min_out {
+                    true => Err(Error::SlippageExceeded),
+                    false => Ok(executed),
+                });
+            self.settle(checkpoints, result.is_ok());
+            result
+        }
+
+        fn execute_split(&mut self, account_id: &Id, token_in: &str, token_out: &str, ledger_fees: u32,
+            quote: SplitQuote<T>, checkpoints: &mut Checkpoints<T, Id>) -> Result<SplitQuote<T>, Error> {
+            let key = (String::from(token_in), String::from(token_out));
+            let mut splits = Vec::new();
+            for split in quote.splits {
+                let moved = split.fees != ledger_fees;
+                if moved {
+                    let ledger = self.get_pool_mut(&key, ledger_fees).ok_or(Error::InvalidTokenPair)?;
+                    checkpoints.push((key.clone(), ledger_fees, ledger.checkpoint(account_id)));
+                    move_token(ledger, account_id, token_in, split.amount_in, false)?;
+                }
+                let amm = self.get_pool_mut(&key, split.fees).ok_or(Error::InvalidTokenPair)?;
+                checkpoints.push((key.clone(), split.fees, amm.checkpoint(account_id)));
+                if moved {
+                    move_token(amm, account_id, token_in, split.amount_in, true)?;
+                }
+                let amount_out = match amm.get_token_names().0 == token_in {
+                    true => amm.swap_token_a_for_token_b(account_id.clone(), split.amount_in, T::default())?,
+                    false => amm.swap_token_b_for_token_a(account_id.clone(), split.amount_in, T::default())?,
+                };
+                if moved {
+                    move_token(amm, account_id, token_out, amount_out, false)?;
+                    let ledger = self.get_pool_mut(&key, ledger_fees).ok_or(Error::InvalidTokenPair)?;
+                    move_token(ledger, account_id, token_out, amount_out, true)?;
+                }
+                splits.push(Split { amount_out, ..split });
+            }
+            let amount_out = splits.iter().try_fold(T::default(), |total, split| add(total, split.amount_out))?;
+            Ok(SplitQuote { splits, amount_out })
+        }
+
+        /// Restores every pool touched, latest first, or keeps their changes
+        /// and sends their events if the swap succeeded
+        fn settle(&mut self, checkpoints: Checkpoints<T, Id>, succeeded: bool) {
+            for (tokens, fees, checkpoint) in checkpoints.into_iter().rev() {
+                if let Some(amm) = self.get_pool_mut(&tokens, fees) {
+                    match succeeded {
+                        true => amm.release(checkpoint),
+                        false => amm.restore(checkpoint),
+                    }
+                }
+            }
+        }
+
+        fn get_pool_mut(&mut self, (token_in, token_out): &(String, String), fees: u32) -> Option<&mut Amm<T, Id>> {
+            self.registry.get_pool_mut(token_in, token_out, fees)
+        }
+
+        fn execute(&mut self, account_id: &Id, path: &[&str], fee_tiers: &[u32], amount_in: T,
+            checkpoints: &mut Checkpoints<T, Id>) -> Result<T, Error> {
+            let mut amount = amount_in;
+            for (hop, (tokens, fees)) in path.windows(2).zip(fee_tiers).enumerate() {
+                let key = (String::from(tokens[0]), String::from(tokens[1]));
+                let amm = self.get_pool_mut(&key, *fees).ok_or(Error::InvalidTokenPair)?;
+                checkpoints.push((key, *fees, amm.checkpoint(account_id)));
+                if hop > 0 {
+                    match amm.get_token_names().0 == tokens[0] {
+                        true => amm.get_free_tokens(account_id.clone(), amount, T::default())?,
+                        false => amm.get_free_tokens(account_id.clone(), T::default(), amount)?,
+                    }
+                }
+                let token_in_is_a = amm.get_token_names().0 == tokens[0];
+                amount = match token_in_is_a {
+                    true => amm.swap_token_a_for_token_b(account_id.clone(), amount, T::default())?,
+                    false => amm.swap_token_b_for_token_a(account_id.clone(), amount, T::default())?,
+                };
+                if hop + 2 < path.len() {
+                    match token_in_is_a {
+                        true => amm.burn_tokens(account_id.clone(), T::default(), amount)?,
+                        false => amm.burn_tokens(account_id.clone(), amount, T::default())?,
+                    }
+                }
+            }
+            Ok(amount)
+        }
+    }
+
+    const DEFAULT_SPLIT_PARTS: u32 = 10;
+
+    /// Part of a split order routed to one fee tier
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Split<T> {
+        pub fees: u32,
+        pub amount_in: T,
+        pub amount_out: T,
+    }
+
+    /// Order split across the fee tiers of a pair
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SplitQuote<T> {
+        pub splits: Vec<Split<T>>,
+        pub amount_out: T,
+    }
+
+    /// Issues `amount` of `token` to the account in the pool, or burns it
+    fn move_token<T: Amount, Id: AccountId>(amm: &mut Amm<T, Id>, account_id: &Id, token: &str, amount: T, issue: bool)
+        -> Result<(), Error> {
+        let (token_a_amount, token_b_amount) = match amm.get_token_names().0 == token {
+            true => (amount, T::default()),
+            false => (T::default(), amount),
+        };
+        match issue {
+            true => amm.get_free_tokens(account_id.clone(), token_a_amount, token_b_amount),
+            false => amm.burn_tokens(account_id.clone(), token_a_amount, token_b_amount),
+        }
+    }
+
+    fn is_valid_route(path: &[&str], fee_tiers: &[u32]) -> Result<(), Error> {
+        if path.len() < 2 || fee_tiers.len() + 1 != path.len() {
+            return Err(Error::AssetCountMismatch);
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::amm::{AmmBuilder, Event, SwapAccessMode};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        type PoolRegistry = crate::amm::PoolRegistry<u128>;
+
+        fn get_account_id() -> String {
+            String::from("account-1")
+        }
+
+        fn registry() -> PoolRegistry {
+            let mut registry = PoolRegistry::new();
+            registry.create_pool(AmmBuilder::new().fees(3).token_names("WETH", "USDC")
+                .initial_reserves(String::from("lp"), 1_000, 2_000_000)).unwrap();
+            registry.create_pool(AmmBuilder::new().fees(1).token_names("DAI", "USDC")
+                .initial_reserves(String::from("lp"), 5_000_000, 5_000_000)).unwrap();
+            registry.get_pool_mut("WETH", "USDC", 3).unwrap().get_free_tokens(get_account_id(), 10, 0).unwrap();
+            registry
+        }
+
+        #[test]
+        fn test_multi_hop_swap() {
+            let mut registry = registry();
+            let mut router = Router::new(&mut registry);
+            assert_eq!(router.get_swap_amount(&["WETH", "USDC", "DAI"], &[3], 10), Err(Error::AssetCountMismatch));
+            let quote = router.get_swap_amount(&["WETH", "USDC", "DAI"], &[3, 1], 10).unwrap();
+            assert_eq!(router.swap(get_account_id(), &["WETH", "USDC", "DAI"], &[3, 1], 10, quote), Ok(quote));
+
+            assert_eq!(registry.get_pool("WETH", "USDC", 3).unwrap().get_account_balance(get_account_id()), (0, 0, 0));
+            assert_eq!(registry.get_pool("DAI", "USDC", 1).unwrap().get_account_balance(get_account_id()), (quote, 0, 0));
+            for (_, amm) in registry.iter() {
+                assert!(amm.audit().unwrap().is_balanced().unwrap());
+            }
+        }
+
+        #[test]
+        fn test_split_route() {
+            let mut registry = PoolRegistry::new();
+            for (fees, token_a_amount, token_b_amount) in [(1, 10_000, 20_000), (3, 100_000, 200_000), (10, 100_000, 200_000)] {
+                registry.create_pool(AmmBuilder::new().fees(fees).token_names("WETH", "USDC")
+                    .initial_reserves(String::from("lp"), token_a_amount, token_b_amount)).unwrap();
+            }
+            registry.get_pool_mut("WETH", "USDC", 3).unwrap().get_free_tokens(get_account_id(), 20_000, 0).unwrap();
+            let mut router = Router::new(&mut registry);
+
+            let (best_fees, best_amount_out) = router.registry.get_best_quote("WETH", "USDC", 20_000).unwrap();
+            assert_eq!(best_fees, 3);
+            router.set_split_parts(20);
+            let quote = router.get_split_quote("WETH", "USDC", 20_000).unwrap();
+            assert_eq!(quote.splits.len(), 3);
+            assert_eq!(quote.splits.iter().map(|split| split.amount_in).sum::<u128>(), 20_000);
+            assert!(quote.amount_out > best_amount_out);
+
+            assert_eq!(router.swap_split(get_account_id(), "WETH", "USDC", 3, 20_000, quote.amount_out + 1),
+                Err(Error::SlippageExceeded));
+            assert_eq!(router.swap_split(get_account_id(), "WETH", "USDC", 3, 20_000, quote.amount_out), Ok(quote.clone()));
+            for (key, amm) in registry.iter() {
+                let expected = match key.2 {
+                    3 => (0, quote.amount_out, 0),
+                    _ => (0, 0, 0),
+                };
+                assert_eq!(amm.get_account_balance(get_account_id()), expected);
+                assert!(amm.audit().unwrap().is_balanced().unwrap());
+            }
+        }
+
+        #[test]
+        fn test_failed_hop_restores_every_pool() {
+            let mut registry = registry();
+            let events = Rc::new(RefCell::new(Vec::new()));
+            registry.get_pool_mut("WETH", "USDC", 3).unwrap().set_event_sink(Some(Box::new(events.clone())));
+            let before: Vec<_> = registry.iter().map(|(_, amm)| amm.pool_state()).collect();
+            let mut router = Router::new(&mut registry);
+            let quote = router.get_swap_amount(&["WETH", "USDC", "DAI"], &[3, 1], 10).unwrap();
+            assert_eq!(router.swap(get_account_id(), &["WETH", "USDC", "DAI"], &[3, 1], 10, quote + 1),
+                Err(Error::SlippageExceeded));
+
+            registry.get_pool_mut("DAI", "USDC", 1).unwrap().set_swap_access_mode(SwapAccessMode::Allowlist);
+            let mut router = Router::new(&mut registry);
+            assert_eq!(router.swap(get_account_id(), &["WETH", "USDC", "DAI"], &[3, 1], 10, 0), Err(Error::SwapNotPermitted));
+
+            let after: Vec<_> = registry.iter().map(|(_, amm)| amm.pool_state()).collect();
+            assert_eq!(before.len(), after.len());
+            assert!(before.iter().zip(&after).all(|(before, after)| before.token_a_pool_balance == after.token_a_pool_balance
+                && before.token_b_pool_balance == after.token_b_pool_balance));
+            assert_eq!(registry.get_pool("WETH", "USDC", 3).unwrap().get_account_balance(get_account_id()), (10, 0, 0));
+            assert_eq!(registry.get_pool("DAI", "USDC", 1).unwrap().get_account_balance(get_account_id()), (0, 0, 0));
+            assert_eq!(registry.get_pool("DAI", "USDC", 1).unwrap().get_total_supply(), (5_000_000, 5_000_000));
+            assert!(events.borrow().is_empty());
+
+            registry.get_pool_mut("DAI", "USDC", 1).unwrap().set_swap_access_mode(SwapAccessMode::Open);
+            let mut router = Router::new(&mut registry);
+            router.swap(get_account_id(), &["WETH", "USDC", "DAI"], &[3, 1], 10, 0).unwrap();
+            assert!(matches!(events.borrow()[..], [Event::Swapped { .. }, Event::TokensBurned { .. }]));
+        }
+    }
+}